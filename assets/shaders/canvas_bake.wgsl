@@ -0,0 +1,31 @@
+#import bevy_core_pipeline::fullscreen_vertex_shader::FullscreenVertexOutput
+
+struct CanvasBakeParams {
+    page_grid: vec2<u32>,
+}
+
+@group(0) @binding(0) var page_texture: texture_2d<f32>;
+@group(0) @binding(1) var<uniform> params: CanvasBakeParams;
+
+// Mirrors `PaintCanvasAtlas::bake_page`, averaging the page pixels that this canvas pixel covers
+// weighted by their alpha, so that transparent pixels don't darken the painted ones.
+@fragment
+fn fragment(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
+    let start = vec2<u32>(in.position.xy) * params.page_grid;
+
+    var color = vec3(0.0);
+    var alpha = 0.0;
+
+    for (var y = 0u; y < params.page_grid.y; y++) {
+        for (var x = 0u; x < params.page_grid.x; x++) {
+            let pixel = textureLoad(page_texture, start + vec2(x, y), 0);
+
+            color += pixel.rgb * pixel.a;
+            alpha += pixel.a;
+        }
+    }
+
+    let pixel_count = f32(params.page_grid.x * params.page_grid.y);
+
+    return vec4(color / max(alpha, 1e-6), alpha / pixel_count);
+}
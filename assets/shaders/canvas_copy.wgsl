@@ -0,0 +1,55 @@
+#import bevy_core_pipeline::fullscreen_vertex_shader::FullscreenVertexOutput
+
+// Matches the `BRUSH_SHAPE_*` constants in `canvas_copy.rs`.
+const BRUSH_SHAPE_VIEWPORT: u32 = 0u;
+const BRUSH_SHAPE_CIRCLE: u32 = 1u;
+const BRUSH_SHAPE_SOFT_ELLIPSE: u32 = 2u;
+const BRUSH_SHAPE_MASK_IMAGE: u32 = 3u;
+
+struct CanvasCopyBrush {
+    radii: vec2<f32>,
+    shape: u32,
+    softness: f32,
+}
+
+@group(0) @binding(0) var source_texture: texture_2d<f32>;
+@group(0) @binding(1) var source_sampler: sampler;
+@group(0) @binding(2) var<uniform> brush: CanvasCopyBrush;
+@group(0) @binding(3) var mask_texture: texture_2d<f32>;
+
+// Mirrors `PaintBrush::alpha`.
+fn brush_alpha(uv: vec2<f32>) -> f32 {
+    let offset = (uv - vec2(0.5)) / brush.radii;
+
+    switch brush.shape {
+        case BRUSH_SHAPE_CIRCLE: {
+            return select(0.0, 1.0, length(offset) <= 1.0);
+        }
+        case BRUSH_SHAPE_SOFT_ELLIPSE: {
+            let fade_start = 1.0 - brush.softness;
+            return 1.0 - clamp((length(offset) - fade_start) / (1.0 - fade_start), 0.0, 1.0);
+        }
+        case BRUSH_SHAPE_MASK_IMAGE: {
+            let mask_uv = (offset + vec2(1.0)) / 2.0;
+
+            if any(mask_uv < vec2(0.0)) || any(mask_uv >= vec2(1.0)) {
+                return 0.0;
+            }
+
+            let mask_size = vec2<f32>(textureDimensions(mask_texture));
+            let mask = textureLoad(mask_texture, vec2<u32>(mask_uv * mask_size), 0);
+
+            return dot(mask.rgb, vec3(0.2126, 0.7152, 0.0722)) * mask.a;
+        }
+        default: {
+            return 1.0;
+        }
+    }
+}
+
+@fragment
+fn fragment(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
+    let color = textureSample(source_texture, source_sampler, in.uv);
+
+    return vec4(color.rgb, color.a * brush_alpha(in.uv));
+}
@@ -0,0 +1,52 @@
+#import bevy_pbr::{
+    forward_io::VertexOutput,
+    mesh_view_bindings::view,
+}
+
+struct PaintedSkyMaterialParams {
+    newest_tint: vec4<f32>,
+    oldest_tint: vec4<f32>,
+    fog_color: vec4<f32>,
+    alpha_cutoff: f32,
+    fog_start: f32,
+    fog_end: f32,
+    newest_layer: f32,
+    fade_layers: f32,
+    oldest_opacity: f32,
+}
+
+@group(#{MATERIAL_BIND_GROUP}) @binding(0) var canvas_texture: texture_2d<f32>;
+@group(#{MATERIAL_BIND_GROUP}) @binding(1) var canvas_sampler: sampler;
+@group(#{MATERIAL_BIND_GROUP}) @binding(2) var<uniform> params: PaintedSkyMaterialParams;
+
+@fragment
+fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
+    let canvas = textureSample(canvas_texture, canvas_sampler, in.uv);
+
+    // Cut out the unpainted background of the screenshot, which is transparent.
+    if canvas.a <= params.alpha_cutoff {
+        discard;
+    }
+
+    // Painted meshes store the layer they were painted on in their second UV channel.
+#ifdef VERTEX_UVS_B
+    let layer = in.uv_b.x;
+#else
+    let layer = params.newest_layer;
+#endif
+
+    let age = clamp((params.newest_layer - layer) / max(params.fade_layers, 1.0), 0.0, 1.0);
+
+    let tint = mix(params.newest_tint, params.oldest_tint, age);
+    let opacity = mix(1.0, params.oldest_opacity, age);
+
+    let fog = smoothstep(
+        params.fog_start,
+        params.fog_end,
+        distance(in.world_position.xyz, view.world_position),
+    ) * params.fog_color.a;
+
+    let color = mix(canvas.rgb * tint.rgb, params.fog_color.rgb, fog);
+
+    return vec4(color, canvas.a * tint.a * opacity);
+}
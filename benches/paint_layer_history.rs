@@ -0,0 +1,90 @@
+//! Benchmarks for recording and truncating paint layer histories, whose cost per layer should stay
+//! flat no matter how many layers have been painted.
+use std::hint::black_box;
+use std::iter;
+
+use bevy::prelude::*;
+use collage::{
+    LayerIndex,
+    PaintLayerHistoryPlugin,
+    PaintableHistory,
+    RecordPresent,
+    RedoPaintLayers,
+    TruncatePaintLayers,
+};
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+
+/// The number of entities with a history, like the paintable meshes of a scene.
+const HISTORY_COUNT: usize = 100;
+
+/// The numbers of layers already painted before each benchmark.
+const LAYER_COUNTS: [u32; 3] = [1_000, 10_000, 50_000];
+
+/// Returns an app with [`HISTORY_COUNT`] transform histories that each have the given number of
+/// layers.
+fn app_with_layers(layer_count: u32) -> App {
+    let history = (0..layer_count).fold(PaintableHistory::default(), |history, layer| {
+        history.with_end(LayerIndex(layer), Some(Transform::IDENTITY))
+    });
+
+    let mut app = App::new();
+    app.add_plugins(PaintLayerHistoryPlugin::<Transform>::default());
+    app.world_mut().spawn_batch(iter::repeat_n(
+        (Transform::IDENTITY, history),
+        HISTORY_COUNT,
+    ));
+    app
+}
+
+/// Records the next layer, over and over.
+fn bench_record_present(c: &mut Criterion) {
+    let mut group = c.benchmark_group("record_present");
+
+    for layer_count in LAYER_COUNTS {
+        let mut app = app_with_layers(layer_count);
+
+        group.bench_with_input(
+            BenchmarkId::from_parameter(layer_count),
+            &layer_count,
+            |b, &layer_count| {
+                b.iter(|| {
+                    app.world_mut().write_message(RecordPresent {
+                        layer: black_box(LayerIndex(layer_count)),
+                    });
+                    app.update();
+                });
+            },
+        );
+    }
+
+    group.finish();
+}
+
+/// Truncates the last layer onto the redo stack and redoes it, over and over.
+fn bench_truncate_redoable(c: &mut Criterion) {
+    let mut group = c.benchmark_group("truncate_redoable");
+
+    for layer_count in LAYER_COUNTS {
+        let mut app = app_with_layers(layer_count);
+
+        group.bench_with_input(
+            BenchmarkId::from_parameter(layer_count),
+            &layer_count,
+            |b, &layer_count| {
+                b.iter(|| {
+                    app.world_mut()
+                        .write_message(TruncatePaintLayers::new(black_box(LayerIndex(
+                            layer_count - 1,
+                        ))));
+                    app.world_mut().write_message(RedoPaintLayers);
+                    app.update();
+                });
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_record_present, bench_truncate_redoable);
+criterion_main!(benches);
@@ -14,6 +14,7 @@ pub struct DevArgs {
     /// Add the ability to spawn a free camera with Ctrl+f.
     #[arg(short, long, env)]
     pub free_cam: bool,
+    /// The game state to start in.
     #[arg(short, long, env)]
     pub game_state: Option<GameState>,
 }
@@ -9,6 +9,18 @@ pub fn button_predicate<A: Actionlike>(button: A) -> impl Fn(Query<&ActionState<
     move |action_query| action_query.iter().any(|action| action.pressed(&button))
 }
 
+/// Predicate system that returns `true` when the given button has just been pressed (on any
+/// `ActionState` entity)
+pub fn button_just_pressed_predicate<A: Actionlike>(
+    button: A,
+) -> impl Fn(Query<&ActionState<A>>) -> bool {
+    move |action_query| {
+        action_query
+            .iter()
+            .any(|action| action.just_pressed(&button))
+    }
+}
+
 /// Add "plugin" for creating and ticking timers while a button is pressed.
 pub fn add_button_timer<A: Actionlike>(
     app: &mut App,
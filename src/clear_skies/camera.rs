@@ -10,7 +10,9 @@ use leafwing_input_manager::prelude::*;
 
 use crate::clear_skies::ClearSkiesState;
 use crate::clear_skies::paint_skies::{
+    CompactedCameraPath,
     LookAtSphericalCoords,
+    PaintBrush,
     Paintable,
     PaintableHistory,
     SphericalCoordsBounds,
@@ -37,6 +39,14 @@ impl Plugin for ClearSkiesCameraPlugin {
                     .in_set(CreateClearSkiesRenderTarget),
             )
             .add_systems(Startup, (|| command_spawn(Camera2d)).pipe(affect))
+            .add_systems(
+                OnEnter(ClearSkiesState::PlaySkies),
+                set_paint_skies_camera_active(false).pipe(affect),
+            )
+            .add_systems(
+                OnExit(ClearSkiesState::PlaySkies),
+                set_paint_skies_camera_active(true).pipe(affect),
+            )
             .add_systems(
                 Update,
                 (
@@ -59,7 +69,14 @@ impl Default for ClearSkiesResolution {
     }
 }
 
+/// The color of the sky, shown behind the transparent background of the
+/// [`ClearSkiesRenderTarget`].
+pub const SKY_COLOR: Color = Color::srgb(0.0, 0.4, 1.0);
+
 /// The render target that will be created with a resolution of [`ClearSkiesResolution`].
+///
+/// Its background is left transparent, so that the unpainted background of canvases captured from
+/// it can be cut out by their alpha. The [`ClearSkiesViewport`] shows [`SKY_COLOR`] behind it.
 #[derive(Default, Debug, PartialEq, Eq, Clone, Hash, Resource, Deref, DerefMut, Reflect)]
 #[reflect(Resource)]
 pub struct ClearSkiesRenderTarget(pub Handle<Image>);
@@ -103,6 +120,33 @@ pub enum PaintSkiesAction {
     /// Button input for removing layers.
     #[actionlike(Button)]
     Remove,
+    /// Button input for restoring removed layers.
+    #[actionlike(Button)]
+    Redo,
+    /// Button input for switching to the next branch of the paint layer history.
+    #[actionlike(Button)]
+    SwitchBranch,
+    /// Button input for saving the paint session to disk.
+    #[actionlike(Button)]
+    SaveSession,
+    /// Button input for loading the paint session from disk.
+    #[actionlike(Button)]
+    LoadSession,
+    /// Button input for replaying the recorded paint layers.
+    #[actionlike(Button)]
+    Replay,
+    /// Held axis input for growing or shrinking the paint brush.
+    #[actionlike(Axis)]
+    ResizeBrush,
+    /// Axis input for growing or shrinking the paint brush in steps, like the scroll wheel.
+    #[actionlike(Axis)]
+    StepBrushSize,
+    /// Button input for switching to the next paint brush shape.
+    #[actionlike(Button)]
+    CycleBrush,
+    /// Button input for switching to [`ClearSkiesState::PlaySkies`].
+    #[actionlike(Button)]
+    PlaySkies,
 }
 
 /// Defines the paint skies camera.
@@ -121,6 +165,27 @@ pub fn spawn_paint_skies_camera(
         .with(PaintSkiesAction::Paint, KeyCode::Space)
         .with(PaintSkiesAction::Remove, GamepadButton::LeftTrigger)
         .with(PaintSkiesAction::Remove, KeyCode::KeyX)
+        .with(PaintSkiesAction::Redo, GamepadButton::LeftTrigger2)
+        .with(PaintSkiesAction::Redo, KeyCode::KeyR)
+        .with(PaintSkiesAction::SwitchBranch, GamepadButton::North)
+        .with(PaintSkiesAction::SwitchBranch, KeyCode::KeyB)
+        .with(PaintSkiesAction::SaveSession, KeyCode::F5)
+        .with(PaintSkiesAction::LoadSession, KeyCode::F9)
+        .with(PaintSkiesAction::Replay, GamepadButton::Select)
+        .with(PaintSkiesAction::Replay, KeyCode::KeyP)
+        .with(PaintSkiesAction::CycleBrush, GamepadButton::West)
+        .with(PaintSkiesAction::CycleBrush, KeyCode::KeyC)
+        .with(PaintSkiesAction::PlaySkies, GamepadButton::Start)
+        .with(PaintSkiesAction::PlaySkies, KeyCode::Tab)
+        .with_axis(PaintSkiesAction::StepBrushSize, MouseScrollAxis::Y)
+        .with_axis(
+            PaintSkiesAction::ResizeBrush,
+            VirtualAxis::new(GamepadButton::DPadDown, GamepadButton::DPadUp),
+        )
+        .with_axis(
+            PaintSkiesAction::ResizeBrush,
+            VirtualAxis::new(KeyCode::BracketLeft, KeyCode::BracketRight),
+        )
         .with_dual_axis(
             PaintSkiesAction::Rotate,
             GamepadStick::LEFT.with_deadzone_symmetric(0.1),
@@ -147,10 +212,47 @@ pub fn spawn_paint_skies_camera(
     ))
 }
 
+/// Returns a system that activates or deactivates the [`PaintSkiesCamera`] and its actions.
+///
+/// The [`Paintable`] scene is only rendered by the paint skies camera, so it's hidden along with
+/// it. Its actions are disabled so that nothing is painted or removed while it's inactive.
+pub fn set_paint_skies_camera_active(
+    active: bool,
+) -> impl Fn() -> QueryMap<
+    (&'static Camera, &'static ActionState<PaintSkiesAction>),
+    (
+        ComponentSet<Camera>,
+        ComponentSet<ActionState<PaintSkiesAction>>,
+    ),
+    With<PaintSkiesCamera>,
+> {
+    move || {
+        query_map(
+            move |(camera, action_state): (&Camera, &ActionState<PaintSkiesAction>)| {
+                let mut action_state = action_state.clone();
+
+                if active {
+                    action_state.enable_all();
+                } else {
+                    action_state.disable_all();
+                }
+
+                (
+                    component_set(Camera {
+                        is_active: active,
+                        ..camera.clone()
+                    }),
+                    component_set(action_state),
+                )
+            },
+        )
+    }
+}
+
 /// The camera controlled in the paint skies state whose subjects get painted.
 #[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Reflect, Component)]
 #[reflect(Component)]
-#[require(Name = "PaintSkiesCamera", Camera3d, LookAtSphericalCoords, Paintable, PaintableHistory<GlobalTransform>, PaintableHistory<ActionState<PaintSkiesAction>>, RenderLayers = PAINTABLE_LAYER.with(0))]
+#[require(Name = "PaintSkiesCamera", Camera3d, LookAtSphericalCoords, PaintBrush, Paintable, PaintableHistory<GlobalTransform>, PaintableHistory<ActionState<PaintSkiesAction>>, CompactedCameraPath, RenderLayers = PAINTABLE_LAYER.with(0))]
 pub struct PaintSkiesCamera;
 
 /// Marker component for the viewport UI node displaying the [`ClearSkiesRenderTarget`].
@@ -163,9 +265,10 @@ pub struct ClearSkiesViewport;
 pub fn spawn_viewport(
     resolution: Res<ClearSkiesResolution>,
     texture: Res<ClearSkiesRenderTarget>,
-) -> CommandSpawn<(ImageNode, ClearSkiesViewport, Node)> {
+) -> CommandSpawn<(ImageNode, BackgroundColor, ClearSkiesViewport, Node)> {
     command_spawn((
         ImageNode::new((**texture).clone()),
+        BackgroundColor(SKY_COLOR),
         ClearSkiesViewport,
         Node {
             aspect_ratio: Some(resolution.x as f32 / resolution.y as f32),
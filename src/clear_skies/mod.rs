@@ -12,6 +12,8 @@ mod play_skies;
 
 mod render_layers;
 
+mod triangle_bvh;
+
 mod camera;
 
 mod switch_gamepads;
@@ -0,0 +1,653 @@
+use bevy::asset::RenderAssetUsages;
+use bevy::platform::collections::{HashMap, HashSet};
+use bevy::prelude::*;
+use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat, TextureUsages};
+use bevy::render::view::screenshot::ScreenshotCaptured;
+use bevy_pipe_affect::prelude::*;
+
+use crate::clear_skies::ClearSkiesState;
+use crate::clear_skies::camera::{ClearSkiesResolution, PaintSkiesCamera};
+use crate::clear_skies::paint_skies::canvas_copy::CanvasCopyMode;
+use crate::clear_skies::paint_skies::paint_brush::{BrushShape, PaintBrush, PaintBrushSettings};
+use crate::clear_skies::paint_skies::paint_layer_history::{
+    BranchIndex,
+    HistoryUnit,
+    PaintableHistory,
+    RecordPresent,
+    TruncatePaintLayers,
+};
+use crate::clear_skies::paint_skies::paint_meshes::{
+    LayerIndex,
+    PaintLayerSettings,
+    PaintMeshesSet,
+    PaintedMesh,
+};
+use crate::clear_skies::paint_skies::painted_sky_material::PaintedSkyMaterial;
+
+/// The format of canvases and atlas pages, which matches the [`ClearSkiesRenderTarget`].
+///
+/// [`ClearSkiesRenderTarget`]: crate::clear_skies::camera::ClearSkiesRenderTarget
+pub const CANVAS_FORMAT: TextureFormat = TextureFormat::Rgba8UnormSrgb;
+
+/// The size of a pixel in [`CANVAS_FORMAT`].
+pub const CANVAS_PIXEL_SIZE: usize = 4;
+
+/// Plugin that stores the canvases of every paint layer in a shared [`PaintCanvasAtlas`].
+///
+/// Canvases are freed once no [`PaintedMesh`] uses them anymore, or once their layer can't be
+/// reached anymore.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Reflect)]
+pub struct PaintCanvasAtlasPlugin;
+
+impl Plugin for PaintCanvasAtlasPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PaintCanvasAtlasSettings>()
+            .register_type::<PaintCanvasAtlasSettings>()
+            .register_type::<PaintCanvasAtlas>()
+            .add_systems(
+                OnEnter(ClearSkiesState::Setup),
+                create_paint_canvas_atlas.pipe(affect),
+            )
+            .add_systems(
+                Update,
+                free_unused_canvases
+                    .pipe(affect)
+                    .after(PaintMeshesSet)
+                    .run_if(resource_exists::<PaintCanvasAtlas>)
+                    .run_if(
+                        any_component_removed::<PaintedMesh>
+                            .or(on_message::<TruncatePaintLayers>)
+                            .or(on_message::<RecordPresent>),
+                    ),
+            );
+    }
+}
+
+/// Settings for the layout of the [`PaintCanvasAtlas`].
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Reflect, Resource)]
+#[reflect(Resource)]
+pub struct PaintCanvasAtlasSettings {
+    /// The number of canvas columns and rows in each atlas page.
+    pub page_grid: UVec2,
+    /// The most atlas pages that can exist at once, limiting the memory used by canvases.
+    ///
+    /// If this is `None`, the atlas is sized to hold the canvas of every layer that can be undone
+    /// within [`PaintLayerSettings::layer_budget`], along with a [`CanvasSlot::Baked`] slot on
+    /// every page.
+    pub max_pages: Option<u32>,
+    /// How canvases are copied from the render target into their slots.
+    pub copy_mode: CanvasCopyMode,
+}
+
+impl Default for PaintCanvasAtlasSettings {
+    fn default() -> Self {
+        PaintCanvasAtlasSettings {
+            page_grid: UVec2::new(4, 4),
+            max_pages: None,
+            copy_mode: default(),
+        }
+    }
+}
+
+/// What a slot of the [`PaintCanvasAtlas`] is holding.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Reflect)]
+pub enum CanvasSlot {
+    /// The slot can be written to.
+    #[default]
+    Free,
+    /// The slot holds the most recent screenshot, which hasn't been painted with yet.
+    ///
+    /// It's overwritten by the next screenshot if the layer isn't painted on.
+    Pending,
+    /// The slot holds the canvas of a paint layer.
+    Layer {
+        /// The layer painted with this canvas.
+        layer: LayerIndex,
+        /// The branch of the layer painted with this canvas.
+        branch: BranchIndex,
+    },
+    /// The slot holds every canvas that was on its page when layers on it were compacted, shrunk
+    /// to fit.
+    ///
+    /// There is at most one of these on each page, which is baked again the next time layers on
+    /// its page are compacted.
+    Baked,
+}
+
+/// A texture holding a grid of canvases, and the material painted meshes use to sample it.
+#[derive(Debug, Clone, PartialEq, Reflect)]
+pub struct PaintCanvasAtlasPage {
+    /// The texture of the page.
+    pub image: Handle<Image>,
+    /// The material that painted meshes on this page use.
+    pub material: Handle<PaintedSkyMaterial>,
+}
+
+/// Resource that stores the canvases of paint layers in pages of canvas grids.
+///
+/// Painted meshes share the material of the page their canvas is on, with UVs pointing into their
+/// canvas's slot. Pages are only created when every existing slot is taken, and freed once none of
+/// their slots are, up to [`PaintCanvasAtlasSettings::max_pages`] or enough for the
+/// [`PaintLayerSettings::layer_budget`].
+///
+/// Systems writing canvases take this and the page assets mutably instead of returning effects,
+/// since cloning whole pages for every layer would defeat the purpose of the atlas.
+#[derive(Debug, Clone, PartialEq, Reflect, Resource)]
+#[reflect(Resource)]
+pub struct PaintCanvasAtlas {
+    canvas_size: UVec2,
+    page_grid: UVec2,
+    max_pages: u32,
+    /// Every page, or `None` for pages that were freed.
+    pages: Vec<Option<PaintCanvasAtlasPage>>,
+    slots: Vec<CanvasSlot>,
+}
+
+impl PaintCanvasAtlas {
+    /// Constructs a new, empty [`PaintCanvasAtlas`], with room for the given layer budget unless
+    /// [`PaintCanvasAtlasSettings::max_pages`] is set.
+    pub fn new(canvas_size: UVec2, settings: &PaintCanvasAtlasSettings, layer_budget: u32) -> Self {
+        let page_grid = settings.page_grid.max(UVec2::ONE);
+
+        PaintCanvasAtlas {
+            canvas_size,
+            page_grid,
+            max_pages: settings
+                .max_pages
+                .unwrap_or_else(|| pages_for_layer_budget(page_grid, layer_budget)),
+            pages: vec![],
+            slots: vec![],
+        }
+    }
+
+    /// Constructs a [`PaintCanvasAtlas`] from saved page images and slots, adding the images as
+    /// assets.
+    pub fn from_saved_pages(
+        canvas_size: UVec2,
+        settings: &PaintCanvasAtlasSettings,
+        layer_budget: u32,
+        pages: Vec<Option<Image>>,
+        slots: Vec<CanvasSlot>,
+        images: &mut Assets<Image>,
+        materials: &mut Assets<PaintedSkyMaterial>,
+    ) -> Self {
+        let mut atlas = PaintCanvasAtlas::new(canvas_size, settings, layer_budget);
+
+        atlas.pages = pages
+            .into_iter()
+            .map(|image| image.map(|image| new_page(image, images, materials)))
+            .collect();
+
+        atlas.slots = slots;
+        atlas.slots.resize(
+            atlas.pages.len() * atlas.canvases_per_page(),
+            CanvasSlot::Free,
+        );
+
+        atlas
+    }
+
+    /// The size of each canvas in the atlas.
+    pub fn canvas_size(&self) -> UVec2 {
+        self.canvas_size
+    }
+
+    /// The number of canvas columns and rows in each page.
+    pub fn page_grid(&self) -> UVec2 {
+        self.page_grid
+    }
+
+    fn canvases_per_page(&self) -> usize {
+        self.page_grid.element_product() as usize
+    }
+
+    fn page_size(&self) -> UVec2 {
+        self.canvas_size * self.page_grid
+    }
+
+    /// The most memory that the atlas pages can take up, in bytes.
+    ///
+    /// Pages are kept in both the main world and the render world, so they take up twice this
+    /// much in practice.
+    pub fn memory_ceiling(&self) -> usize {
+        self.page_size().element_product() as usize * CANVAS_PIXEL_SIZE * self.max_pages as usize
+    }
+
+    /// Returns every page, or `None` for pages that were freed.
+    pub fn pages(&self) -> &[Option<PaintCanvasAtlasPage>] {
+        &self.pages
+    }
+
+    /// Returns what every slot is holding.
+    pub fn slots(&self) -> &[CanvasSlot] {
+        &self.slots
+    }
+
+    /// Returns the page the given slot is on.
+    pub fn page(&self, slot: usize) -> Option<&PaintCanvasAtlasPage> {
+        self.pages.get(slot / self.canvases_per_page())?.as_ref()
+    }
+
+    /// Returns the slot holding the most recent screenshot, if it hasn't been painted with yet.
+    pub fn pending_slot(&self) -> Option<usize> {
+        self.slots
+            .iter()
+            .position(|slot| *slot == CanvasSlot::Pending)
+    }
+
+    /// Returns the pixel position of the given slot's canvas within its page.
+    pub fn slot_origin(&self, slot: usize) -> UVec2 {
+        let page_slot = (slot % self.canvases_per_page()) as u32;
+
+        UVec2::new(page_slot % self.page_grid.x, page_slot / self.page_grid.x) * self.canvas_size
+    }
+
+    /// Converts a UV within a canvas into a UV within the page of the given slot.
+    pub fn slot_uv(&self, slot: usize, uv: Vec2) -> Vec2 {
+        let page_slot = (slot % self.canvases_per_page()) as u32;
+        let cell = UVec2::new(page_slot % self.page_grid.x, page_slot / self.page_grid.x);
+
+        (cell.as_vec2() + uv) / self.page_grid.as_vec2()
+    }
+
+    /// Returns this [`PaintCanvasAtlas`] with the given slot holding something else.
+    pub fn with_slot(mut self, slot: usize, canvas_slot: CanvasSlot) -> Self {
+        if let Some(existing) = self.slots.get_mut(slot) {
+            *existing = canvas_slot;
+        }
+
+        self
+    }
+
+    /// Returns this [`PaintCanvasAtlas`] with every layer slot that doesn't pass the predicate
+    /// freed, along with any pages that are left empty.
+    pub fn retain_layers(mut self, f: impl Fn(LayerIndex, BranchIndex) -> bool) -> Self {
+        for slot in self.slots.iter_mut() {
+            if let CanvasSlot::Layer { layer, branch } = *slot
+                && !f(layer, branch)
+            {
+                *slot = CanvasSlot::Free;
+            }
+        }
+
+        let canvases_per_page = self.canvases_per_page();
+
+        for (page, slots) in self
+            .pages
+            .iter_mut()
+            .zip(self.slots.chunks(canvases_per_page))
+        {
+            if slots.iter().all(|slot| *slot == CanvasSlot::Free) {
+                *page = None;
+            }
+        }
+
+        self
+    }
+
+    /// Returns this [`PaintCanvasAtlas`] with the slots of the given layers freed, along with the
+    /// [`CanvasSlot::Baked`] slot that each page they were on should be baked into, by page index.
+    ///
+    /// The baked slot takes the place of one of the freed slots, or of the page's previous baked
+    /// slot, which is freed too since the page is baked again.
+    pub fn with_baked_layers(
+        mut self,
+        layers: &HashSet<(LayerIndex, BranchIndex)>,
+    ) -> (Self, HashMap<usize, usize>) {
+        let is_baked_layer = |slot: &CanvasSlot| match *slot {
+            CanvasSlot::Layer { layer, branch } => layers.contains(&(layer, branch)),
+            _ => false,
+        };
+
+        let canvases_per_page = self.canvases_per_page();
+        let mut baked_slots = HashMap::new();
+
+        for (page, slots) in self.slots.chunks_mut(canvases_per_page).enumerate() {
+            if !slots.iter().any(is_baked_layer) {
+                continue;
+            }
+
+            let mut freed = slots
+                .iter_mut()
+                .enumerate()
+                .filter(|(_, slot)| is_baked_layer(slot) || **slot == CanvasSlot::Baked);
+
+            if let Some((page_slot, slot)) = freed.next() {
+                *slot = CanvasSlot::Baked;
+                baked_slots.insert(page, page * canvases_per_page + page_slot);
+            }
+
+            for (_, slot) in freed {
+                *slot = CanvasSlot::Free;
+            }
+        }
+
+        (self, baked_slots)
+    }
+
+    /// Returns the index of the page that uses the given material.
+    pub fn page_with_material(&self, material: AssetId<PaintedSkyMaterial>) -> Option<usize> {
+        self.pages.iter().position(|page| {
+            page.as_ref()
+                .is_some_and(|page| page.material.id() == material)
+        })
+    }
+
+    /// Adds a page with the given image, returning the first slot on it.
+    ///
+    /// The page replaces a freed one if there is any.
+    pub fn add_page(
+        &mut self,
+        image: Image,
+        images: &mut Assets<Image>,
+        materials: &mut Assets<PaintedSkyMaterial>,
+    ) -> usize {
+        let page = new_page(image, images, materials);
+
+        let index = match self.pages.iter().position(Option::is_none) {
+            Some(index) => {
+                self.pages[index] = Some(page);
+                index
+            }
+            None => {
+                self.pages.push(Some(page));
+                self.slots.extend(std::iter::repeat_n(
+                    CanvasSlot::Free,
+                    self.canvases_per_page(),
+                ));
+                self.pages.len() - 1
+            }
+        };
+
+        index * self.canvases_per_page()
+    }
+
+    /// Returns an empty image for a page of this atlas.
+    pub fn new_page_image(&self) -> Image {
+        let page_size = self.page_size();
+
+        Image::new_fill(
+            Extent3d {
+                width: page_size.x,
+                height: page_size.y,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            &[0; CANVAS_PIXEL_SIZE],
+            CANVAS_FORMAT,
+            // Pages are kept in the main world too, so that they can be saved with the session.
+            RenderAssetUsages::all(),
+        )
+    }
+
+    /// Returns a slot the next canvas can be written to, adding a page if every slot is taken.
+    ///
+    /// Returns `None` if the atlas is full.
+    pub fn reserve_slot(
+        &mut self,
+        images: &mut Assets<Image>,
+        materials: &mut Assets<PaintedSkyMaterial>,
+    ) -> Option<usize> {
+        match self.writable_slot() {
+            Some(slot) => Some(slot),
+            None if self.has_page_capacity() => {
+                let page_image = self.new_page_image();
+                Some(self.add_page(page_image, images, materials))
+            }
+            None => {
+                warn!(
+                    "paint canvas atlas is full at {} pages, so this layer can't be painted",
+                    self.max_pages
+                );
+                None
+            }
+        }
+    }
+
+    /// Marks the given slot as holding the most recent canvas, which hasn't been painted with yet.
+    pub fn set_pending(&mut self, slot: usize) {
+        self.slots[slot] = CanvasSlot::Pending;
+    }
+
+    /// Returns a slot the next canvas can be written to, replacing the pending canvas if there is
+    /// one.
+    fn writable_slot(&self) -> Option<usize> {
+        self.pending_slot().or_else(|| {
+            (0..self.slots.len())
+                .find(|slot| self.slots[*slot] == CanvasSlot::Free && self.page(*slot).is_some())
+        })
+    }
+
+    fn has_page_capacity(&self) -> bool {
+        self.pages.iter().flatten().count() < self.max_pages as usize
+    }
+
+    /// Shrinks the whole page image into the given slot on it, averaging the pixels of each canvas
+    /// cell weighted by their alpha.
+    ///
+    /// Mirrors `canvas_bake.wgsl`, averaging in linear space.
+    pub fn bake_page(&self, page_image: &mut Image, slot: usize) {
+        let Some(page_data) = page_image.data.as_ref() else {
+            warn!("canvas atlas page has no data to bake");
+            return;
+        };
+
+        let to_linear: [f32; 256] =
+            std::array::from_fn(|value| Srgba::gamma_function(value as f32 / 255.0));
+
+        let page_width = self.page_size().x as usize;
+        let canvas_width = self.canvas_size.x as usize;
+        let (grid_width, grid_height) = (self.page_grid.x as usize, self.page_grid.y as usize);
+        let to_u8 = |value: f32| (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+
+        let baked_data = (0..self.canvas_size.element_product() as usize)
+            .flat_map(|index| {
+                let x = index % canvas_width * grid_width;
+                let y = index / canvas_width * grid_height;
+
+                let mut color = Vec3::ZERO;
+                let mut alpha = 0.0;
+
+                for pixel_y in y..y + grid_height {
+                    for pixel_x in x..x + grid_width {
+                        let start = (pixel_y * page_width + pixel_x) * CANVAS_PIXEL_SIZE;
+                        let pixel = &page_data[start..start + CANVAS_PIXEL_SIZE];
+                        let pixel_alpha = pixel[3] as f32 / 255.0;
+
+                        color += Vec3::from_array(std::array::from_fn(|channel| {
+                            to_linear[pixel[channel] as usize]
+                        })) * pixel_alpha;
+                        alpha += pixel_alpha;
+                    }
+                }
+
+                let color = color / f32::max(alpha, f32::EPSILON);
+
+                [
+                    to_u8(Srgba::gamma_function_inverse(color.x)),
+                    to_u8(Srgba::gamma_function_inverse(color.y)),
+                    to_u8(Srgba::gamma_function_inverse(color.z)),
+                    to_u8(alpha / (grid_width * grid_height) as f32),
+                ]
+            })
+            .collect();
+
+        let canvas = Image::new(
+            Extent3d {
+                width: self.canvas_size.x,
+                height: self.canvas_size.y,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            baked_data,
+            CANVAS_FORMAT,
+            RenderAssetUsages::MAIN_WORLD,
+        );
+
+        self.write_canvas(page_image, slot, &canvas);
+    }
+
+    /// Copies the given canvas into the given slot of the page image.
+    fn write_canvas(&self, page_image: &mut Image, slot: usize, canvas: &Image) {
+        let (Some(page_data), Some(canvas_data)) = (page_image.data.as_mut(), canvas.data.as_ref())
+        else {
+            warn!("canvas atlas page or screenshot has no data");
+            return;
+        };
+
+        let origin = self.slot_origin(slot);
+
+        let page_row_size = self.page_size().x as usize * CANVAS_PIXEL_SIZE;
+        let canvas_row_size = self.canvas_size.x as usize * CANVAS_PIXEL_SIZE;
+
+        for (row, canvas_row) in canvas_data
+            .chunks_exact(canvas_row_size)
+            .take(self.canvas_size.y as usize)
+            .enumerate()
+        {
+            let start =
+                (origin.y as usize + row) * page_row_size + origin.x as usize * CANVAS_PIXEL_SIZE;
+
+            page_data[start..start + canvas_row_size].copy_from_slice(canvas_row);
+        }
+    }
+}
+
+/// Returns how many pages it takes to hold the canvas of every layer within the given layer budget,
+/// leaving room for a [`CanvasSlot::Baked`] slot on each.
+///
+/// Compaction lets one more layer than the budget be painted before it runs, and the pending
+/// canvas takes up a slot too.
+fn pages_for_layer_budget(page_grid: UVec2, layer_budget: u32) -> u32 {
+    (layer_budget + 2).div_ceil(page_grid.element_product().saturating_sub(1).max(1))
+}
+
+/// Adds the given page image as an asset, along with the material that samples it.
+///
+/// Pages can be rendered to and read back, so that canvases can be copied into them on the GPU.
+fn new_page(
+    mut image: Image,
+    images: &mut Assets<Image>,
+    materials: &mut Assets<PaintedSkyMaterial>,
+) -> PaintCanvasAtlasPage {
+    image.texture_descriptor.usage |= TextureUsages::RENDER_ATTACHMENT | TextureUsages::COPY_SRC;
+
+    let image = images.add(image);
+
+    PaintCanvasAtlasPage {
+        material: materials.add(PaintedSkyMaterial::from(image.clone())),
+        image,
+    }
+}
+
+/// System that creates the [`PaintCanvasAtlas`], reporting how much memory it may use.
+fn create_paint_canvas_atlas(
+    resolution: Res<ClearSkiesResolution>,
+    settings: Res<PaintCanvasAtlasSettings>,
+    paint_layer_settings: Res<PaintLayerSettings>,
+) -> CommandInsertResource<PaintCanvasAtlas> {
+    let atlas = PaintCanvasAtlas::new(**resolution, &settings, paint_layer_settings.layer_budget);
+
+    info!(
+        "paint canvas atlas can hold {} canvases in up to {:.1} MiB",
+        atlas.canvases_per_page() * atlas.max_pages as usize,
+        atlas.memory_ceiling() as f64 / (1024.0 * 1024.0)
+    );
+
+    command_insert_resource(atlas)
+}
+
+/// Makes the pixels of a canvas transparent where the [`PaintBrush`] doesn't paint.
+fn mask_canvas(
+    canvas: &mut Image,
+    brush: &PaintBrush,
+    settings: &PaintBrushSettings,
+    mask_image: Option<&Image>,
+) {
+    if brush.shape == BrushShape::Viewport {
+        return;
+    }
+
+    let size = canvas.size();
+    let aspect_ratio = size.x as f32 / size.y as f32;
+
+    let Some(data) = canvas.data.as_mut() else {
+        return;
+    };
+
+    for (index, pixel) in data.chunks_exact_mut(CANVAS_PIXEL_SIZE).enumerate() {
+        let coords = UVec2::new(index as u32 % size.x, index as u32 / size.x);
+        let uv = (coords.as_vec2() + 0.5) / size.as_vec2();
+
+        let alpha = brush.alpha(settings, aspect_ratio, mask_image, uv);
+
+        pixel[3] = (pixel[3] as f32 * alpha).round() as u8;
+    }
+}
+
+/// Observer that writes a screenshot into the [`PaintCanvasAtlas`] as the pending canvas, masked
+/// by the [`PaintBrush`], for [`CanvasCopyMode::CpuReadback`].
+pub fn save_screenshot_to_canvas(
+    screenshot: On<ScreenshotCaptured>,
+    brush: Single<&PaintBrush, With<PaintSkiesCamera>>,
+    brush_settings: Res<PaintBrushSettings>,
+    mut atlas: ResMut<PaintCanvasAtlas>,
+    mut images: ResMut<Assets<Image>>,
+    mut materials: ResMut<Assets<PaintedSkyMaterial>>,
+) {
+    let canvas = if screenshot.image.texture_descriptor.format == CANVAS_FORMAT {
+        Some(screenshot.image.clone())
+    } else {
+        screenshot.image.convert(CANVAS_FORMAT)
+    };
+
+    let Some(mut canvas) = canvas.filter(|canvas| canvas.size() == atlas.canvas_size) else {
+        warn!("screenshot doesn't match the canvas atlas, so it can't be painted with");
+        return;
+    };
+
+    mask_canvas(
+        &mut canvas,
+        &brush,
+        &brush_settings,
+        brush_settings
+            .mask_image
+            .as_ref()
+            .and_then(|mask_image| images.get(mask_image)),
+    );
+
+    let Some(slot) = atlas.reserve_slot(&mut images, &mut materials) else {
+        return;
+    };
+
+    let Some(page_image) = atlas
+        .page(slot)
+        .and_then(|page| images.get_mut(&page.image))
+    else {
+        return;
+    };
+
+    atlas.write_canvas(page_image, slot, &canvas);
+
+    atlas.set_pending(slot);
+}
+
+/// System that frees the canvases of layers that no [`PaintedMesh`] uses anymore, or that can't be
+/// reached by redoing or switching branches.
+///
+/// It runs right after new layers are painted, so that layers which painted no meshes at all don't
+/// hold on to their canvases.
+fn free_unused_canvases(
+    atlas: Res<PaintCanvasAtlas>,
+    history: Single<&PaintableHistory<HistoryUnit>>,
+    painted_meshes: Query<&PaintedMesh>,
+) -> ResSet<PaintCanvasAtlas> {
+    let used_layers = painted_meshes
+        .iter()
+        .map(|painted_mesh| (painted_mesh.paint_layer, painted_mesh.paint_branch))
+        .collect::<HashSet<_>>();
+
+    res_set(atlas.clone().retain_layers(|layer, branch| {
+        used_layers.contains(&(layer, branch)) && history.can_reach(layer, branch)
+    }))
+}
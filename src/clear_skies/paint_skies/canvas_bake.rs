@@ -0,0 +1,410 @@
+use bevy::core_pipeline::FullscreenShader;
+use bevy::prelude::*;
+use bevy::render::graph::CameraDriverLabel;
+use bevy::render::render_asset::RenderAssets;
+use bevy::render::render_graph::{
+    Node,
+    NodeRunError,
+    RenderGraph,
+    RenderGraphContext,
+    RenderLabel,
+};
+use bevy::render::render_resource::binding_types::{texture_2d, uniform_buffer};
+use bevy::render::render_resource::{
+    BindGroup,
+    BindGroupEntries,
+    BindGroupLayoutDescriptor,
+    BindGroupLayoutEntries,
+    CachedRenderPipelineId,
+    ColorTargetState,
+    ColorWrites,
+    Extent3d,
+    FragmentState,
+    LoadOp,
+    Operations,
+    Origin3d,
+    PipelineCache,
+    RenderPassColorAttachment,
+    RenderPassDescriptor,
+    RenderPipelineDescriptor,
+    ShaderStages,
+    ShaderType,
+    StoreOp,
+    TexelCopyTextureInfo,
+    Texture,
+    TextureAspect,
+    TextureDescriptor,
+    TextureDimension,
+    TextureSampleType,
+    TextureUsages,
+    TextureView,
+    TextureViewDescriptor,
+    UniformBuffer,
+};
+use bevy::render::renderer::{RenderContext, RenderDevice, RenderQueue};
+use bevy::render::texture::GpuImage;
+use bevy::render::{Extract, ExtractSchedule, Render, RenderApp, RenderStartup, RenderSystems};
+use bevy_pipe_affect::prelude::*;
+
+use crate::clear_skies::paint_skies::canvas_atlas::{
+    CANVAS_FORMAT,
+    PaintCanvasAtlas,
+    PaintCanvasAtlasSettings,
+};
+use crate::clear_skies::paint_skies::canvas_copy::CanvasCopyMode;
+
+/// The path of the shader that bakes pages of the [`PaintCanvasAtlas`].
+const CANVAS_BAKE_SHADER_PATH: &str = "shaders/canvas_bake.wgsl";
+
+/// Plugin that bakes pages of the [`PaintCanvasAtlas`] into one of their slots when paint layers
+/// are compacted, so that compacted layers don't hold on to a slot each.
+///
+/// Pages are baked on the GPU for [`CanvasCopyMode::Gpu`], and on the CPU otherwise.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Reflect)]
+pub struct CanvasBakePlugin;
+
+impl Plugin for CanvasBakePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_message::<BakeCanvasPage>()
+            .init_resource::<CanvasBakeQueue>()
+            .add_systems(First, finish_canvas_bakes.pipe(affect))
+            .add_systems(
+                PostUpdate,
+                (bake_canvas_pages_on_cpu, queue_canvas_bakes.pipe(affect))
+                    .run_if(resource_exists::<PaintCanvasAtlas>)
+                    .run_if(on_message::<BakeCanvasPage>),
+            );
+
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app
+            .init_resource::<PendingCanvasBakes>()
+            .init_resource::<PreparedCanvasBakes>()
+            .add_systems(RenderStartup, init_canvas_bake_pipeline)
+            .add_systems(ExtractSchedule, extract_canvas_bakes)
+            .add_systems(
+                Render,
+                prepare_canvas_bakes
+                    .in_set(RenderSystems::PrepareBindGroups)
+                    .in_set(PrepareCanvasBakesSet),
+            );
+
+        let mut render_graph = render_app.world_mut().resource_mut::<RenderGraph>();
+        render_graph.add_node(CanvasBakeLabel, CanvasBakeNode);
+        // Pages are baked before anything is rendered or copied into the slots this frees.
+        render_graph.add_node_edge(CanvasBakeLabel, CameraDriverLabel);
+    }
+}
+
+/// Render world system set for the system preparing page bakes, which canvas copies wait for.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default, Hash, SystemSet)]
+pub struct PrepareCanvasBakesSet;
+
+/// Message requesting that the whole page of the given [`PaintCanvasAtlas`] slot be shrunk into
+/// that slot.
+///
+/// Every canvas on the page ends up in the slot at a fraction of its resolution, so that baked
+/// meshes can keep showing the canvases of compacted layers once their slots are freed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Message)]
+pub struct BakeCanvasPage {
+    /// The [`CanvasSlot::Baked`](crate::clear_skies::paint_skies::canvas_atlas::CanvasSlot::Baked)
+    /// slot to bake the page into.
+    pub slot: usize,
+}
+
+/// The layout of a [`PaintCanvasAtlas`] page, as a shader uniform.
+#[derive(Debug, Default, Copy, Clone, PartialEq, ShaderType)]
+pub struct CanvasBakeParams {
+    page_grid: UVec2,
+}
+
+/// A page bake, waiting to be run by the render graph.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CanvasBakeRequest {
+    /// The atlas page image being baked.
+    page: Handle<Image>,
+    /// The pixel position of the baked slot within the page.
+    origin: UVec2,
+    /// The size of the baked slot.
+    canvas_size: UVec2,
+    params: CanvasBakeParams,
+}
+
+/// Resource of the page bakes queued this frame, which are extracted to the render world.
+#[derive(Debug, Default, Clone, PartialEq, Deref, DerefMut, Resource)]
+struct CanvasBakeQueue(Vec<CanvasBakeRequest>);
+
+/// System that bakes pages into their slot of the main world page image, for
+/// [`CanvasCopyMode::CpuReadback`].
+///
+/// It runs before the next screenshot can be written into the slots that compaction freed.
+fn bake_canvas_pages_on_cpu(
+    mut bakes: MessageReader<BakeCanvasPage>,
+    atlas: Res<PaintCanvasAtlas>,
+    atlas_settings: Res<PaintCanvasAtlasSettings>,
+    mut images: ResMut<Assets<Image>>,
+) {
+    if atlas_settings.copy_mode != CanvasCopyMode::CpuReadback {
+        return;
+    }
+
+    for &BakeCanvasPage { slot } in bakes.read() {
+        if let Some(page_image) = atlas
+            .page(slot)
+            .and_then(|page| images.get_mut(&page.image))
+        {
+            atlas.bake_page(page_image, slot);
+        }
+    }
+}
+
+/// System that queues page bakes for the render graph, for [`CanvasCopyMode::Gpu`].
+fn queue_canvas_bakes(
+    mut bakes: MessageReader<BakeCanvasPage>,
+    atlas: Res<PaintCanvasAtlas>,
+    atlas_settings: Res<PaintCanvasAtlasSettings>,
+) -> Option<ResSet<CanvasBakeQueue>> {
+    let requests = bakes
+        .read()
+        .filter_map(|&BakeCanvasPage { slot }| {
+            Some(CanvasBakeRequest {
+                page: atlas.page(slot)?.image.clone(),
+                origin: atlas.slot_origin(slot),
+                canvas_size: atlas.canvas_size(),
+                params: CanvasBakeParams {
+                    page_grid: atlas.page_grid(),
+                },
+            })
+        })
+        .collect();
+
+    (atlas_settings.copy_mode == CanvasCopyMode::Gpu).then(|| res_set(CanvasBakeQueue(requests)))
+}
+
+/// System that clears the page bakes that were extracted to the render world last frame.
+fn finish_canvas_bakes(queue: Res<CanvasBakeQueue>) -> Option<ResSet<CanvasBakeQueue>> {
+    (!queue.is_empty()).then(|| res_set(CanvasBakeQueue::default()))
+}
+
+/// The render graph label of the [`CanvasBakeNode`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, RenderLabel)]
+struct CanvasBakeLabel;
+
+/// Render world resource holding the pipeline that bakes pages.
+#[derive(Resource)]
+struct CanvasBakePipeline {
+    layout: BindGroupLayoutDescriptor,
+    pipeline: CachedRenderPipelineId,
+}
+
+fn init_canvas_bake_pipeline(
+    mut commands: Commands,
+    fullscreen_shader: Res<FullscreenShader>,
+    asset_server: Res<AssetServer>,
+    pipeline_cache: Res<PipelineCache>,
+) {
+    let layout = BindGroupLayoutDescriptor::new(
+        "canvas_bake_bind_group_layout",
+        &BindGroupLayoutEntries::sequential(
+            ShaderStages::FRAGMENT,
+            (
+                texture_2d(TextureSampleType::Float { filterable: false }),
+                uniform_buffer::<CanvasBakeParams>(false),
+            ),
+        ),
+    );
+
+    let pipeline = pipeline_cache.queue_render_pipeline(RenderPipelineDescriptor {
+        label: Some("canvas_bake_pipeline".into()),
+        layout: vec![layout.clone()],
+        vertex: fullscreen_shader.to_vertex_state(),
+        fragment: Some(FragmentState {
+            shader: asset_server.load(CANVAS_BAKE_SHADER_PATH),
+            targets: vec![Some(ColorTargetState {
+                format: CANVAS_FORMAT,
+                blend: None,
+                write_mask: ColorWrites::ALL,
+            })],
+            ..default()
+        }),
+        ..default()
+    });
+
+    commands.insert_resource(CanvasBakePipeline { layout, pipeline });
+}
+
+/// Render world resource of the page bakes that haven't been drawn yet.
+///
+/// Canvas copies wait for these, since they may be copied into the slots that baking frees.
+#[derive(Debug, Default, Deref, DerefMut, Resource)]
+pub struct PendingCanvasBakes(Vec<CanvasBakeRequest>);
+
+/// A page bake with everything the [`CanvasBakeNode`] needs to draw it.
+struct PreparedCanvasBake {
+    bind_group: BindGroup,
+    /// The texture the page is shrunk into before it's copied into the baked slot.
+    ///
+    /// A page can't be drawn into while it's sampled.
+    scratch: Texture,
+    scratch_view: TextureView,
+    /// The atlas page being baked.
+    page: Texture,
+    /// The pixel position of the baked slot within the page.
+    origin: UVec2,
+    /// The size of the baked slot.
+    size: Extent3d,
+}
+
+/// Render world resource of the page bakes that are drawn this frame.
+#[derive(Default, Deref, DerefMut, Resource)]
+struct PreparedCanvasBakes(Vec<PreparedCanvasBake>);
+
+/// Extract system that adds the page bakes queued this frame to the [`PendingCanvasBakes`].
+fn extract_canvas_bakes(
+    queue: Extract<Res<CanvasBakeQueue>>,
+    mut pending: ResMut<PendingCanvasBakes>,
+) {
+    if queue.is_changed() {
+        pending.extend(queue.iter().cloned());
+    }
+}
+
+/// System that prepares every pending page bake once the pipeline is ready and the page is on the
+/// GPU.
+fn prepare_canvas_bakes(
+    mut pending: ResMut<PendingCanvasBakes>,
+    mut prepared: ResMut<PreparedCanvasBakes>,
+    bake_pipeline: Res<CanvasBakePipeline>,
+    pipeline_cache: Res<PipelineCache>,
+    gpu_images: Res<RenderAssets<GpuImage>>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    prepared.clear();
+
+    if pipeline_cache
+        .get_render_pipeline(bake_pipeline.pipeline)
+        .is_none()
+    {
+        return;
+    }
+
+    let layout = pipeline_cache.get_bind_group_layout(&bake_pipeline.layout);
+
+    pending.retain(|request| {
+        let Some(page) = gpu_images.get(&request.page) else {
+            return true;
+        };
+
+        let mut params = UniformBuffer::from(request.params);
+        params.write_buffer(&render_device, &render_queue);
+
+        let Some(params) = params.binding() else {
+            return true;
+        };
+
+        let size = Extent3d {
+            width: request.canvas_size.x,
+            height: request.canvas_size.y,
+            depth_or_array_layers: 1,
+        };
+
+        let scratch = render_device.create_texture(&TextureDescriptor {
+            label: Some("canvas_bake_scratch_texture"),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: CANVAS_FORMAT,
+            usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+
+        prepared.push(PreparedCanvasBake {
+            bind_group: render_device.create_bind_group(
+                "canvas_bake_bind_group",
+                &layout,
+                &BindGroupEntries::sequential((&page.texture_view, params)),
+            ),
+            scratch_view: scratch.create_view(&TextureViewDescriptor::default()),
+            scratch,
+            page: page.texture.clone(),
+            origin: request.origin,
+            size,
+        });
+
+        false
+    });
+}
+
+/// Render graph node that bakes every prepared page into its slot.
+struct CanvasBakeNode;
+
+impl Node for CanvasBakeNode {
+    fn run<'w>(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext<'w>,
+        world: &'w World,
+    ) -> Result<(), NodeRunError> {
+        let prepared = world.resource::<PreparedCanvasBakes>();
+
+        if prepared.is_empty() {
+            return Ok(());
+        }
+
+        let bake_pipeline = world.resource::<CanvasBakePipeline>();
+        let pipeline_cache = world.resource::<PipelineCache>();
+
+        // Bakes are only prepared once the pipeline is ready.
+        let Some(pipeline) = pipeline_cache.get_render_pipeline(bake_pipeline.pipeline) else {
+            return Ok(());
+        };
+
+        for bake in prepared.iter() {
+            {
+                let mut render_pass =
+                    render_context
+                        .command_encoder()
+                        .begin_render_pass(&RenderPassDescriptor {
+                            label: Some("canvas_bake_pass"),
+                            color_attachments: &[Some(RenderPassColorAttachment {
+                                view: &bake.scratch_view,
+                                depth_slice: None,
+                                resolve_target: None,
+                                ops: Operations {
+                                    load: LoadOp::Clear(default()),
+                                    store: StoreOp::Store,
+                                },
+                            })],
+                            depth_stencil_attachment: None,
+                            timestamp_writes: None,
+                            occlusion_query_set: None,
+                        });
+
+                render_pass.set_pipeline(pipeline);
+                render_pass.set_bind_group(0, &bake.bind_group, &[]);
+                render_pass.draw(0..3, 0..1);
+            }
+
+            render_context.command_encoder().copy_texture_to_texture(
+                bake.scratch.as_image_copy(),
+                TexelCopyTextureInfo {
+                    texture: &bake.page,
+                    mip_level: 0,
+                    origin: Origin3d {
+                        x: bake.origin.x,
+                        y: bake.origin.y,
+                        z: 0,
+                    },
+                    aspect: TextureAspect::All,
+                },
+                bake.size,
+            );
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,585 @@
+use std::sync::Mutex;
+use std::sync::mpsc::{Receiver, Sender, channel};
+
+use bevy::core_pipeline::FullscreenShader;
+use bevy::prelude::*;
+use bevy::render::graph::CameraDriverLabel;
+use bevy::render::render_asset::RenderAssets;
+use bevy::render::render_graph::{
+    Node,
+    NodeRunError,
+    RenderGraph,
+    RenderGraphContext,
+    RenderLabel,
+};
+use bevy::render::render_resource::binding_types::{sampler, texture_2d, uniform_buffer};
+use bevy::render::render_resource::{
+    BindGroup,
+    BindGroupEntries,
+    BindGroupLayoutDescriptor,
+    BindGroupLayoutEntries,
+    CachedRenderPipelineId,
+    ColorTargetState,
+    ColorWrites,
+    FragmentState,
+    LoadOp,
+    Operations,
+    PipelineCache,
+    RenderPassColorAttachment,
+    RenderPassDescriptor,
+    RenderPipelineDescriptor,
+    Sampler,
+    SamplerBindingType,
+    SamplerDescriptor,
+    ShaderStages,
+    ShaderType,
+    StoreOp,
+    TextureSampleType,
+    TextureView,
+    UniformBuffer,
+};
+use bevy::render::renderer::{RenderContext, RenderDevice, RenderQueue};
+use bevy::render::texture::{FallbackImageZero, GpuImage};
+use bevy::render::{Extract, ExtractSchedule, Render, RenderApp, RenderStartup, RenderSystems};
+use bevy_pipe_affect::prelude::*;
+
+use crate::clear_skies::camera::PaintSkiesCamera;
+use crate::clear_skies::paint_skies::canvas_atlas::{
+    CANVAS_FORMAT,
+    PaintCanvasAtlas,
+    PaintCanvasAtlasSettings,
+};
+use crate::clear_skies::paint_skies::canvas_bake::{PendingCanvasBakes, PrepareCanvasBakesSet};
+use crate::clear_skies::paint_skies::paint_brush::{BrushShape, PaintBrush, PaintBrushSettings};
+use crate::clear_skies::paint_skies::painted_sky_material::PaintedSkyMaterial;
+
+/// The path of the shader that copies canvases into the [`PaintCanvasAtlas`].
+const CANVAS_COPY_SHADER_PATH: &str = "shaders/canvas_copy.wgsl";
+
+/// Plugin that copies canvases into the [`PaintCanvasAtlas`] on the GPU, for
+/// [`CanvasCopyMode::Gpu`].
+///
+/// The render world reports every copy it draws back to the main world, so [`CanvasCopied`] is
+/// only triggered once the canvas is actually in its slot.
+///
+/// Without a render app, such as in headless tests, nothing would ever report a copy, so
+/// [`CanvasCopyMode::CpuReadback`] is used instead.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Reflect)]
+pub struct CanvasCopyPlugin;
+
+impl Plugin for CanvasCopyPlugin {
+    fn build(&self, app: &mut App) {
+        let (sender, receiver) = channel();
+
+        app.init_resource::<CanvasCopyQueue>()
+            .insert_resource(CanvasCopyReceiver(Mutex::new(receiver)))
+            .register_type::<CanvasCopyMode>()
+            .add_systems(First, finish_canvas_copies.pipe(affect))
+            .add_systems(
+                Update,
+                queue_canvas_copies
+                    .pipe(affect)
+                    .run_if(resource_exists::<PaintCanvasAtlas>),
+            );
+
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            app.world_mut()
+                .get_resource_or_init::<PaintCanvasAtlasSettings>()
+                .copy_mode = CanvasCopyMode::CpuReadback;
+            return;
+        };
+
+        render_app
+            .insert_resource(CanvasCopySender(sender))
+            .init_resource::<PendingCanvasCopies>()
+            .init_resource::<PreparedCanvasCopies>()
+            .add_systems(RenderStartup, init_canvas_copy_pipeline)
+            .add_systems(ExtractSchedule, extract_canvas_copies)
+            .add_systems(
+                Render,
+                prepare_canvas_copies
+                    .in_set(RenderSystems::PrepareBindGroups)
+                    .after(PrepareCanvasBakesSet),
+            );
+
+        let mut render_graph = render_app.world_mut().resource_mut::<RenderGraph>();
+        render_graph.add_node(CanvasCopyLabel, CanvasCopyNode);
+        render_graph.add_node_edge(CameraDriverLabel, CanvasCopyLabel);
+    }
+}
+
+/// How canvases are copied from the render target into the [`PaintCanvasAtlas`].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Reflect)]
+pub enum CanvasCopyMode {
+    /// A render graph node copies the render target straight into the canvas slot.
+    #[default]
+    Gpu,
+    /// The render target is screenshotted, masked on the CPU, and written into the page image.
+    ///
+    /// This is slower, since every canvas is read back from the GPU, but keeps the main world page
+    /// images up to date, so saving a session doesn't need to read them back.
+    CpuReadback,
+}
+
+/// Component requesting a copy of the given render target into the [`PaintCanvasAtlas`] as the
+/// pending canvas, masked by the [`PaintBrush`].
+///
+/// [`CanvasCopied`] is triggered on the entity once the copy is done, before it's despawned.
+#[derive(Debug, Clone, PartialEq, Eq, Component)]
+pub struct CanvasCopy(pub Handle<Image>);
+
+/// Event triggered on a [`CanvasCopy`] entity once its canvas is in the [`PaintCanvasAtlas`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, EntityEvent)]
+pub struct CanvasCopied {
+    /// The [`CanvasCopy`] entity.
+    pub entity: Entity,
+}
+
+/// The brush shape codes understood by the canvas copy shader.
+const BRUSH_SHAPE_VIEWPORT: u32 = 0;
+const BRUSH_SHAPE_CIRCLE: u32 = 1;
+const BRUSH_SHAPE_SOFT_ELLIPSE: u32 = 2;
+const BRUSH_SHAPE_MASK_IMAGE: u32 = 3;
+
+/// The [`PaintBrush`] of a canvas copy, as a shader uniform.
+#[derive(Debug, Default, Copy, Clone, PartialEq, ShaderType)]
+pub struct CanvasCopyBrush {
+    radii: Vec2,
+    shape: u32,
+    softness: f32,
+}
+
+impl CanvasCopyBrush {
+    fn new(brush: &PaintBrush, settings: &PaintBrushSettings, aspect_ratio: f32) -> Self {
+        CanvasCopyBrush {
+            radii: brush.radii(settings, aspect_ratio),
+            shape: match brush.shape {
+                BrushShape::Viewport => BRUSH_SHAPE_VIEWPORT,
+                BrushShape::Circle => BRUSH_SHAPE_CIRCLE,
+                BrushShape::SoftEllipse => BRUSH_SHAPE_SOFT_ELLIPSE,
+                BrushShape::MaskImage => BRUSH_SHAPE_MASK_IMAGE,
+            },
+            softness: settings.softness.clamp(f32::EPSILON, 1.0),
+        }
+    }
+}
+
+/// A copy of a render target into a canvas slot, waiting to be run by the render graph.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CanvasCopyRequest {
+    /// The [`CanvasCopy`] entity.
+    entity: Entity,
+    /// The render target being copied.
+    source: Handle<Image>,
+    /// The atlas page image being copied into.
+    page: Handle<Image>,
+    /// The pixel position of the canvas slot within the page.
+    origin: UVec2,
+    brush: CanvasCopyBrush,
+    mask_image: Option<Handle<Image>>,
+}
+
+/// Resource of the canvas copies queued this frame, which are extracted to the render world.
+#[derive(Debug, Default, Clone, PartialEq, Deref, DerefMut, Resource)]
+pub struct CanvasCopyQueue(Vec<CanvasCopyRequest>);
+
+/// Resource receiving the [`CanvasCopy`] entities whose copies the render graph has drawn.
+#[derive(Debug, Resource)]
+pub struct CanvasCopyReceiver(Mutex<Receiver<Entity>>);
+
+/// Render world resource sending the [`CanvasCopy`] entities whose copies were drawn back to the
+/// main world.
+#[derive(Debug, Resource)]
+struct CanvasCopySender(Sender<Entity>);
+
+/// System that reserves canvas slots for new [`CanvasCopy`]s and queues their copies.
+///
+/// Copies that can't get a slot finish right away.
+fn queue_canvas_copies(
+    new_copies: Query<(Entity, &CanvasCopy), Added<CanvasCopy>>,
+    brush: Single<&PaintBrush, With<PaintSkiesCamera>>,
+    brush_settings: Res<PaintBrushSettings>,
+    mut atlas: ResMut<PaintCanvasAtlas>,
+    mut queue: ResMut<CanvasCopyQueue>,
+    mut images: ResMut<Assets<Image>>,
+    mut materials: ResMut<Assets<PaintedSkyMaterial>>,
+) -> Vec<(CommandTrigger<CanvasCopied>, EntityCommandDespawn)> {
+    let canvas_size = atlas.canvas_size();
+    let brush = CanvasCopyBrush::new(
+        &brush,
+        &brush_settings,
+        canvas_size.x as f32 / canvas_size.y as f32,
+    );
+
+    new_copies
+        .iter()
+        .filter_map(|(entity, CanvasCopy(source))| {
+            let request = atlas
+                .reserve_slot(&mut images, &mut materials)
+                .and_then(|slot| {
+                    let page = atlas.page(slot)?.image.clone();
+                    atlas.set_pending(slot);
+
+                    Some(CanvasCopyRequest {
+                        entity,
+                        source: source.clone(),
+                        page,
+                        origin: atlas.slot_origin(slot),
+                        brush,
+                        mask_image: brush_settings.mask_image.clone(),
+                    })
+                });
+
+            match request {
+                Some(request) => {
+                    queue.push(request);
+                    None
+                }
+                None => Some((
+                    command_trigger(CanvasCopied { entity }),
+                    entity_command_despawn(entity),
+                )),
+            }
+        })
+        .collect()
+}
+
+/// System that clears the canvas copies that were extracted to the render world last frame, and
+/// finishes the ones that the render graph reported as drawn.
+fn finish_canvas_copies(
+    queue: Res<CanvasCopyQueue>,
+    receiver: Res<CanvasCopyReceiver>,
+) -> (
+    Option<ResSet<CanvasCopyQueue>>,
+    Vec<(CommandTrigger<CanvasCopied>, EntityCommandDespawn)>,
+) {
+    let drawn = match receiver.0.lock() {
+        Ok(receiver) => receiver.try_iter().collect(),
+        Err(_) => vec![],
+    };
+
+    (
+        (!queue.is_empty()).then(|| res_set(CanvasCopyQueue::default())),
+        drawn
+            .into_iter()
+            .map(|entity| {
+                (
+                    command_trigger(CanvasCopied { entity }),
+                    entity_command_despawn(entity),
+                )
+            })
+            .collect(),
+    )
+}
+
+/// Render world resource of the canvas copies that haven't been drawn yet.
+#[derive(Debug, Default, Deref, DerefMut, Resource)]
+struct PendingCanvasCopies(Vec<CanvasCopyRequest>);
+
+/// A canvas copy with everything the [`CanvasCopyNode`] needs to draw it.
+struct PreparedCanvasCopy {
+    /// The [`CanvasCopy`] entity.
+    entity: Entity,
+    bind_group: BindGroup,
+    /// The atlas page being copied into.
+    page: TextureView,
+    /// The pixel position of the canvas slot within the page.
+    origin: UVec2,
+    /// The size of the render target being copied.
+    size: UVec2,
+}
+
+/// Render world resource of the canvas copies that are drawn this frame.
+#[derive(Default, Deref, DerefMut, Resource)]
+struct PreparedCanvasCopies(Vec<PreparedCanvasCopy>);
+
+/// Extract system that adds the canvas copies queued this frame to the [`PendingCanvasCopies`].
+fn extract_canvas_copies(
+    queue: Extract<Res<CanvasCopyQueue>>,
+    mut pending: ResMut<PendingCanvasCopies>,
+) {
+    if queue.is_changed() {
+        pending.extend(queue.iter().cloned());
+    }
+}
+
+/// System that prepares every pending canvas copy that can be drawn this frame.
+///
+/// Copies stay pending while the pipeline is compiling or their images aren't on the GPU yet, and
+/// while any pages are waiting to be baked, since canvases may be copied into slots that baking
+/// frees.
+fn prepare_canvas_copies(
+    mut pending: ResMut<PendingCanvasCopies>,
+    mut prepared: ResMut<PreparedCanvasCopies>,
+    pending_bakes: Res<PendingCanvasBakes>,
+    copy_pipeline: Res<CanvasCopyPipeline>,
+    pipeline_cache: Res<PipelineCache>,
+    gpu_images: Res<RenderAssets<GpuImage>>,
+    no_mask: Res<FallbackImageZero>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    prepared.clear();
+
+    if !pending_bakes.is_empty()
+        || pipeline_cache
+            .get_render_pipeline(copy_pipeline.pipeline)
+            .is_none()
+    {
+        return;
+    }
+
+    let layout = pipeline_cache.get_bind_group_layout(&copy_pipeline.layout);
+
+    pending.retain(|request| {
+        let (Some(source), Some(page)) = (
+            gpu_images.get(&request.source),
+            gpu_images.get(&request.page),
+        ) else {
+            return true;
+        };
+
+        // Mask brushes without a mask image paint nothing, like on the CPU.
+        let mask = request
+            .mask_image
+            .as_ref()
+            .and_then(|mask_image| gpu_images.get(mask_image))
+            .unwrap_or(&no_mask);
+
+        let mut brush = UniformBuffer::from(request.brush);
+        brush.write_buffer(&render_device, &render_queue);
+
+        let Some(brush) = brush.binding() else {
+            return true;
+        };
+
+        let bind_group = render_device.create_bind_group(
+            "canvas_copy_bind_group",
+            &layout,
+            &BindGroupEntries::sequential((
+                &source.texture_view,
+                &copy_pipeline.sampler,
+                brush,
+                &mask.texture_view,
+            )),
+        );
+
+        prepared.push(PreparedCanvasCopy {
+            entity: request.entity,
+            bind_group,
+            page: page.texture_view.clone(),
+            origin: request.origin,
+            size: UVec2::new(source.size.width, source.size.height),
+        });
+
+        false
+    });
+}
+
+/// The render graph label of the [`CanvasCopyNode`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, RenderLabel)]
+struct CanvasCopyLabel;
+
+/// Render world resource holding the pipeline that copies canvases.
+#[derive(Resource)]
+struct CanvasCopyPipeline {
+    layout: BindGroupLayoutDescriptor,
+    sampler: Sampler,
+    pipeline: CachedRenderPipelineId,
+}
+
+fn init_canvas_copy_pipeline(
+    mut commands: Commands,
+    render_device: Res<RenderDevice>,
+    fullscreen_shader: Res<FullscreenShader>,
+    asset_server: Res<AssetServer>,
+    pipeline_cache: Res<PipelineCache>,
+) {
+    let layout = BindGroupLayoutDescriptor::new(
+        "canvas_copy_bind_group_layout",
+        &BindGroupLayoutEntries::sequential(
+            ShaderStages::FRAGMENT,
+            (
+                texture_2d(TextureSampleType::Float { filterable: false }),
+                sampler(SamplerBindingType::NonFiltering),
+                uniform_buffer::<CanvasCopyBrush>(false),
+                texture_2d(TextureSampleType::Float { filterable: false }),
+            ),
+        ),
+    );
+
+    let pipeline = pipeline_cache.queue_render_pipeline(RenderPipelineDescriptor {
+        label: Some("canvas_copy_pipeline".into()),
+        layout: vec![layout.clone()],
+        vertex: fullscreen_shader.to_vertex_state(),
+        fragment: Some(FragmentState {
+            shader: asset_server.load(CANVAS_COPY_SHADER_PATH),
+            targets: vec![Some(ColorTargetState {
+                format: CANVAS_FORMAT,
+                // Canvases replace whatever was in their slot.
+                blend: None,
+                write_mask: ColorWrites::ALL,
+            })],
+            ..default()
+        }),
+        ..default()
+    });
+
+    commands.insert_resource(CanvasCopyPipeline {
+        layout,
+        sampler: render_device.create_sampler(&SamplerDescriptor::default()),
+        pipeline,
+    });
+}
+
+/// Render graph node that draws every prepared canvas copy into its slot of the atlas page, and
+/// reports it as drawn to the main world.
+struct CanvasCopyNode;
+
+impl Node for CanvasCopyNode {
+    fn run<'w>(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext<'w>,
+        world: &'w World,
+    ) -> Result<(), NodeRunError> {
+        let prepared = world.resource::<PreparedCanvasCopies>();
+
+        if prepared.is_empty() {
+            return Ok(());
+        }
+
+        let copy_pipeline = world.resource::<CanvasCopyPipeline>();
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let sender = world.resource::<CanvasCopySender>();
+
+        // Copies are only prepared once the pipeline is ready.
+        let Some(pipeline) = pipeline_cache.get_render_pipeline(copy_pipeline.pipeline) else {
+            return Ok(());
+        };
+
+        for copy in prepared.iter() {
+            let mut render_pass =
+                render_context
+                    .command_encoder()
+                    .begin_render_pass(&RenderPassDescriptor {
+                        label: Some("canvas_copy_pass"),
+                        color_attachments: &[Some(RenderPassColorAttachment {
+                            view: &copy.page,
+                            depth_slice: None,
+                            resolve_target: None,
+                            ops: Operations {
+                                load: LoadOp::Load,
+                                store: StoreOp::Store,
+                            },
+                        })],
+                        depth_stencil_attachment: None,
+                        timestamp_writes: None,
+                        occlusion_query_set: None,
+                    });
+
+            render_pass.set_viewport(
+                copy.origin.x as f32,
+                copy.origin.y as f32,
+                copy.size.x as f32,
+                copy.size.y as f32,
+                0.0,
+                1.0,
+            );
+            render_pass.set_pipeline(pipeline);
+            render_pass.set_bind_group(0, &copy.bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+
+            // Sending only fails once the main world is gone, which has nothing left to finish.
+            let _ = sender.0.send(copy.entity);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::asset::RenderAssetUsages;
+    use bevy::ecs::system::RunSystemOnce;
+    use bevy::render::render_resource::{Extent3d, TextureDimension};
+    use bevy::render::view::screenshot::{Screenshot, ScreenshotCaptured};
+    use leafwing_input_manager::prelude::ActionState;
+
+    use super::*;
+    use crate::clear_skies::camera::{ClearSkiesRenderTarget, PaintSkiesAction};
+    use crate::clear_skies::paint_skies::material_transfer::PaintSource;
+    use crate::clear_skies::paint_skies::paint_layer_history::{HistoryUnit, RecordPresent};
+    use crate::clear_skies::paint_skies::paint_meshes::{LayerIndex, capture_canvas};
+
+    #[test]
+    fn headless_painting_completes_layers() {
+        let mut app = App::new();
+        app.init_resource::<PaintCanvasAtlasSettings>()
+            .init_resource::<PaintBrushSettings>()
+            .init_resource::<Assets<Image>>()
+            .init_resource::<Assets<PaintedSkyMaterial>>()
+            .add_message::<RecordPresent>()
+            .add_plugins(CanvasCopyPlugin);
+
+        let settings = *app.world().resource::<PaintCanvasAtlasSettings>();
+        assert_eq!(settings.copy_mode, CanvasCopyMode::CpuReadback);
+
+        let canvas_size = UVec2::new(4, 4);
+
+        let world = app.world_mut();
+        world.insert_resource(PaintCanvasAtlas::new(canvas_size, &settings, 4));
+        world.spawn(HistoryUnit);
+        world.spawn((PaintSkiesCamera, ActionState::<PaintSkiesAction>::default()));
+
+        world
+            .run_system_once(
+                (move || {
+                    capture_canvas(
+                        &ClearSkiesRenderTarget::default(),
+                        settings.copy_mode,
+                        PaintSource::Canvas,
+                    )
+                })
+                .pipe(affect),
+            )
+            .unwrap();
+
+        let screenshot = world
+            .query_filtered::<Entity, With<Screenshot>>()
+            .single(world)
+            .unwrap();
+
+        world.trigger(ScreenshotCaptured {
+            entity: screenshot,
+            image: Image::new_fill(
+                Extent3d {
+                    width: canvas_size.x,
+                    height: canvas_size.y,
+                    depth_or_array_layers: 1,
+                },
+                TextureDimension::D2,
+                &[255; 4],
+                CANVAS_FORMAT,
+                RenderAssetUsages::all(),
+            ),
+        });
+
+        assert_eq!(
+            world
+                .resource::<Messages<RecordPresent>>()
+                .iter_current_update_messages()
+                .collect::<Vec<_>>(),
+            [&RecordPresent {
+                layer: LayerIndex(1)
+            }]
+        );
+
+        assert!(
+            world
+                .resource::<PaintCanvasAtlas>()
+                .pending_slot()
+                .is_some()
+        );
+    }
+}
@@ -0,0 +1,186 @@
+use bevy::camera::primitives::{Frustum, HalfSpace};
+use bevy::prelude::*;
+
+/// The side and near planes of a camera's view frustum, which painted triangles are clipped to.
+///
+/// The far plane is left out, since Bevy's default perspective projection is an infinite reverse-Z
+/// one, which has no far plane. Its far half-space has a zero normal, so clipping to it would only
+/// produce NaNs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PaintFrustum(Vec<HalfSpace>);
+
+impl PaintFrustum {
+    /// Returns the [`PaintFrustum`] of the given camera at the given transform.
+    pub fn new(camera: &Camera, camera_transform: &GlobalTransform) -> Self {
+        let clip_from_world = camera.clip_from_view() * camera_transform.to_matrix().inverse();
+        let Frustum { half_spaces } = Frustum::from_clip_from_world(&clip_from_world);
+
+        PaintFrustum(half_spaces[..5].to_vec())
+    }
+
+    /// Returns this [`PaintFrustum`], further limited to the given half-spaces.
+    pub fn with_half_spaces(mut self, half_spaces: impl IntoIterator<Item = HalfSpace>) -> Self {
+        self.0.extend(half_spaces);
+        self
+    }
+}
+
+/// Clips a triangle against every given frustum, returning the part inside all of them split into
+/// triangles.
+///
+/// Each frustum is paired with the transform from the triangle's space into its world, so that a
+/// triangle can be clipped where a moving mesh and camera were at different times. Since the
+/// clipping happens in the triangle's own space, the returned triangles cover the same part of the
+/// triangle in all of them.
+pub fn clip_triangle(
+    triangle: Triangle3d,
+    frustums: &[(&PaintFrustum, &GlobalTransform)],
+) -> Vec<Triangle3d> {
+    let polygon = frustums.iter().fold(
+        triangle.vertices.to_vec(),
+        |polygon, (PaintFrustum(half_spaces), transform)| {
+            half_spaces.iter().fold(polygon, |polygon, half_space| {
+                clip_polygon(polygon, |vertex| {
+                    half_space
+                        .normal_d()
+                        .dot(transform.transform_point(vertex).extend(1.0))
+                })
+            })
+        },
+    );
+
+    // The clipped polygon is convex, so it can be split into a fan.
+    polygon
+        .get(1..)
+        .unwrap_or_default()
+        .windows(2)
+        .map(|edge| Triangle3d::new(polygon[0], edge[0], edge[1]))
+        .collect()
+}
+
+/// Clips a convex polygon to where the given signed distance is positive.
+///
+/// The signed distance must be linear in the polygon's space, so that edges can be split where
+/// they cross zero.
+fn clip_polygon(polygon: Vec<Vec3>, signed_distance: impl Fn(Vec3) -> f32) -> Vec<Vec3> {
+    let distances = polygon
+        .iter()
+        .map(|vertex| signed_distance(*vertex))
+        .collect::<Vec<_>>();
+
+    if distances.iter().all(|distance| *distance >= 0.0) {
+        return polygon;
+    }
+
+    (0..polygon.len())
+        .flat_map(|index| {
+            let next_index = (index + 1) % polygon.len();
+
+            let (vertex, next_vertex) = (polygon[index], polygon[next_index]);
+            let (distance, next_distance) = (distances[index], distances[next_index]);
+
+            let kept = (distance >= 0.0).then_some(vertex);
+
+            let crossing = ((distance >= 0.0) != (next_distance >= 0.0))
+                .then(|| vertex.lerp(next_vertex, distance / (distance - next_distance)));
+
+            [kept, crossing].into_iter().flatten()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Asserts that the polygons have the same vertices in the same order, up to rotation.
+    fn assert_polygon_eq(actual: &[Vec3], expected: &[Vec3]) {
+        assert_eq!(actual.len(), expected.len(), "{actual:?} != {expected:?}");
+
+        let matches_rotated = (0..expected.len()).any(|rotation| {
+            actual
+                .iter()
+                .zip(expected.iter().cycle().skip(rotation))
+                .all(|(a, b)| a.abs_diff_eq(*b, 1e-5))
+        });
+        assert!(matches_rotated, "{actual:?} != {expected:?}");
+    }
+
+    const TRIANGLE: [Vec3; 3] = [
+        Vec3::ZERO,
+        Vec3::new(2.0, 0.0, 0.0),
+        Vec3::new(0.0, 2.0, 0.0),
+    ];
+
+    #[test]
+    fn clip_polygon_keeps_polygons_inside() {
+        let clipped = clip_polygon(TRIANGLE.to_vec(), |vertex| vertex.x + 1.0);
+
+        assert_eq!(clipped, TRIANGLE.to_vec());
+    }
+
+    #[test]
+    fn clip_polygon_removes_polygons_outside() {
+        let clipped = clip_polygon(TRIANGLE.to_vec(), |vertex| -vertex.x - 1.0);
+
+        assert!(clipped.is_empty());
+    }
+
+    #[test]
+    fn clip_polygon_cutting_off_one_vertex_returns_a_quad() {
+        let clipped = clip_polygon(TRIANGLE.to_vec(), |vertex| 1.0 - vertex.x);
+
+        assert_polygon_eq(
+            &clipped,
+            &[
+                Vec3::ZERO,
+                Vec3::new(1.0, 0.0, 0.0),
+                Vec3::new(1.0, 1.0, 0.0),
+                Vec3::new(0.0, 2.0, 0.0),
+            ],
+        );
+    }
+
+    #[test]
+    fn clip_polygon_keeping_one_vertex_returns_a_triangle() {
+        let clipped = clip_polygon(TRIANGLE.to_vec(), |vertex| vertex.x - 1.0);
+
+        assert_polygon_eq(
+            &clipped,
+            &[
+                Vec3::new(1.0, 0.0, 0.0),
+                Vec3::new(2.0, 0.0, 0.0),
+                Vec3::new(1.0, 1.0, 0.0),
+            ],
+        );
+    }
+
+    #[test]
+    fn clip_polygon_keeps_vertices_on_the_plane() {
+        let clipped = clip_polygon(TRIANGLE.to_vec(), |vertex| 2.0 - vertex.x - vertex.y);
+
+        assert_eq!(clipped, TRIANGLE.to_vec());
+    }
+
+    #[test]
+    fn clip_triangle_fans_the_clipped_polygon() {
+        let frustum =
+            PaintFrustum(vec![]).with_half_spaces([HalfSpace::new(Vec4::new(-1.0, 0.0, 0.0, 2.0))]);
+        let transform = GlobalTransform::from_translation(Vec3::X);
+
+        let triangles = clip_triangle(
+            Triangle3d::new(TRIANGLE[0], TRIANGLE[1], TRIANGLE[2]),
+            &[(&frustum, &transform)],
+        );
+
+        assert_eq!(triangles.len(), 2);
+        let area = triangles.iter().map(Triangle3d::area).sum::<f32>();
+        assert!((area - 1.5).abs() < 1e-5);
+        assert!(
+            triangles
+                .iter()
+                .flat_map(|triangle| triangle.vertices)
+                .all(|vertex| vertex.x <= 1.0 + 1e-5)
+        );
+    }
+}
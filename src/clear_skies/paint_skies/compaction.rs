@@ -0,0 +1,363 @@
+use bevy::camera::visibility::RenderLayers;
+use bevy::mesh::VertexAttributeValues;
+use bevy::platform::collections::{HashMap, HashSet};
+use bevy::prelude::*;
+use bevy_pipe_affect::prelude::*;
+use leafwing_input_manager::prelude::*;
+
+use crate::clear_skies::ClearSkiesState;
+use crate::clear_skies::camera::{PaintSkiesAction, PaintSkiesCamera};
+use crate::clear_skies::paint_skies::canvas_atlas::PaintCanvasAtlas;
+use crate::clear_skies::paint_skies::canvas_bake::BakeCanvasPage;
+use crate::clear_skies::paint_skies::paint_layer_history::{
+    CompactPaintLayers,
+    HistoryUnit,
+    PaintableHistory,
+    RecordPaintLayerHistorySet,
+    last_layer_index,
+};
+use crate::clear_skies::paint_skies::paint_meshes::{
+    LayerIndex,
+    PaintLayerSettings,
+    PaintedMaterials,
+    PaintedMesh,
+};
+use crate::clear_skies::paint_skies::painted_sky_material::PaintedSkyMaterial;
+use crate::clear_skies::render_layers::PAINTED_LAYER;
+
+/// Plugin that keeps the number of undoable paint layers within
+/// [`PaintLayerSettings::layer_budget`].
+///
+/// Once the budget is exceeded, the oldest layers are compacted into a checkpoint, and the
+/// [`PaintedMesh`]es painted on them are baked into [`BakedPaintLayers`].
+///
+/// Compaction also forgets the histories of compacted layers. The [`PaintSkiesCamera`] transforms
+/// of the active branch are kept in its [`CompactedCameraPath`] so that rails still fly the whole
+/// path, but replays only cover layers after the checkpoint. See
+/// [`PaintLayerSettings::layer_budget`].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Reflect)]
+pub struct PaintLayerCompactionPlugin;
+
+impl Plugin for PaintLayerCompactionPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_type::<BakedPaintLayers>()
+            .register_type::<CompactedCameraPath>()
+            .add_systems(
+                Update,
+                last_layer_index
+                    .pipe(compact_paint_layers)
+                    .pipe(affect)
+                    .before(RecordPaintLayerHistorySet)
+                    .run_if(in_state(ClearSkiesState::PaintSkies)),
+            );
+    }
+}
+
+/// Component for the [`PaintSkiesCamera`] with its recorded transforms of compacted layers, oldest
+/// first, which its [`PaintableHistory`] has forgotten.
+///
+/// Layers without a recorded transform are left out.
+#[derive(Debug, Default, Clone, PartialEq, Reflect, Component)]
+#[reflect(Component)]
+pub struct CompactedCameraPath(pub Vec<(LayerIndex, GlobalTransform)>);
+
+/// The [`PaintedMesh`] data of a painted mesh that was merged into [`BakedPaintLayers`].
+#[derive(Debug, Clone, PartialEq, Eq, Reflect)]
+pub struct BakedPaintedMesh {
+    /// The entity whose mesh was used to paint the painted mesh.
+    pub painted_from: Entity,
+    /// The triangle of the original mesh that each octahedron was painted from.
+    pub triangle_indices: Vec<usize>,
+    /// The layer that the painted mesh was painted on.
+    pub paint_layer: LayerIndex,
+    /// The first triangle of the baked mesh that the painted mesh's triangles were merged into.
+    pub first_triangle: usize,
+}
+
+/// Component for meshes that painted meshes of compacted layers were merged into.
+///
+/// There is one of these per [`PaintCanvasAtlas`] page, or per compaction and source material for
+/// [`PaintSource::MaterialTransfer`] layers.
+///
+/// The canvases of compacted layers are baked into one [`CanvasSlot::Baked`] slot of their page,
+/// freeing the slots of the layers themselves, so they lose resolution every time more layers on
+/// their page are compacted.
+///
+/// The [`PaintedMesh`] data of every merged mesh is kept, so baked layers can still be collided
+/// with in [`ClearSkiesState::PlaySkies`].
+///
+/// [`PaintSource::MaterialTransfer`]: crate::clear_skies::paint_skies::material_transfer::PaintSource::MaterialTransfer
+/// [`CanvasSlot::Baked`]: crate::clear_skies::paint_skies::canvas_atlas::CanvasSlot::Baked
+#[derive(Debug, Default, Clone, PartialEq, Eq, Reflect, Component)]
+#[reflect(Component)]
+#[require(RenderLayers = PAINTED_LAYER)]
+pub struct BakedPaintLayers {
+    /// Every painted mesh that was merged into this one, in the order their triangles were.
+    pub painted_meshes: Vec<BakedPaintedMesh>,
+}
+
+impl BakedPaintLayers {
+    /// Returns the merged painted mesh that the given triangle of the baked mesh came from.
+    pub fn painted_mesh(&self, triangle: usize) -> Option<&BakedPaintedMesh> {
+        let merged_before = self
+            .painted_meshes
+            .partition_point(|painted_mesh| painted_mesh.first_triangle <= triangle);
+
+        self.painted_meshes.get(merged_before.checked_sub(1)?)
+    }
+}
+
+/// Effect that spawns [`BakedPaintLayers`] with the given mesh and material.
+pub type SpawnBakedPaintLayers<M = PaintedSkyMaterial> =
+    AssetAddAnd<Mesh, CommandSpawn<(Mesh3d, MeshMaterial3d<M>, BakedPaintLayers)>>;
+
+/// Returns the [`SpawnBakedPaintLayers`] effect for the given mesh, material and
+/// [`BakedPaintLayers`].
+pub fn spawn_baked_paint_layers<M: Material>(
+    mesh: Mesh,
+    material: Handle<M>,
+    baked_paint_layers: BakedPaintLayers,
+) -> SpawnBakedPaintLayers<M> {
+    asset_add_and(mesh, move |mesh_handle| {
+        command_spawn((
+            Mesh3d(mesh_handle),
+            MeshMaterial3d(material),
+            baked_paint_layers,
+        ))
+    })
+}
+
+/// Compacts the oldest paint layers once there are more than the budget allows.
+///
+/// Compaction leaves half of the budget undoable, so that it only happens occasionally.
+///
+/// Baked meshes on the pages of compacted layers are baked again along with them, since their
+/// pages are shrunk into a single slot again.
+///
+/// The [`PaintSkiesCamera`] transforms of compacted layers are moved into its
+/// [`CompactedCameraPath`].
+fn compact_paint_layers(
+    In(last_layer_index): In<LayerIndex>,
+    settings: Res<PaintLayerSettings>,
+    history: Single<&PaintableHistory<HistoryUnit>>,
+    paint_action_history: Single<&PaintableHistory<ActionState<PaintSkiesAction>>>,
+    paint_skies_camera: Single<
+        (&PaintableHistory<GlobalTransform>, &CompactedCameraPath),
+        With<PaintSkiesCamera>,
+    >,
+    painted_meshes: Query<(Entity, &PaintedMesh, &Transform, &Mesh3d, PaintedMaterials)>,
+    baked_sky_meshes: Query<(
+        Entity,
+        &BakedPaintLayers,
+        &Mesh3d,
+        &MeshMaterial3d<PaintedSkyMaterial>,
+    )>,
+    mesh_assets: Res<Assets<Mesh>>,
+    atlas: Res<PaintCanvasAtlas>,
+) -> Option<(
+    MessageWrite<CompactPaintLayers>,
+    Vec<MessageWrite<BakeCanvasPage>>,
+    Vec<EntityCommandDespawn>,
+    Vec<SpawnBakedPaintLayers>,
+    Vec<SpawnBakedPaintLayers<StandardMaterial>>,
+    ResSet<PaintCanvasAtlas>,
+    QueryAffect<ComponentSet<CompactedCameraPath>, With<PaintSkiesCamera>>,
+)> {
+    if last_layer_index.saturating_sub(*history.checkpoint()) <= settings.layer_budget {
+        return None;
+    }
+
+    let checkpoint = LayerIndex(*last_layer_index - settings.layer_budget / 2);
+    let compacted_history = (*history).clone().compact(checkpoint);
+
+    let (camera_transform_history, CompactedCameraPath(compacted_camera_path)) =
+        *paint_skies_camera;
+    let compacted_camera_path = compacted_camera_path
+        .iter()
+        .copied()
+        .chain(
+            (*camera_transform_history.checkpoint()..*checkpoint).filter_map(|layer| {
+                let layer = LayerIndex(layer);
+                Some((layer, *camera_transform_history.get(layer)?))
+            }),
+        )
+        .collect();
+
+    // Meshes of pruned branches and of redo segments dropped by compaction can't be shown anymore,
+    // so they're despawned to free their canvases along with the compacted ones.
+    let (compacted_meshes, unreachable_meshes) = painted_meshes
+        .iter()
+        .filter(|(_, painted_mesh, ..)| {
+            *painted_mesh.paint_layer <= *checkpoint
+                || !compacted_history.can_reach(painted_mesh.paint_layer, painted_mesh.paint_branch)
+        })
+        .partition::<Vec<_>, _>(|(_, painted_mesh, ..)| *painted_mesh.paint_layer <= *checkpoint);
+
+    let meshes_to_bake = compacted_meshes
+        .iter()
+        .filter(|(_, painted_mesh, ..)| {
+            painted_mesh.visibility_in(&paint_action_history) != Visibility::Hidden
+        })
+        .filter_map(|(_, painted_mesh, transform, mesh, materials)| {
+            let mesh = mesh_assets.get(*mesh)?.clone().transformed_by(**transform);
+
+            Some((*painted_mesh, mesh, *materials))
+        })
+        .collect::<Vec<_>>();
+
+    let baked_layers = meshes_to_bake
+        .iter()
+        .map(|(painted_mesh, ..)| (painted_mesh.paint_layer, painted_mesh.paint_branch))
+        .collect::<HashSet<_>>();
+
+    // Material transfer painted meshes are baked per source material instead of per page.
+    let (baked_meshes, baked_transfer_meshes) = meshes_to_bake.into_iter().fold(
+        (
+            BakedMeshes::<PaintedSkyMaterial>::new(),
+            BakedMeshes::<StandardMaterial>::new(),
+        ),
+        |(baked_meshes, baked_transfer_meshes), (painted_mesh, mesh, materials)| {
+            let baked_painted_mesh = BakedPaintedMesh {
+                painted_from: painted_mesh.painted_from,
+                triangle_indices: painted_mesh.triangle_indices.clone(),
+                paint_layer: painted_mesh.paint_layer,
+                first_triangle: 0,
+            };
+
+            match materials {
+                (Some(material), _) => (
+                    bake_mesh(baked_meshes, material, mesh, [baked_painted_mesh]),
+                    baked_transfer_meshes,
+                ),
+                (None, Some(material)) => (
+                    baked_meshes,
+                    bake_mesh(baked_transfer_meshes, material, mesh, [baked_painted_mesh]),
+                ),
+                (None, None) => (baked_meshes, baked_transfer_meshes),
+            }
+        },
+    );
+
+    let (atlas, baked_slots) = atlas.clone().with_baked_layers(&baked_layers);
+    let baked_slot = |material: AssetId<PaintedSkyMaterial>| {
+        atlas
+            .page_with_material(material)
+            .and_then(|page| baked_slots.get(&page).copied())
+    };
+
+    let rebaked_sky_meshes = baked_sky_meshes
+        .iter()
+        .filter(|(.., material)| baked_slot(material.id()).is_some())
+        .filter_map(|(entity, baked_paint_layers, mesh, material)| {
+            Some((entity, baked_paint_layers, mesh_assets.get(mesh)?, material))
+        })
+        .collect::<Vec<_>>();
+
+    let baked_meshes = rebaked_sky_meshes.iter().fold(
+        baked_meshes,
+        |baked_meshes, (_, baked_paint_layers, mesh, material)| {
+            bake_mesh(
+                baked_meshes,
+                material,
+                (*mesh).clone(),
+                baked_paint_layers.painted_meshes.iter().cloned(),
+            )
+        },
+    );
+
+    let despawns = compacted_meshes
+        .iter()
+        .chain(&unreachable_meshes)
+        .map(|(entity, ..)| *entity)
+        .chain(rebaked_sky_meshes.iter().map(|(entity, ..)| *entity))
+        .map(entity_command_despawn)
+        .collect();
+
+    Some((
+        message_write(CompactPaintLayers { checkpoint }),
+        baked_slots
+            .values()
+            .map(|&slot| message_write(BakeCanvasPage { slot }))
+            .collect(),
+        despawns,
+        baked_meshes
+            .into_iter()
+            .map(|(material_id, (material, mesh, baked_paint_layers))| {
+                let mesh = match baked_slot(material_id) {
+                    Some(slot) => with_baked_uvs(mesh, &atlas, slot),
+                    None => mesh,
+                };
+
+                spawn_baked_paint_layers(mesh, material, baked_paint_layers)
+            })
+            .collect(),
+        baked_transfer_meshes
+            .into_values()
+            .map(|(material, mesh, baked_paint_layers)| {
+                spawn_baked_paint_layers(mesh, material, baked_paint_layers)
+            })
+            .collect(),
+        res_set(atlas),
+        query_affect(component_set(CompactedCameraPath(compacted_camera_path))),
+    ))
+}
+
+/// Painted meshes being baked, merged per material.
+type BakedMeshes<M> = HashMap<AssetId<M>, (Handle<M>, Mesh, BakedPaintLayers)>;
+
+/// Returns the given baked meshes with the given mesh merged into the baked mesh of its material,
+/// keeping the data of the painted meshes it's made of.
+///
+/// The first triangle of each painted mesh is relative to the given mesh.
+fn bake_mesh<M: Material>(
+    mut baked_meshes: BakedMeshes<M>,
+    material: &MeshMaterial3d<M>,
+    mesh: Mesh,
+    painted_meshes: impl IntoIterator<Item = BakedPaintedMesh>,
+) -> BakedMeshes<M> {
+    match baked_meshes.get_mut(&material.id()) {
+        Some((_, baked_mesh, baked_paint_layers)) => {
+            let first_triangle = baked_mesh.indices().map_or(0, |indices| indices.len() / 3);
+
+            match baked_mesh.merge(&mesh) {
+                Ok(()) => baked_paint_layers
+                    .painted_meshes
+                    .extend(
+                        painted_meshes
+                            .into_iter()
+                            .map(|painted_mesh| BakedPaintedMesh {
+                                first_triangle: first_triangle + painted_mesh.first_triangle,
+                                ..painted_mesh
+                            }),
+                    ),
+                Err(e) => warn!("failed to bake painted mesh: {e}"),
+            }
+        }
+        None => {
+            baked_meshes.insert(
+                material.id(),
+                (
+                    (**material).clone(),
+                    mesh,
+                    BakedPaintLayers {
+                        painted_meshes: painted_meshes.into_iter().collect(),
+                    },
+                ),
+            );
+        }
+    }
+
+    baked_meshes
+}
+
+/// Returns the given mesh with its UVs into an atlas page moved into the baked slot that the page
+/// is shrunk into.
+fn with_baked_uvs(mut mesh: Mesh, atlas: &PaintCanvasAtlas, slot: usize) -> Mesh {
+    if let Some(VertexAttributeValues::Float32x2(uvs)) = mesh.attribute_mut(Mesh::ATTRIBUTE_UV_0) {
+        for uv in uvs.iter_mut() {
+            *uv = atlas.slot_uv(slot, Vec2::from_array(*uv)).to_array();
+        }
+    }
+
+    mesh
+}
@@ -0,0 +1,316 @@
+use std::sync::Arc;
+
+use bevy::platform::collections::HashMap;
+use bevy::prelude::*;
+
+use crate::clear_skies::paint_skies::paint_meshes::LayerIndex;
+
+/// The name of the built-in [`LayerDistanceCurve::Custom`] curve, whose layer distances fall off as
+/// `1000 / (1 + layer / 50)`.
+///
+/// Apparent sizes are inversely proportional to distance, so things painted on each layer look the
+/// same amount bigger than on the last.
+pub const INVERSE_LAYER_DISTANCE_CURVE: &str = "inverse";
+
+/// Plugin that sets up the [`LayerDistanceCurves`] registry, with the
+/// [`INVERSE_LAYER_DISTANCE_CURVE`] registered.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Reflect)]
+pub struct LayerDistancePlugin;
+
+impl Plugin for LayerDistancePlugin {
+    fn build(&self, app: &mut App) {
+        app.register_type::<LayerDistanceCurve>()
+            .register_layer_distance_curve(INVERSE_LAYER_DISTANCE_CURVE, |layer| {
+                1000.0 / (1.0 + *layer as f32 / 50.0)
+            });
+    }
+}
+
+/// Extension trait for registering custom [`LayerDistanceCurves`] on an [`App`].
+pub trait RegisterLayerDistanceCurve {
+    /// Registers a custom curve that [`LayerDistanceCurve::Custom`] can refer to by the given
+    /// name, replacing any curve that already had it.
+    fn register_layer_distance_curve(
+        &mut self,
+        name: impl Into<String>,
+        curve: impl Fn(LayerIndex) -> f32 + Send + Sync + 'static,
+    ) -> &mut Self;
+}
+
+impl RegisterLayerDistanceCurve for App {
+    fn register_layer_distance_curve(
+        &mut self,
+        name: impl Into<String>,
+        curve: impl Fn(LayerIndex) -> f32 + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.init_resource::<LayerDistanceCurves>()
+            .world_mut()
+            .resource_mut::<LayerDistanceCurves>()
+            .register(name, curve);
+
+        self
+    }
+}
+
+/// A keyframe of a [`LayerDistanceCurve::Spline`].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Reflect)]
+pub struct LayerDistanceKeyframe {
+    /// The layer this keyframe is at.
+    pub layer: LayerIndex,
+    /// The distance of the layer.
+    pub distance: f32,
+}
+
+/// How far from the play skies camera each paint layer is painted.
+#[derive(Debug, Clone, PartialEq, Reflect)]
+pub enum LayerDistanceCurve {
+    /// Each layer is the same distance closer than the last.
+    Linear {
+        /// The distance of the first layer.
+        zero_layer_distance: f32,
+        /// How much closer each layer is than the last.
+        step: f32,
+        /// The closest that layers can get.
+        min_distance: f32,
+    },
+    /// Each layer is a fixed fraction of the distance of the last.
+    Exponential {
+        /// The distance of the first layer.
+        zero_layer_distance: f32,
+        /// The fraction of the last layer's distance that each layer is at.
+        collapse_rate: f32,
+    },
+    /// Layers get closer by less and less, in proportion to the logarithm of the layer.
+    Logarithmic {
+        /// The distance of the first layer.
+        zero_layer_distance: f32,
+        /// How much closer layers get per factor of e.
+        scale: f32,
+        /// The closest that layers can get.
+        min_distance: f32,
+    },
+    /// A Catmull-Rom spline through keyframes, which must be sorted by layer.
+    ///
+    /// Layers outside the keyframes keep the distance of the nearest one.
+    Spline(Vec<LayerDistanceKeyframe>),
+    /// A curve registered in [`LayerDistanceCurves`] with this name, like the
+    /// [`INVERSE_LAYER_DISTANCE_CURVE`].
+    ///
+    /// Register curves with [`RegisterLayerDistanceCurve::register_layer_distance_curve`].
+    Custom(String),
+}
+
+impl Default for LayerDistanceCurve {
+    fn default() -> Self {
+        LayerDistanceCurve::Exponential {
+            zero_layer_distance: 1000.0,
+            collapse_rate: 0.98,
+        }
+    }
+}
+
+impl LayerDistanceCurve {
+    /// Returns the distance of the given layer, or `None` if it's a custom curve that isn't
+    /// registered, or a spline without keyframes.
+    pub fn distance(&self, layer: LayerIndex, custom_curves: &LayerDistanceCurves) -> Option<f32> {
+        let n = *layer as f32;
+
+        match self {
+            LayerDistanceCurve::Linear {
+                zero_layer_distance,
+                step,
+                min_distance,
+            } => Some((zero_layer_distance - step * n).max(*min_distance)),
+            LayerDistanceCurve::Exponential {
+                zero_layer_distance,
+                collapse_rate,
+            } => Some(zero_layer_distance * collapse_rate.powf(n)),
+            LayerDistanceCurve::Logarithmic {
+                zero_layer_distance,
+                scale,
+                min_distance,
+            } => Some((zero_layer_distance - scale * n.ln_1p()).max(*min_distance)),
+            LayerDistanceCurve::Spline(keyframes) => spline_distance(keyframes, n),
+            LayerDistanceCurve::Custom(name) => Some(custom_curves.curves.get(name)?(layer)),
+        }
+    }
+}
+
+/// Evaluates a Catmull-Rom spline through unevenly spaced keyframes.
+fn spline_distance(keyframes: &[LayerDistanceKeyframe], n: f32) -> Option<f32> {
+    let point = |index: usize| {
+        let keyframe = keyframes[index.min(keyframes.len() - 1)];
+        (*keyframe.layer as f32, keyframe.distance)
+    };
+
+    let (first, last) = (keyframes.first()?, keyframes.last()?);
+
+    if n <= *first.layer as f32 {
+        return Some(first.distance);
+    }
+
+    if n >= *last.layer as f32 {
+        return Some(last.distance);
+    }
+
+    let segment = keyframes
+        .windows(2)
+        .position(|window| n < *window[1].layer as f32)?;
+
+    let tangent = |index: usize| {
+        let (before_x, before_y) = point(index.saturating_sub(1));
+        let (after_x, after_y) = point(index + 1);
+
+        (after_y - before_y) / (after_x - before_x)
+    };
+
+    let (x0, y0) = point(segment);
+    let (x1, y1) = point(segment + 1);
+
+    let width = x1 - x0;
+    let t = (n - x0) / width;
+    let (t2, t3) = (t * t, t * t * t);
+
+    Some(
+        (2.0 * t3 - 3.0 * t2 + 1.0) * y0
+            + (t3 - 2.0 * t2 + t) * width * tangent(segment)
+            + (-2.0 * t3 + 3.0 * t2) * y1
+            + (t3 - t2) * width * tangent(segment + 1),
+    )
+}
+
+/// A band around the layer distance that painted vertices are spread over by their depth from the
+/// paint skies camera, so that painted objects keep some parallax.
+#[derive(Debug, Copy, Clone, PartialEq, Reflect)]
+pub struct ParallaxDepthBand {
+    /// The depth from the paint skies camera that is painted at exactly the layer distance.
+    pub reference_depth: f32,
+    /// How far vertices can be painted from the layer distance, as a fraction of it.
+    ///
+    /// Keep this below the gap between layers, or the layers will overlap.
+    pub half_width: f32,
+}
+
+impl Default for ParallaxDepthBand {
+    fn default() -> Self {
+        ParallaxDepthBand {
+            reference_depth: 10.0,
+            half_width: 0.01,
+        }
+    }
+}
+
+impl ParallaxDepthBand {
+    /// Returns the distance to paint a vertex at the given depth, compressing every depth into
+    /// the band around the layer distance.
+    pub fn distance(&self, layer_distance: f32, depth: f32) -> f32 {
+        let depth_squared = depth * depth;
+        let reference_squared = self.reference_depth * self.reference_depth;
+
+        // tanh(ln(depth / reference_depth)), which is 0 at the reference depth and approaches -1
+        // and 1 for depths much closer and further.
+        let offset = (depth_squared - reference_squared) / (depth_squared + reference_squared);
+
+        layer_distance * (1.0 + self.half_width * offset)
+    }
+}
+
+/// A custom layer distance curve, mapping layers to distances.
+pub type CustomLayerDistanceCurve = Arc<dyn Fn(LayerIndex) -> f32 + Send + Sync>;
+
+/// Resource of custom curves that [`LayerDistanceCurve::Custom`] can refer to by name.
+#[derive(Default, Clone, Resource)]
+pub struct LayerDistanceCurves {
+    curves: HashMap<String, CustomLayerDistanceCurve>,
+}
+
+impl LayerDistanceCurves {
+    /// Registers a custom curve with the given name, replacing any curve that already had it.
+    pub fn register(
+        &mut self,
+        name: impl Into<String>,
+        curve: impl Fn(LayerIndex) -> f32 + Send + Sync + 'static,
+    ) {
+        self.curves.insert(name.into(), Arc::new(curve));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyframes(points: &[(u32, f32)]) -> Vec<LayerDistanceKeyframe> {
+        points
+            .iter()
+            .map(|(layer, distance)| LayerDistanceKeyframe {
+                layer: LayerIndex(*layer),
+                distance: *distance,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn spline_distance_without_keyframes_is_none() {
+        assert_eq!(spline_distance(&[], 3.0), None);
+    }
+
+    #[test]
+    fn spline_distance_is_constant_for_one_keyframe() {
+        let keyframes = keyframes(&[(10, 500.0)]);
+
+        [0.0, 10.0, 20.0].into_iter().for_each(|n| {
+            assert_eq!(spline_distance(&keyframes, n), Some(500.0));
+        });
+    }
+
+    #[test]
+    fn spline_distance_keeps_the_nearest_keyframe_outside_them() {
+        let keyframes = keyframes(&[(10, 500.0), (20, 300.0), (50, 100.0)]);
+
+        assert_eq!(spline_distance(&keyframes, 0.0), Some(500.0));
+        assert_eq!(spline_distance(&keyframes, 10.0), Some(500.0));
+        assert_eq!(spline_distance(&keyframes, 50.0), Some(100.0));
+        assert_eq!(spline_distance(&keyframes, 80.0), Some(100.0));
+    }
+
+    #[test]
+    fn spline_distance_passes_through_keyframes() {
+        let keyframes = keyframes(&[(0, 1000.0), (5, 600.0), (40, 550.0), (41, 100.0)]);
+
+        keyframes.iter().for_each(|keyframe| {
+            let distance = spline_distance(&keyframes, *keyframe.layer as f32).unwrap();
+            assert!((distance - keyframe.distance).abs() < 1e-3);
+        });
+    }
+
+    #[test]
+    fn spline_distance_through_unevenly_spaced_collinear_keyframes_is_linear() {
+        let keyframes = keyframes(&[(0, 100.0), (10, 80.0), (40, 20.0)]);
+
+        [2.5, 5.0, 17.0, 33.0].into_iter().for_each(|n| {
+            let distance = spline_distance(&keyframes, n).unwrap();
+            assert!(
+                (distance - (100.0 - 2.0 * n)).abs() < 1e-3,
+                "{n}: {distance}"
+            );
+        });
+    }
+
+    #[test]
+    fn custom_curves_are_looked_up_by_name() {
+        let mut app = App::new();
+        app.add_plugins(LayerDistancePlugin)
+            .register_layer_distance_curve("flat", |_| 42.0);
+
+        let curves = app.world().resource::<LayerDistanceCurves>();
+
+        let inverse = LayerDistanceCurve::Custom(INVERSE_LAYER_DISTANCE_CURVE.to_string());
+        assert_eq!(inverse.distance(LayerIndex(50), curves), Some(500.0));
+
+        let flat = LayerDistanceCurve::Custom("flat".to_string());
+        assert_eq!(flat.distance(LayerIndex(7), curves), Some(42.0));
+
+        let missing = LayerDistanceCurve::Custom("missing".to_string());
+        assert_eq!(missing.distance(LayerIndex(7), curves), None);
+    }
+}
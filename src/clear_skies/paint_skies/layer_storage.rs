@@ -0,0 +1,358 @@
+use std::fmt::{self, Debug};
+use std::sync::Arc;
+
+use bevy::prelude::*;
+
+/// The number of layers in each shared chunk of a [`LayerStorage`].
+const CHUNK_LEN: usize = 64;
+
+/// A full chunk of layers, linked to the chunk of layers before it.
+struct LayerChunk<C> {
+    values: Vec<Option<C>>,
+    previous: Option<Arc<LayerChunk<C>>>,
+}
+
+/// Storage for per-layer values that shares most of its memory between clones.
+///
+/// Layers are stored in fixed-size chunks that are shared between clones once they're full, plus
+/// a short tail that isn't. So, cloning, appending, and truncating recent layers all take the same
+/// time no matter how many layers there are. Accessing older layers is slower, but only linearly
+/// in the number of chunks after them.
+///
+/// Layers before [`Self::start`] have been forgotten, and aren't stored at all.
+#[derive(Reflect)]
+#[reflect(opaque, Clone)]
+#[reflect(where C: Clone)]
+pub struct LayerStorage<C> {
+    /// The first layer that is stored.
+    start: usize,
+    /// Full chunks, newest first.
+    chunks: Option<Arc<LayerChunk<C>>>,
+    chunk_count: usize,
+    /// Layers after the last full chunk, always fewer than [`CHUNK_LEN`].
+    tail: Vec<Option<C>>,
+}
+
+impl<C> Default for LayerStorage<C> {
+    fn default() -> Self {
+        LayerStorage::starting_at(0)
+    }
+}
+
+impl<C: Clone> Clone for LayerStorage<C> {
+    fn clone(&self) -> Self {
+        LayerStorage {
+            start: self.start,
+            chunks: self.chunks.clone(),
+            chunk_count: self.chunk_count,
+            tail: self.tail.clone(),
+        }
+    }
+}
+
+impl<C: Debug> Debug for LayerStorage<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LayerStorage")
+            .field("start", &self.start)
+            .field("layers", &self.iter().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl<C: PartialEq> PartialEq for LayerStorage<C> {
+    fn eq(&self, other: &Self) -> bool {
+        self.start == other.start && self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+impl<C: Eq> Eq for LayerStorage<C> {}
+
+impl<C> Extend<Option<C>> for LayerStorage<C> {
+    fn extend<I: IntoIterator<Item = Option<C>>>(&mut self, iter: I) {
+        iter.into_iter().for_each(|value| self.push(value));
+    }
+}
+
+impl<C> LayerStorage<C> {
+    /// Constructs an empty [`LayerStorage`] whose first pushed value will be at the given layer.
+    pub fn starting_at(start: usize) -> Self {
+        LayerStorage {
+            start,
+            chunks: None,
+            chunk_count: 0,
+            tail: vec![],
+        }
+    }
+
+    /// Returns the first layer that is stored.
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    /// Returns the number of layers, including forgotten ones.
+    pub fn len(&self) -> usize {
+        self.start + self.stored_len()
+    }
+
+    /// The number of layers that are actually stored.
+    fn stored_len(&self) -> usize {
+        self.full_len() + self.tail.len()
+    }
+
+    /// The number of layers stored in full chunks.
+    fn full_len(&self) -> usize {
+        self.chunk_count * CHUNK_LEN
+    }
+
+    /// Returns an iterator over the full chunks, newest first.
+    fn iter_chunks(&self) -> impl Iterator<Item = &LayerChunk<C>> {
+        std::iter::successors(self.chunks.as_deref(), |chunk| chunk.previous.as_deref())
+    }
+
+    /// Returns the full chunk at the given chunk index, counting from the oldest.
+    fn chunk(&self, chunk_index: usize) -> Option<&LayerChunk<C>> {
+        self.iter_chunks()
+            .nth(self.chunk_count.checked_sub(chunk_index + 1)?)
+    }
+
+    /// Get the value stored at this layer, if it's stored and not `None`.
+    pub fn get(&self, index: usize) -> Option<&C> {
+        let stored_index = index.checked_sub(self.start)?;
+
+        match stored_index.checked_sub(self.full_len()) {
+            Some(tail_index) => self.tail.get(tail_index)?.as_ref(),
+            None => self.chunk(stored_index / CHUNK_LEN)?.values[stored_index % CHUNK_LEN].as_ref(),
+        }
+    }
+
+    /// Appends a value as the new last layer.
+    pub fn push(&mut self, value: Option<C>) {
+        self.tail.push(value);
+
+        if self.tail.len() == CHUNK_LEN {
+            self.chunks = Some(Arc::new(LayerChunk {
+                values: std::mem::take(&mut self.tail),
+                previous: self.chunks.take(),
+            }));
+            self.chunk_count += 1;
+        }
+    }
+
+    /// Returns a new [`LayerStorage`] with the same layers, but with every value mapped by the
+    /// given function.
+    pub fn map<D>(&self, f: impl Fn(&C) -> D) -> LayerStorage<D> {
+        let mut mapped = LayerStorage::starting_at(self.start);
+        mapped.extend(self.iter().map(|c| c.as_ref().map(&f)));
+        mapped
+    }
+
+    /// Returns an iterator over the stored layers, starting at the given one.
+    pub fn iter_from(&self, start: usize) -> impl Iterator<Item = &Option<C>> {
+        let stored_start = start.saturating_sub(self.start);
+        let first_chunk = stored_start / CHUNK_LEN;
+
+        let mut chunks = self
+            .iter_chunks()
+            .take(self.chunk_count.saturating_sub(first_chunk))
+            .collect::<Vec<_>>();
+        chunks.reverse();
+
+        let skip = match stored_start.checked_sub(self.full_len()) {
+            Some(tail_index) => tail_index,
+            None => stored_start % CHUNK_LEN,
+        };
+
+        chunks
+            .into_iter()
+            .flat_map(|chunk| chunk.values.iter())
+            .chain(self.tail.iter())
+            .skip(skip)
+    }
+
+    /// Returns an iterator over every stored layer, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &Option<C>> {
+        self.iter_from(self.start)
+    }
+
+    /// Returns an iterator over every stored layer, newest first.
+    ///
+    /// Unlike [`Self::iter`], this doesn't need to visit the older chunks before yielding.
+    pub fn iter_rev(&self) -> impl Iterator<Item = &Option<C>> {
+        self.tail.iter().rev().chain(
+            self.iter_chunks()
+                .flat_map(|chunk| chunk.values.iter().rev()),
+        )
+    }
+}
+
+impl<C: Clone> LayerStorage<C> {
+    /// Keeps only the layers before `len`, but never forgets any more layers.
+    ///
+    /// Chunks before the new end stay shared with any clones.
+    pub fn truncate(&mut self, len: usize) {
+        let stored_len = len.saturating_sub(self.start);
+
+        if let Some(tail_len) = stored_len.checked_sub(self.full_len()) {
+            self.tail.truncate(tail_len);
+            return;
+        }
+
+        let chunk_index = stored_len / CHUNK_LEN;
+        let Some(chunk) = self.chunk(chunk_index) else {
+            return;
+        };
+
+        let tail = chunk.values[..stored_len % CHUNK_LEN].to_vec();
+        let previous = chunk.previous.clone();
+
+        self.tail = tail;
+        self.chunks = previous;
+        self.chunk_count = chunk_index;
+    }
+
+    /// Splits off the layers starting at the given one into a new [`LayerStorage`].
+    pub fn split_off(&mut self, at: usize) -> Self {
+        let at = at.max(self.start);
+
+        let mut split = LayerStorage::starting_at(at);
+        split.extend(self.iter_from(at).cloned());
+        self.truncate(at);
+        split
+    }
+
+    /// Stops storing the layers before the given one, freeing their memory.
+    ///
+    /// This copies the layers that are kept, so avoid calling it every layer.
+    pub fn forget_before(&mut self, start: usize) {
+        let start = start.min(self.len());
+
+        if start > self.start {
+            let mut kept = LayerStorage::starting_at(start);
+            kept.extend(self.iter_from(start).cloned());
+            *self = kept;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Constructs a [`LayerStorage`] where every layer stores its own index.
+    fn counting_storage(len: usize) -> LayerStorage<usize> {
+        let mut storage = LayerStorage::default();
+        storage.extend((0..len).map(Some));
+        storage
+    }
+
+    /// Asserts that the storage holds exactly the layers `start..end`, each storing its own index.
+    fn assert_counts(storage: &LayerStorage<usize>, start: usize, end: usize) {
+        assert_eq!(storage.start(), start);
+        assert_eq!(storage.len(), end);
+        assert_eq!(
+            storage.iter().cloned().collect::<Vec<_>>(),
+            (start..end).map(Some).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            storage.iter_rev().cloned().collect::<Vec<_>>(),
+            (start..end).rev().map(Some).collect::<Vec<_>>()
+        );
+        (0..end + CHUNK_LEN).for_each(|layer| {
+            let expected = (start..end).contains(&layer).then_some(&layer);
+            assert_eq!(storage.get(layer), expected, "layer {layer}");
+        });
+    }
+
+    /// Lengths on and around chunk boundaries.
+    const BOUNDARY_LENS: [usize; 9] = [
+        0,
+        1,
+        CHUNK_LEN - 1,
+        CHUNK_LEN,
+        CHUNK_LEN + 1,
+        2 * CHUNK_LEN - 1,
+        2 * CHUNK_LEN,
+        2 * CHUNK_LEN + 1,
+        3 * CHUNK_LEN + 7,
+    ];
+
+    #[test]
+    fn get_and_iter_across_chunks() {
+        BOUNDARY_LENS
+            .into_iter()
+            .for_each(|len| assert_counts(&counting_storage(len), 0, len));
+    }
+
+    #[test]
+    fn iter_from_across_chunks() {
+        let storage = counting_storage(3 * CHUNK_LEN + 7);
+
+        BOUNDARY_LENS.into_iter().for_each(|from| {
+            assert_eq!(
+                storage.iter_from(from).cloned().collect::<Vec<_>>(),
+                (from..3 * CHUNK_LEN + 7).map(Some).collect::<Vec<_>>(),
+                "from {from}"
+            );
+        });
+    }
+
+    #[test]
+    fn truncate_across_chunks_keeps_clones_intact() {
+        let original = counting_storage(3 * CHUNK_LEN + 7);
+
+        BOUNDARY_LENS.into_iter().for_each(|len| {
+            let mut truncated = original.clone();
+            truncated.truncate(len);
+            assert_counts(&truncated, 0, len);
+
+            truncated.extend((len..len + CHUNK_LEN + 1).map(Some));
+            assert_counts(&truncated, 0, len + CHUNK_LEN + 1);
+        });
+
+        assert_counts(&original, 0, 3 * CHUNK_LEN + 7);
+    }
+
+    #[test]
+    fn truncate_never_forgets_more_layers() {
+        let mut storage = counting_storage(2 * CHUNK_LEN + 1);
+        storage.forget_before(CHUNK_LEN + 1);
+
+        storage.truncate(CHUNK_LEN);
+        assert_counts(&storage, CHUNK_LEN + 1, CHUNK_LEN + 1);
+    }
+
+    #[test]
+    fn split_off_across_chunks() {
+        BOUNDARY_LENS.into_iter().for_each(|at| {
+            let mut storage = counting_storage(3 * CHUNK_LEN + 7);
+            let split = storage.split_off(at);
+
+            assert_counts(&storage, 0, at);
+            assert_counts(&split, at, 3 * CHUNK_LEN + 7);
+        });
+    }
+
+    #[test]
+    fn forget_before_across_chunks() {
+        BOUNDARY_LENS.into_iter().for_each(|start| {
+            let mut storage = counting_storage(3 * CHUNK_LEN + 7);
+            storage.forget_before(start);
+            assert_counts(&storage, start, 3 * CHUNK_LEN + 7);
+
+            storage.extend((3 * CHUNK_LEN + 7..4 * CHUNK_LEN + 7).map(Some));
+            assert_counts(&storage, start, 4 * CHUNK_LEN + 7);
+
+            let split = storage.split_off(start + CHUNK_LEN);
+            assert_counts(&storage, start, start + CHUNK_LEN);
+            assert_counts(&split, start + CHUNK_LEN, 4 * CHUNK_LEN + 7);
+        });
+    }
+
+    #[test]
+    fn forget_before_past_the_end_forgets_everything() {
+        let mut storage = counting_storage(CHUNK_LEN + 1);
+        storage.forget_before(2 * CHUNK_LEN);
+
+        assert_counts(&storage, CHUNK_LEN + 1, CHUNK_LEN + 1);
+    }
+}
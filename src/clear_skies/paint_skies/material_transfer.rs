@@ -0,0 +1,159 @@
+use bevy::mesh::{PrimitiveTopology, VertexAttributeValues};
+use bevy::prelude::*;
+
+/// Where painted meshes get their colors from.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Reflect)]
+pub enum PaintSource {
+    /// The canvas of the [`ClearSkiesRenderTarget`] captured when the layer was painted.
+    ///
+    /// [`ClearSkiesRenderTarget`]: crate::clear_skies::camera::ClearSkiesRenderTarget
+    #[default]
+    Canvas,
+    /// The [`StandardMaterial`], UVs and vertex colors of the [`Paintable`] mesh that was painted.
+    ///
+    /// This needs no canvas, so it keeps the full texture resolution regardless of the
+    /// [`ClearSkiesResolution`], but painted meshes aren't shaded by the
+    /// [`PaintedSkyMaterial`], and soft brush edges are hard.
+    ///
+    /// [`Paintable`]: crate::clear_skies::paint_skies::Paintable
+    /// [`ClearSkiesResolution`]: crate::clear_skies::camera::ClearSkiesResolution
+    /// [`PaintedSkyMaterial`]: crate::clear_skies::paint_skies::painted_sky_material::PaintedSkyMaterial
+    MaterialTransfer,
+}
+
+/// A triangle of a [`Paintable`] mesh with its UVs and vertex colors, which the vertices of
+/// [`PaintSource::MaterialTransfer`] painted meshes are interpolated from.
+///
+/// [`Paintable`]: crate::clear_skies::paint_skies::Paintable
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TransferTriangle {
+    triangle: Triangle3d,
+    uvs: [Vec2; 3],
+    colors: [Vec4; 3],
+}
+
+impl TransferTriangle {
+    /// Reads the attributes of the triangle at the given index of a triangle list mesh.
+    ///
+    /// Vertices without UVs get zero UVs, and vertices without colors are white.
+    pub fn from_mesh(mesh: &Mesh, triangle: Triangle3d, triangle_index: usize) -> Option<Self> {
+        if mesh.primitive_topology() != PrimitiveTopology::TriangleList {
+            return None;
+        }
+
+        let vertex_indices = match mesh.indices() {
+            Some(indices) => {
+                let mut indices = indices.iter().skip(triangle_index * 3);
+                [indices.next()?, indices.next()?, indices.next()?]
+            }
+            None => [0, 1, 2].map(|corner| triangle_index * 3 + corner),
+        };
+
+        let uvs = match mesh.attribute(Mesh::ATTRIBUTE_UV_0) {
+            Some(VertexAttributeValues::Float32x2(uvs)) => vertex_indices
+                .map(|index| uvs.get(index).copied().map(Vec2::from).unwrap_or_default()),
+            _ => [Vec2::ZERO; 3],
+        };
+
+        let colors = match mesh.attribute(Mesh::ATTRIBUTE_COLOR) {
+            Some(VertexAttributeValues::Float32x4(colors)) => {
+                vertex_indices.map(|index| colors.get(index).copied().map_or(Vec4::ONE, Vec4::from))
+            }
+            _ => [Vec4::ONE; 3],
+        };
+
+        Some(TransferTriangle {
+            triangle,
+            uvs,
+            colors,
+        })
+    }
+
+    /// Returns the UV and color of the given point on the triangle, interpolating its vertices.
+    pub fn at(&self, point: Vec3) -> (Vec2, Vec4) {
+        let [a, b, c] = self.triangle.vertices;
+
+        let (ab, ac, ap) = (b - a, c - a, point - a);
+        let (d00, d01, d11) = (ab.dot(ab), ab.dot(ac), ac.dot(ac));
+        let (d20, d21) = (ap.dot(ab), ap.dot(ac));
+        let denominator = d00 * d11 - d01 * d01;
+
+        // Degenerate triangles take the attributes of their first vertex.
+        if denominator.abs() <= f32::EPSILON {
+            return (self.uvs[0], self.colors[0]);
+        }
+
+        let v = (d11 * d20 - d01 * d21) / denominator;
+        let w = (d00 * d21 - d01 * d20) / denominator;
+        let u = 1.0 - v - w;
+
+        (
+            self.uvs[0] * u + self.uvs[1] * v + self.uvs[2] * w,
+            self.colors[0] * u + self.colors[1] * v + self.colors[2] * w,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transfer_triangle(vertices: [Vec3; 3]) -> TransferTriangle {
+        TransferTriangle {
+            triangle: Triangle3d::new(vertices[0], vertices[1], vertices[2]),
+            uvs: [
+                Vec2::new(0.0, 0.0),
+                Vec2::new(1.0, 0.0),
+                Vec2::new(0.0, 1.0),
+            ],
+            colors: [Vec4::X, Vec4::Y, Vec4::Z],
+        }
+    }
+
+    #[test]
+    fn at_vertices_returns_their_attributes() {
+        let triangle = transfer_triangle([Vec3::ZERO, Vec3::new(2.0, 0.0, 1.0), Vec3::Y]);
+
+        triangle
+            .triangle
+            .vertices
+            .into_iter()
+            .enumerate()
+            .for_each(|(corner, vertex)| {
+                let (uv, color) = triangle.at(vertex);
+
+                assert!(uv.abs_diff_eq(triangle.uvs[corner], 1e-5));
+                assert!(color.abs_diff_eq(triangle.colors[corner], 1e-5));
+            });
+    }
+
+    #[test]
+    fn at_interpolates_barycentrically() {
+        let triangle = transfer_triangle([Vec3::ZERO, Vec3::new(2.0, 0.0, 1.0), Vec3::Y]);
+
+        let centroid = triangle.triangle.centroid();
+        let (uv, color) = triangle.at(centroid);
+        assert!(uv.abs_diff_eq(Vec2::splat(1.0 / 3.0), 1e-5));
+        assert!(color.abs_diff_eq(Vec3::splat(1.0 / 3.0).extend(0.0), 1e-5));
+
+        let edge_midpoint = triangle.triangle.vertices[0].midpoint(triangle.triangle.vertices[1]);
+        let (uv, color) = triangle.at(edge_midpoint);
+        assert!(uv.abs_diff_eq(Vec2::new(0.5, 0.0), 1e-5));
+        assert!(color.abs_diff_eq(Vec4::new(0.5, 0.5, 0.0, 0.0), 1e-5));
+    }
+
+    #[test]
+    fn at_projects_points_off_the_triangle_onto_its_plane() {
+        let triangle = transfer_triangle([Vec3::ZERO, Vec3::X, Vec3::Y]);
+
+        let (uv, _) = triangle.at(Vec3::new(0.25, 0.5, 3.0));
+        assert!(uv.abs_diff_eq(Vec2::new(0.25, 0.5), 1e-5));
+    }
+
+    #[test]
+    fn at_degenerate_triangle_returns_first_vertex() {
+        let triangle = transfer_triangle([Vec3::ZERO, Vec3::X, Vec3::X * 2.0]);
+
+        assert_eq!(triangle.at(Vec3::X), (triangle.uvs[0], triangle.colors[0]));
+    }
+}
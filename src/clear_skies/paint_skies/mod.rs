@@ -4,14 +4,59 @@ pub use plugin::PaintSkiesPlugin;
 mod settings;
 
 mod spherical_coords;
-pub use spherical_coords::{LookAtSphericalCoords, SphericalCoordsBounds};
+pub use spherical_coords::{
+    LookAtSphericalCoords,
+    SphericalCoordsBounds,
+    look_at_spherical_coords,
+};
 
 mod control_spherical_coords;
 
+mod paint_brush;
+pub use paint_brush::PaintBrush;
+
+mod layer_distance;
+pub use layer_distance::LayerDistanceCurves;
+
+mod sky_projection;
+
 mod paint_meshes;
-pub use paint_meshes::Paintable;
+pub use paint_meshes::{LayerIndex, PaintLayerSettings, Paintable, PaintedMesh};
+
+mod paintable_scene;
 
 mod triangle_with_uvs;
 
+mod clip_triangle;
+
+mod paint_visibility;
+
+mod canvas_atlas;
+
+mod canvas_copy;
+
+mod canvas_bake;
+
+mod painted_sky_material;
+
+mod material_transfer;
+
+mod posed_mesh;
+
+mod layer_storage;
+
 mod paint_layer_history;
-pub use paint_layer_history::PaintableHistory;
+pub use paint_layer_history::{
+    PaintLayerHistoryPlugin,
+    PaintableHistory,
+    RecordPresent,
+    RedoPaintLayers,
+    TruncatePaintLayers,
+};
+
+mod paint_session;
+
+mod replay;
+
+mod compaction;
+pub use compaction::{BakedPaintLayers, CompactedCameraPath};
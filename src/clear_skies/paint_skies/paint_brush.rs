@@ -0,0 +1,283 @@
+use std::f32::consts::TAU;
+
+use bevy::camera::primitives::HalfSpace;
+use bevy::prelude::*;
+use bevy_pipe_affect::prelude::*;
+use leafwing_input_manager::prelude::*;
+
+use crate::clear_skies::ClearSkiesState;
+use crate::clear_skies::camera::{PaintSkiesAction, PaintSkiesCamera};
+use crate::clear_skies::paint_skies::replay::paint_replay_inactive;
+
+/// The number of sides of the polygon that painted triangles are clipped to for round brushes.
+const BRUSH_POLYGON_SIDES: usize = 16;
+
+/// Plugin for controlling the [`PaintBrush`] of the [`PaintSkiesCamera`].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Reflect)]
+pub struct PaintBrushPlugin;
+
+impl Plugin for PaintBrushPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PaintBrushSettings>()
+            .register_type::<PaintBrushSettings>()
+            .register_type::<PaintBrush>()
+            .add_systems(
+                Update,
+                control_paint_brush
+                    .pipe(affect)
+                    .run_if(in_state(ClearSkiesState::PaintSkies).and(paint_replay_inactive)),
+            );
+    }
+}
+
+/// The shapes a [`PaintBrush`] can have.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Reflect)]
+pub enum BrushShape {
+    /// The whole viewport is painted.
+    #[default]
+    Viewport,
+    /// A hard-edged circle.
+    Circle,
+    /// An ellipse whose edge fades out, see [`PaintBrushSettings::ellipse_aspect_ratio`] and
+    /// [`PaintBrushSettings::softness`].
+    SoftEllipse,
+    /// The [`PaintBrushSettings::mask_image`], stretched over a square.
+    MaskImage,
+}
+
+/// Settings shared by every [`PaintBrush`].
+#[derive(Debug, Clone, PartialEq, Reflect, Resource)]
+#[reflect(Resource)]
+pub struct PaintBrushSettings {
+    /// The smallest brush size, as a fraction of the viewport height.
+    pub min_size: f32,
+    /// The largest brush size, as a fraction of the viewport height.
+    pub max_size: f32,
+    /// How much the brush size is multiplied by per second of [`PaintSkiesAction::ResizeBrush`].
+    pub resize_factor: f32,
+    /// How much the brush size is multiplied by per step of [`PaintSkiesAction::StepBrushSize`].
+    pub step_factor: f32,
+    /// How much wider than tall [`BrushShape::SoftEllipse`] is.
+    pub ellipse_aspect_ratio: f32,
+    /// The fraction of [`BrushShape::SoftEllipse`]'s radius that fades out.
+    pub softness: f32,
+    /// The image used by [`BrushShape::MaskImage`], whose luminance times alpha is the mask.
+    ///
+    /// [`BrushShape::MaskImage`] is skipped while cycling brushes if this isn't set.
+    pub mask_image: Option<Handle<Image>>,
+}
+
+impl Default for PaintBrushSettings {
+    fn default() -> Self {
+        PaintBrushSettings {
+            min_size: 0.05,
+            max_size: 1.0,
+            resize_factor: 2.0,
+            step_factor: 1.1,
+            ellipse_aspect_ratio: 1.5,
+            softness: 0.3,
+            mask_image: None,
+        }
+    }
+}
+
+/// Component limiting which part of the viewport is painted, centered on the viewport.
+#[derive(Debug, Copy, Clone, PartialEq, Reflect, Component)]
+#[reflect(Component)]
+pub struct PaintBrush {
+    /// The shape of the brush.
+    pub shape: BrushShape,
+    /// The radius of the brush, as a fraction of the viewport height.
+    pub size: f32,
+}
+
+impl Default for PaintBrush {
+    fn default() -> Self {
+        PaintBrush {
+            shape: BrushShape::Viewport,
+            size: 0.25,
+        }
+    }
+}
+
+impl PaintBrush {
+    /// Returns the radii of the brush in viewport UVs, for a viewport with the given aspect ratio.
+    pub fn radii(&self, settings: &PaintBrushSettings, aspect_ratio: f32) -> Vec2 {
+        let circle_radii = Vec2::new(self.size / aspect_ratio, self.size);
+
+        match self.shape {
+            BrushShape::SoftEllipse => circle_radii * Vec2::new(settings.ellipse_aspect_ratio, 1.0),
+            _ => circle_radii,
+        }
+    }
+
+    /// Returns how much of the viewport pixel at the given UV is painted, from 0 to 1.
+    ///
+    /// The mask image is only needed for [`BrushShape::MaskImage`], which paints nothing without
+    /// it.
+    pub fn alpha(
+        &self,
+        settings: &PaintBrushSettings,
+        aspect_ratio: f32,
+        mask_image: Option<&Image>,
+        uv: Vec2,
+    ) -> f32 {
+        let offset = (uv - Vec2::splat(0.5)) / self.radii(settings, aspect_ratio);
+
+        match self.shape {
+            BrushShape::Viewport => 1.0,
+            BrushShape::Circle => {
+                if offset.length() <= 1.0 {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            BrushShape::SoftEllipse => {
+                let fade_start = 1.0 - settings.softness.clamp(f32::EPSILON, 1.0);
+
+                1.0 - ((offset.length() - fade_start) / (1.0 - fade_start)).clamp(0.0, 1.0)
+            }
+            BrushShape::MaskImage => {
+                let mask_uv = (offset + Vec2::ONE) / 2.0;
+
+                let Some(mask_image) = mask_image
+                    .filter(|_| mask_uv.cmpge(Vec2::ZERO).all() && mask_uv.cmplt(Vec2::ONE).all())
+                else {
+                    return 0.0;
+                };
+
+                let pixel = (mask_uv * mask_image.size().as_vec2()).as_uvec2();
+
+                mask_image
+                    .get_color_at(pixel.x, pixel.y)
+                    .map(|color| {
+                        let color = color.to_linear();
+                        color.luminance() * color.alpha
+                    })
+                    .unwrap_or_default()
+            }
+        }
+    }
+
+    /// Returns whether any of the given viewport UVs are painted.
+    pub fn covers(
+        &self,
+        settings: &PaintBrushSettings,
+        aspect_ratio: f32,
+        mask_image: Option<&Image>,
+        uvs: impl IntoIterator<Item = Vec2>,
+    ) -> bool {
+        uvs.into_iter()
+            .any(|uv| self.alpha(settings, aspect_ratio, mask_image, uv) > 0.0)
+    }
+
+    /// Returns the half-spaces of the cone from the camera through the brush, which painted
+    /// triangles are clipped to.
+    ///
+    /// Round brushes are approximated by a polygon around them. The whole viewport and mask
+    /// images are already covered by the camera frustum, so they don't add any half-spaces.
+    pub fn half_spaces(
+        &self,
+        settings: &PaintBrushSettings,
+        camera: &Camera,
+        camera_transform: &GlobalTransform,
+    ) -> Vec<HalfSpace> {
+        if matches!(self.shape, BrushShape::Viewport | BrushShape::MaskImage) {
+            return vec![];
+        }
+
+        let Some(viewport_size) = camera.logical_viewport_size() else {
+            return vec![];
+        };
+
+        // Circumscribe the polygon, so that it contains the whole brush.
+        let radii = self.radii(settings, viewport_size.x / viewport_size.y)
+            / (TAU / (2.0 * BRUSH_POLYGON_SIDES as f32)).cos();
+
+        let eye = camera_transform.translation();
+
+        let corner_rays = (0..BRUSH_POLYGON_SIDES)
+            .map(|corner| {
+                let angle = corner as f32 * TAU / BRUSH_POLYGON_SIDES as f32;
+                let uv = Vec2::splat(0.5) + Vec2::from_angle(angle) * radii;
+                let ndc = Vec2::new(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0);
+
+                Some(camera.ndc_to_world(camera_transform, ndc.extend(1.0))? - eye)
+            })
+            .collect::<Option<Vec<_>>>();
+
+        let Some(corner_rays) = corner_rays else {
+            return vec![];
+        };
+
+        let center_ray = camera_transform.forward();
+
+        (0..corner_rays.len())
+            .map(|corner| {
+                let next_corner = corner_rays[(corner + 1) % corner_rays.len()];
+                let normal = corner_rays[corner].cross(next_corner);
+
+                // Face the normal into the cone.
+                let normal = if normal.dot(*center_ray) < 0.0 {
+                    -normal
+                } else {
+                    normal
+                };
+
+                HalfSpace::new(normal.extend(-normal.dot(eye)))
+            })
+            .collect()
+    }
+
+    /// Returns the shape after this one, skipping [`BrushShape::MaskImage`] without a mask image.
+    fn next_shape(&self, settings: &PaintBrushSettings) -> BrushShape {
+        match self.shape {
+            BrushShape::Viewport => BrushShape::Circle,
+            BrushShape::Circle => BrushShape::SoftEllipse,
+            BrushShape::SoftEllipse if settings.mask_image.is_some() => BrushShape::MaskImage,
+            BrushShape::SoftEllipse | BrushShape::MaskImage => BrushShape::Viewport,
+        }
+    }
+}
+
+/// Updates the [`PaintBrush`] of the [`PaintSkiesCamera`] according to
+/// [`PaintSkiesAction::ResizeBrush`], [`PaintSkiesAction::StepBrushSize`] and
+/// [`PaintSkiesAction::CycleBrush`] input.
+///
+/// Held resize input is scaled by the frame time, while steps like scroll wheel notches are applied
+/// as they come.
+fn control_paint_brush(
+    settings: Res<PaintBrushSettings>,
+    time: Res<Time>,
+) -> QueryMap<
+    (&'static PaintBrush, &'static ActionState<PaintSkiesAction>),
+    Option<ComponentSet<PaintBrush>>,
+    With<PaintSkiesCamera>,
+> {
+    let settings = settings.clone();
+    let delta_secs = time.delta_secs();
+
+    query_map(
+        move |(brush, action_state): (&PaintBrush, &ActionState<PaintSkiesAction>)| {
+            let shape = if action_state.just_pressed(&PaintSkiesAction::CycleBrush) {
+                brush.next_shape(&settings)
+            } else {
+                brush.shape
+            };
+
+            let resize_by = settings
+                .resize_factor
+                .powf(action_state.value(&PaintSkiesAction::ResizeBrush) * delta_secs)
+                * settings
+                    .step_factor
+                    .powf(action_state.value(&PaintSkiesAction::StepBrushSize));
+
+            let size = (brush.size * resize_by).clamp(settings.min_size, settings.max_size);
+
+            let new_brush = PaintBrush { shape, size };
+
+            (new_brush != *brush).then(|| component_set(new_brush))
+        },
+    )
+}
@@ -6,6 +6,7 @@ use bevy::reflect::{FromReflect, GetTypeRegistration, Typed};
 use bevy_pipe_affect::prelude::*;
 
 use crate::clear_skies::ClearSkiesState;
+use crate::clear_skies::paint_skies::layer_storage::LayerStorage;
 use crate::clear_skies::paint_skies::paint_meshes::LayerIndex;
 
 /// System set for systems that modify paint layer history.
@@ -25,15 +26,27 @@ where
         app.register_type::<PaintableHistory<C>>()
             .add_message::<TruncatePaintLayers>()
             .add_message::<RecordPresent>()
+            .add_message::<RedoPaintLayers>()
+            .add_message::<BranchPaintLayers>()
+            .add_message::<SwitchPaintBranch>()
+            .add_message::<CompactPaintLayers>()
             .add_systems(
                 Update,
                 (
-                    truncate_history::<C>
+                    compact_history::<C>
                         .pipe(affect)
-                        .run_if(on_message::<TruncatePaintLayers>),
-                    record_present::<C>
+                        .run_if(on_message::<CompactPaintLayers>),
+                    truncate_history::<C>.run_if(on_message::<TruncatePaintLayers>),
+                    redo_history::<C>
                         .pipe(affect)
-                        .run_if(on_message::<RecordPresent>),
+                        .run_if(on_message::<RedoPaintLayers>),
+                    switch_branch_history::<C>
+                        .pipe(affect)
+                        .run_if(on_message::<SwitchPaintBranch>),
+                    branch_history::<C>
+                        .pipe(affect)
+                        .run_if(on_message::<BranchPaintLayers>),
+                    record_present::<C>.run_if(on_message::<RecordPresent>),
                 )
                     .chain()
                     .in_set(RecordPaintLayerHistorySet),
@@ -62,41 +75,410 @@ where
     }
 }
 
+/// Index for a branch of the paint layer history.
+#[derive(Default, Debug, PartialEq, Eq, Copy, Clone, Hash, Reflect, Deref, DerefMut)]
+pub struct BranchIndex(pub u32);
+
+/// Describes where a branch of the paint layer history forked from its parent.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Hash, Reflect)]
+pub struct BranchFork {
+    /// The branch that was forked from.
+    pub parent: BranchIndex,
+    /// The first layer index that isn't shared with the parent branch.
+    pub layer: LayerIndex,
+}
+
+/// A single branch of a [`PaintableHistory`].
+#[derive(Clone, PartialEq, Eq, Debug, Reflect)]
+#[reflect(where C: Clone)]
+pub struct PaintableHistoryBranch<C> {
+    history: LayerStorage<C>,
+    fork: Option<BranchFork>,
+}
+
+impl<C> PaintableHistoryBranch<C> {
+    fn map<D>(&self, f: &impl Fn(&C) -> D) -> PaintableHistoryBranch<D> {
+        PaintableHistoryBranch {
+            history: self.history.map(f),
+            fork: self.fork,
+        }
+    }
+}
+
+impl<C> Default for PaintableHistoryBranch<C> {
+    fn default() -> Self {
+        PaintableHistoryBranch {
+            history: default(),
+            fork: None,
+        }
+    }
+}
+
+/// Returns an empty [`LayerStorage`] with the same start and length as the given one.
+fn empty_layers_like<C, D>(history: &LayerStorage<C>) -> LayerStorage<D> {
+    let mut empty = LayerStorage::starting_at(history.start());
+    empty.extend(std::iter::repeat_with(|| None).take(history.len() - history.start()));
+    empty
+}
+
 /// `Component` that stores the history of another component by layer index.
-#[derive(Clone, PartialEq, Eq, Debug, Default, Component, Reflect)]
-#[reflect(Component)]
+///
+/// The history is a tree of branches, only one of which is active at a time. All the other
+/// methods read from and write to the active branch.
+///
+/// Layers are kept in a [`LayerStorage`], so cloning the history to record a new layer costs the
+/// same no matter how many layers have been painted.
+///
+/// Layers before the checkpoint have been compacted with [`Self::compact`], and can't be undone.
+#[derive(Clone, PartialEq, Eq, Debug, Component, Reflect)]
+#[reflect(Component, where C: Clone)]
 pub struct PaintableHistory<C> {
-    history: Vec<Option<C>>,
+    /// Every branch of the history, or `None` for branches that were pruned by compaction.
+    branches: Vec<Option<PaintableHistoryBranch<C>>>,
+    active_branch: BranchIndex,
+    checkpoint: LayerIndex,
+    /// Stack of truncated history segments, paired with the layer index they started at.
+    redo_stack: Vec<(LayerIndex, LayerStorage<C>)>,
+}
+
+impl<C> Default for PaintableHistory<C> {
+    fn default() -> Self {
+        PaintableHistory {
+            branches: vec![Some(default())],
+            active_branch: BranchIndex(0),
+            checkpoint: LayerIndex(0),
+            redo_stack: vec![],
+        }
+    }
 }
 
-impl<C> PaintableHistory<C> {
+impl<C: Clone> PaintableHistory<C> {
+    fn active(&self) -> &PaintableHistoryBranch<C> {
+        self.branches[*self.active_branch as usize]
+            .as_ref()
+            .expect("the active branch should never be pruned")
+    }
+
+    fn history(&self) -> &LayerStorage<C> {
+        &self.active().history
+    }
+
+    fn history_mut(&mut self) -> &mut LayerStorage<C> {
+        &mut self.branches[*self.active_branch as usize]
+            .as_mut()
+            .expect("the active branch should never be pruned")
+            .history
+    }
+
     /// Get the historical value of the component at this layer index.
     pub fn get(&self, LayerIndex(absolute_index): LayerIndex) -> Option<&C> {
-        self.history.get(absolute_index as usize)?.as_ref()
+        self.history().get(absolute_index as usize)
     }
 
     /// Return the layer index of the last layer, if the history is non-empty.
     pub fn last_layer_index(&self) -> Option<LayerIndex> {
-        let len = self.history.len();
+        let len = self.history().len();
 
         (len > 0).then(|| LayerIndex(len as u32 - 1))
     }
 
-    /// Similar to `vec.iter().enumerate()`, returns an iterator that enumerates the history with `LayerIndex`es.
-    pub fn iter_enumerate_layers(&self) -> impl Iterator<Item = (LayerIndex, Option<&C>)> {
-        self.history
+    /// Returns the oldest layer that is still stored. Layers can't be truncated past it.
+    pub fn checkpoint(&self) -> LayerIndex {
+        self.checkpoint
+    }
+
+    /// Similar to `vec.iter().enumerate().rev()`, returns an iterator that enumerates the history
+    /// with `LayerIndex`es, starting from the last layer.
+    pub fn iter_enumerate_layers_rev(&self) -> impl Iterator<Item = (LayerIndex, Option<&C>)> {
+        let len = self.history().len();
+
+        self.history()
+            .iter_rev()
+            .enumerate()
+            .map(move |(i, c)| (LayerIndex((len - 1 - i) as u32), c.as_ref()))
+    }
+
+    /// Returns this [`PaintableHistory`] with only the elements before layer n, pushing the
+    /// removed elements onto the redo stack so they can be restored with [`Self::redo`].
+    ///
+    /// The checkpoint is always kept, even if n is lower.
+    pub fn truncate_redoable(mut self, n: LayerIndex) -> Self {
+        self.truncate_to_redo_stack(n);
+        self
+    }
+
+    /// Keeps only the elements before layer n, pushing the removed elements onto the redo stack
+    /// in place, like [`Self::truncate_redoable`].
+    pub fn truncate_to_redo_stack(&mut self, LayerIndex(n): LayerIndex) {
+        let n = n.max(*self.checkpoint + 1);
+
+        if (n as usize) < self.history().len() {
+            let truncated = self.history_mut().split_off(n as usize);
+            self.redo_stack.push((LayerIndex(n), truncated));
+        }
+    }
+
+    /// Returns this [`PaintableHistory`] with the most recently truncated elements restored at
+    /// the layer index they were truncated from.
+    ///
+    /// Any layers recorded after that truncation are discarded in the process.
+    pub fn redo(mut self) -> Self {
+        if let Some((LayerIndex(n), redone)) = self.redo_stack.pop() {
+            let history = self.history_mut();
+            history.truncate(n as usize);
+            history.extend(redone.iter().cloned());
+        }
+
+        self
+    }
+
+    /// Returns this [`PaintableHistory`] with every truncated element restored.
+    fn redo_all(mut self) -> Self {
+        while self.can_redo() {
+            self = self.redo();
+        }
+
+        self
+    }
+
+    /// Returns `true` if there are truncated elements that can be restored with [`Self::redo`].
+    pub fn can_redo(&self) -> bool {
+        !self.redo_stack.is_empty()
+    }
+
+    /// Returns this [`PaintableHistory`] with the present continuing on a new branch.
+    ///
+    /// Any truncated elements are restored onto the previously active branch rather than being
+    /// discarded, and the new branch forks from the earliest layer that was truncated.
+    pub fn branch_off(self) -> Self {
+        let present = self.history().clone();
+
+        let fork_layer = self
+            .redo_stack
             .iter()
+            .map(|(LayerIndex(n), _)| *n)
+            .min()
+            .unwrap_or(present.len() as u32);
+
+        let PaintableHistory {
+            mut branches,
+            active_branch,
+            checkpoint,
+            redo_stack,
+        } = self.redo_all();
+
+        branches.push(Some(PaintableHistoryBranch {
+            history: present,
+            fork: Some(BranchFork {
+                parent: active_branch,
+                layer: LayerIndex(fork_layer),
+            }),
+        }));
+
+        PaintableHistory {
+            active_branch: BranchIndex(branches.len() as u32 - 1),
+            branches,
+            checkpoint,
+            redo_stack,
+        }
+    }
+
+    /// Returns this [`PaintableHistory`] with the given branch active.
+    ///
+    /// Any truncated elements are restored onto the previously active branch first. If the
+    /// branch doesn't exist in this history yet, empty branches are created up to it. If the
+    /// branch was pruned by compaction, the history is returned unchanged.
+    pub fn switch_branch(self, branch: BranchIndex) -> Self {
+        if matches!(self.branches.get(*branch as usize), Some(None)) {
+            return self;
+        }
+
+        let PaintableHistory {
+            mut branches,
+            checkpoint,
+            redo_stack,
+            ..
+        } = self.redo_all();
+
+        if branches.len() <= *branch as usize {
+            branches.resize_with(*branch as usize + 1, || Some(default()));
+        }
+
+        PaintableHistory {
+            branches,
+            active_branch: branch,
+            checkpoint,
+            redo_stack,
+        }
+    }
+
+    /// Returns this [`PaintableHistory`] with every layer before the given checkpoint forgotten.
+    ///
+    /// Branches that don't share every layer up to the checkpoint with the active branch are
+    /// pruned, since their differences can no longer be reached. Truncated layers from before the
+    /// checkpoint can no longer be redone either.
+    pub fn compact(self, checkpoint: LayerIndex) -> Self {
+        if *checkpoint <= *self.checkpoint {
+            return self;
+        }
+
+        let kept_branches = self
+            .iter_branches()
+            .map(|(branch, _)| branch)
+            .filter(|branch| {
+                self.divergence(self.active_branch, *branch)
+                    .is_none_or(|divergence| *divergence > *checkpoint)
+            })
+            .collect::<Vec<_>>();
+
+        let PaintableHistory {
+            branches,
+            active_branch,
+            redo_stack,
+            ..
+        } = self;
+
+        let branches = branches
+            .into_iter()
             .enumerate()
-            .map(|(i, c)| (LayerIndex(i as u32), c.as_ref()))
+            .map(|(i, branch)| {
+                let mut branch = branch?;
+
+                kept_branches.contains(&BranchIndex(i as u32)).then(|| {
+                    branch.history.forget_before(*checkpoint as usize);
+                    branch
+                })
+            })
+            .collect();
+
+        let redo_stack = redo_stack
+            .into_iter()
+            .filter(|(LayerIndex(n), _)| *n > *checkpoint)
+            .collect();
+
+        PaintableHistory {
+            branches,
+            active_branch,
+            checkpoint,
+            redo_stack,
+        }
     }
 
-    /// Returns this [`PaintableHistory`] with only the elements before layer n.
-    pub fn truncate(self, LayerIndex(n): LayerIndex) -> Self {
-        let PaintableHistory { mut history } = self;
+    /// Returns `true` if the given layer of the given branch can still be shown, by redoing or
+    /// switching branches.
+    ///
+    /// Layers of pruned branches can't be, and neither can truncated layers that were dropped from
+    /// the redo stack by [`Self::compact`].
+    pub fn can_reach(&self, LayerIndex(layer): LayerIndex, branch: BranchIndex) -> bool {
+        let layer = layer as usize;
+
+        let shared_with_active = self
+            .divergence(self.active_branch, branch)
+            .is_none_or(|divergence| layer < *divergence as usize);
 
-        history.truncate(n as usize);
+        if shared_with_active {
+            layer < self.history().len()
+                || self
+                    .redo_stack
+                    .iter()
+                    .any(|(LayerIndex(n), redone)| (*n as usize..redone.len()).contains(&layer))
+        } else {
+            self.branches
+                .get(*branch as usize)
+                .and_then(Option::as_ref)
+                .is_some_and(|branch| layer < branch.history.len())
+        }
+    }
+
+    /// Returns the index of the active branch.
+    pub fn active_branch(&self) -> BranchIndex {
+        self.active_branch
+    }
 
-        PaintableHistory { history }
+    /// Returns an iterator over every branch index that hasn't been pruned, and where that branch
+    /// forked from.
+    pub fn iter_branches(&self) -> impl Iterator<Item = (BranchIndex, Option<BranchFork>)> {
+        self.branches
+            .iter()
+            .enumerate()
+            .filter_map(|(i, branch)| Some((BranchIndex(i as u32), branch.as_ref()?.fork)))
+    }
+
+    /// Returns the given branch and its ancestors, each paired with the number of leading layers
+    /// the given branch shares with it.
+    fn iter_ancestry(&self, branch: BranchIndex) -> impl Iterator<Item = (BranchIndex, u32)> {
+        std::iter::successors(Some((branch, u32::MAX)), |(branch, shared_layers)| {
+            let fork = self.branches.get(**branch as usize)?.as_ref()?.fork?;
+
+            Some((fork.parent, (*shared_layers).min(*fork.layer)))
+        })
+    }
+
+    /// Compares two branches, returning the first layer index at which they may differ.
+    ///
+    /// Layers before this index are shared by both branches. Returns `None` if the branches share
+    /// every layer, like when comparing a branch with itself.
+    pub fn divergence(&self, a: BranchIndex, b: BranchIndex) -> Option<LayerIndex> {
+        let a_ancestry = self.iter_ancestry(a).collect::<Vec<_>>();
+
+        self.iter_ancestry(b)
+            .find_map(|(b_ancestor, b_shared_layers)| {
+                a_ancestry
+                    .iter()
+                    .find(|(a_ancestor, _)| *a_ancestor == b_ancestor)
+                    .map(|(_, a_shared_layers)| (*a_shared_layers).min(b_shared_layers))
+            })
+            .map_or(Some(LayerIndex(0)), |shared_layers| {
+                (shared_layers < u32::MAX).then_some(LayerIndex(shared_layers))
+            })
+    }
+
+    /// Returns a new [`PaintableHistory`] with the same branches and layers, but with every value
+    /// mapped by the given function.
+    pub fn map<D>(&self, f: impl Fn(&C) -> D) -> PaintableHistory<D> {
+        PaintableHistory {
+            branches: self
+                .branches
+                .iter()
+                .map(|branch| Some(branch.as_ref()?.map(&f)))
+                .collect(),
+            active_branch: self.active_branch,
+            checkpoint: self.checkpoint,
+            redo_stack: self
+                .redo_stack
+                .iter()
+                .map(|(layer, history)| (*layer, history.map(&f)))
+                .collect(),
+        }
+    }
+
+    /// Returns a new [`PaintableHistory`] with the same branches, checkpoint and redo stack, but
+    /// with no value recorded at any layer.
+    ///
+    /// Histories of entities added after painting has started are created from an existing one,
+    /// so that branch indices stay in sync between them.
+    pub fn without_layers<D>(&self) -> PaintableHistory<D> {
+        PaintableHistory {
+            branches: self
+                .branches
+                .iter()
+                .map(|branch| {
+                    let branch = branch.as_ref()?;
+
+                    Some(PaintableHistoryBranch {
+                        history: empty_layers_like(&branch.history),
+                        fork: branch.fork,
+                    })
+                })
+                .collect(),
+            active_branch: self.active_branch,
+            checkpoint: self.checkpoint,
+            redo_stack: self
+                .redo_stack
+                .iter()
+                .map(|(layer, history)| (*layer, empty_layers_like(history)))
+                .collect(),
+        }
     }
 
     /// Returns this [`PaintableHistory`] with the given value/index as its new ending.
@@ -106,16 +488,106 @@ impl<C> PaintableHistory<C> {
     ///
     /// If the layer index is much higher than the current history length, the new history will
     /// have `None`s in the interim.
-    pub fn with_end(self, n: LayerIndex, value: Option<C>) -> Self {
-        let PaintableHistory { mut history } = self.truncate(n);
+    ///
+    /// Forgotten layers can't be recorded over, so if the layer index is before the oldest stored
+    /// layer, the value is recorded at the oldest stored layer instead.
+    pub fn with_end(mut self, n: LayerIndex, value: Option<C>) -> Self {
+        self.record(n, value);
+        self
+    }
+
+    /// Records the given value/index as the new ending in place, like [`Self::with_end`].
+    pub fn record(&mut self, LayerIndex(n): LayerIndex, value: Option<C>) {
+        let history = self.history_mut();
+        history.truncate(n as usize);
 
-        history.extend(std::iter::repeat_with(|| None).take(n.0 as usize - history.len()));
+        let empty_layers = (n as usize).saturating_sub(history.len());
+        history.extend(std::iter::repeat_with(|| None).take(empty_layers));
 
         history.push(value);
+    }
+}
+
+/// A plain copy of a [`PaintableHistory`], with every layer of a branch stored contiguously.
+///
+/// Unlike [`PaintableHistory`], this can be serialized with reflection, but it's expensive to
+/// clone.
+#[derive(Clone, PartialEq, Debug, Reflect)]
+pub struct PaintableHistoryRecord<C> {
+    branches: Vec<Option<PaintableHistoryBranchRecord<C>>>,
+    active_branch: BranchIndex,
+    checkpoint: LayerIndex,
+    redo_stack: Vec<(LayerIndex, Vec<Option<C>>)>,
+}
+
+/// A plain copy of a single branch of a [`PaintableHistory`].
+#[derive(Clone, PartialEq, Debug, Reflect)]
+pub struct PaintableHistoryBranchRecord<C> {
+    /// The first layer stored in this branch.
+    start: LayerIndex,
+    history: Vec<Option<C>>,
+    fork: Option<BranchFork>,
+}
+
+impl<C: Clone> From<&PaintableHistory<C>> for PaintableHistoryRecord<C> {
+    fn from(paintable_history: &PaintableHistory<C>) -> Self {
+        PaintableHistoryRecord {
+            branches: paintable_history
+                .branches
+                .iter()
+                .map(|branch| {
+                    let branch = branch.as_ref()?;
+
+                    Some(PaintableHistoryBranchRecord {
+                        start: LayerIndex(branch.history.start() as u32),
+                        history: branch.history.iter().cloned().collect(),
+                        fork: branch.fork,
+                    })
+                })
+                .collect(),
+            active_branch: paintable_history.active_branch,
+            checkpoint: paintable_history.checkpoint,
+            redo_stack: paintable_history
+                .redo_stack
+                .iter()
+                .map(|(layer, history)| (*layer, history.iter().cloned().collect()))
+                .collect(),
+        }
+    }
+}
+
+impl<C> From<PaintableHistoryRecord<C>> for PaintableHistory<C> {
+    fn from(record: PaintableHistoryRecord<C>) -> Self {
+        let layer_storage = |LayerIndex(start): LayerIndex, history: Vec<Option<C>>| {
+            let mut storage = LayerStorage::starting_at(start as usize);
+            storage.extend(history);
+            storage
+        };
+
+        PaintableHistory {
+            branches: record
+                .branches
+                .into_iter()
+                .map(|branch| {
+                    let branch = branch?;
 
-        PaintableHistory { history }
+                    Some(PaintableHistoryBranch {
+                        history: layer_storage(branch.start, branch.history),
+                        fork: branch.fork,
+                    })
+                })
+                .collect(),
+            active_branch: record.active_branch,
+            checkpoint: record.checkpoint,
+            redo_stack: record
+                .redo_stack
+                .into_iter()
+                .map(|(layer, history)| (layer, layer_storage(layer, history)))
+                .collect(),
+        }
     }
 }
+
 /// Send this message when you want to record a new layer.
 #[derive(Default, Debug, Copy, Clone, PartialEq, Eq, Message)]
 pub struct RecordPresent {
@@ -125,19 +597,20 @@ pub struct RecordPresent {
 
 /// System that records the present value of a component into the history if it has a corresponding
 /// `PaintableHistory` component.
-fn record_present<C>() -> MessagesReadAnd<
-    RecordPresent,
-    QueryMap<(&'static PaintableHistory<C>, Option<&'static C>), ComponentSet<PaintableHistory<C>>>,
->
-where
+///
+/// This runs for every history on every layer, so histories are appended to in place rather than
+/// cloned and set with an effect.
+fn record_present<C>(
+    mut messages: MessageReader<RecordPresent>,
+    mut histories: Query<(&mut PaintableHistory<C>, Option<&C>)>,
+) where
     C: Component + Clone,
 {
-    messages_read_and(|RecordPresent { layer }| {
-        let layer = *layer;
-        query_map(move |(history, c): (&PaintableHistory<C>, Option<&C>)| {
-            component_set(history.clone().with_end(layer, c.cloned()))
-        })
-    })
+    messages.read().for_each(|&RecordPresent { layer }| {
+        histories
+            .iter_mut()
+            .for_each(|(mut history, c)| history.record(layer, c.cloned()));
+    });
 }
 
 /// Send this message when you want to remove paint layers.
@@ -157,23 +630,102 @@ impl TruncatePaintLayers {
             layer: LayerIndex(layer_index),
         }
     }
+}
 
-    /// Returns an immutable reference to the internal `LayerIndex` value.
-    pub fn layer(&self) -> &LayerIndex {
-        &self.layer
-    }
+/// Truncates every history in place, like [`record_present`] records them.
+fn truncate_history<C>(
+    mut messages: MessageReader<TruncatePaintLayers>,
+    mut histories: Query<&mut PaintableHistory<C>>,
+) where
+    C: Component + Clone,
+{
+    messages.read().for_each(|&TruncatePaintLayers { layer }| {
+        histories
+            .iter_mut()
+            .for_each(|mut history| history.truncate_to_redo_stack(layer));
+    });
+}
+
+/// Send this message when you want to restore the most recently truncated paint layers.
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq, Message)]
+pub struct RedoPaintLayers;
+
+fn redo_history<C>() -> MessagesReadAnd<
+    RedoPaintLayers,
+    QueryMap<&'static PaintableHistory<C>, ComponentSet<PaintableHistory<C>>>,
+>
+where
+    C: Component + Clone,
+{
+    messages_read_and(|_: &RedoPaintLayers| {
+        query_map(|paintable_history: &PaintableHistory<C>| {
+            component_set(paintable_history.clone().redo())
+        })
+    })
+}
+
+/// Send this message when the present should continue on a new branch, like when a new layer is
+/// painted while there are truncated layers that could be redone.
+///
+/// Every history branches off when this is sent, so that branch indices stay in sync between
+/// them.
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq, Message)]
+pub struct BranchPaintLayers;
+
+fn branch_history<C>() -> MessagesReadAnd<
+    BranchPaintLayers,
+    QueryMap<&'static PaintableHistory<C>, ComponentSet<PaintableHistory<C>>>,
+>
+where
+    C: Component + Clone,
+{
+    messages_read_and(|_: &BranchPaintLayers| {
+        query_map(|paintable_history: &PaintableHistory<C>| {
+            component_set(paintable_history.clone().branch_off())
+        })
+    })
+}
+
+/// Send this message when you want to make a different branch of the history active.
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq, Message)]
+pub struct SwitchPaintBranch {
+    /// The branch to make active.
+    pub branch: BranchIndex,
 }
 
-fn truncate_history<C>() -> MessagesReadAnd<
-    TruncatePaintLayers,
+fn switch_branch_history<C>() -> MessagesReadAnd<
+    SwitchPaintBranch,
     QueryMap<&'static PaintableHistory<C>, ComponentSet<PaintableHistory<C>>>,
 >
 where
     C: Component + Clone,
 {
-    messages_read_and(|&TruncatePaintLayers { layer }| {
+    messages_read_and(|&SwitchPaintBranch { branch }| {
         query_map(move |paintable_history: &PaintableHistory<C>| {
-            component_set(paintable_history.clone().truncate(layer))
+            component_set(paintable_history.clone().switch_branch(branch))
+        })
+    })
+}
+
+/// Send this message when you want to compact every paint layer before the given checkpoint.
+///
+/// See [`PaintableHistory::compact`].
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq, Message)]
+pub struct CompactPaintLayers {
+    /// The oldest layer to keep.
+    pub checkpoint: LayerIndex,
+}
+
+fn compact_history<C>() -> MessagesReadAnd<
+    CompactPaintLayers,
+    QueryMap<&'static PaintableHistory<C>, ComponentSet<PaintableHistory<C>>>,
+>
+where
+    C: Component + Clone,
+{
+    messages_read_and(|&CompactPaintLayers { checkpoint }| {
+        query_map(move |paintable_history: &PaintableHistory<C>| {
+            component_set(paintable_history.clone().compact(checkpoint))
         })
     })
 }
@@ -182,7 +734,7 @@ where
 /// the universal history state, like with [`last_layer_index`].
 #[derive(Clone, PartialEq, Eq, Debug, Default, Component, Reflect)]
 #[reflect(Component)]
-#[require(Name = "HistoryUnit", PaintableHistory::<HistoryUnit> { history: vec![Some(HistoryUnit)] })]
+#[require(Name = "HistoryUnit", PaintableHistory::<HistoryUnit>::default().with_end(LayerIndex(0), Some(HistoryUnit)))]
 pub struct HistoryUnit;
 
 /// System that returns the last layer index in the history. Pipe this into a system
@@ -202,3 +754,168 @@ pub fn triggerable_last_layer_index<E: Event>(
 ) -> LayerIndex {
     last_layer_index(paintable_history)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Constructs a [`PaintableHistory`] where every layer stores its own index.
+    fn counting_history(len: u32) -> PaintableHistory<u32> {
+        (0..len).fold(default(), |history, layer| {
+            history.with_end(LayerIndex(layer), Some(layer))
+        })
+    }
+
+    /// Constructs a [`PaintableHistory`] with three branches:
+    /// - branch 0 has layers 0 to 5,
+    /// - branch 1 forks from branch 0 at layer 3,
+    /// - and branch 2, which is active, forks from branch 1 at layer 5.
+    fn branching_history() -> PaintableHistory<u32> {
+        counting_history(6)
+            .truncate_redoable(LayerIndex(3))
+            .branch_off()
+            .with_end(LayerIndex(3), Some(13))
+            .with_end(LayerIndex(4), Some(14))
+            .with_end(LayerIndex(5), Some(15))
+            .truncate_redoable(LayerIndex(5))
+            .branch_off()
+            .with_end(LayerIndex(5), Some(25))
+    }
+
+    #[test]
+    fn divergence_of_a_branch_with_itself_is_none() {
+        let history = branching_history();
+
+        (0..3).for_each(|branch| {
+            assert_eq!(
+                history.divergence(BranchIndex(branch), BranchIndex(branch)),
+                None
+            );
+        });
+    }
+
+    #[test]
+    fn divergence_is_the_earliest_fork_between_branches() {
+        let history = branching_history();
+
+        [((0, 1), 3), ((1, 2), 5), ((0, 2), 3)]
+            .into_iter()
+            .for_each(|((a, b), layer)| {
+                assert_eq!(
+                    history.divergence(BranchIndex(a), BranchIndex(b)),
+                    Some(LayerIndex(layer))
+                );
+                assert_eq!(
+                    history.divergence(BranchIndex(b), BranchIndex(a)),
+                    Some(LayerIndex(layer))
+                );
+            });
+    }
+
+    #[test]
+    fn divergence_of_unrelated_branches_is_the_first_layer() {
+        let history = branching_history().switch_branch(BranchIndex(4));
+
+        assert_eq!(
+            history.divergence(BranchIndex(0), BranchIndex(4)),
+            Some(LayerIndex(0))
+        );
+        assert_eq!(
+            history.divergence(BranchIndex(3), BranchIndex(4)),
+            Some(LayerIndex(0))
+        );
+    }
+
+    #[test]
+    fn branches_keep_their_layers() {
+        let history = branching_history();
+
+        assert_eq!(
+            [0, 1, 2].map(|branch| {
+                let history = history.clone().switch_branch(BranchIndex(branch));
+                (0..6)
+                    .map(|layer| history.get(LayerIndex(layer)).copied())
+                    .collect::<Vec<_>>()
+            }),
+            [
+                vec![Some(0), Some(1), Some(2), Some(3), Some(4), Some(5)],
+                vec![Some(0), Some(1), Some(2), Some(13), Some(14), Some(15)],
+                vec![Some(0), Some(1), Some(2), Some(13), Some(14), Some(25)],
+            ]
+        );
+    }
+
+    #[test]
+    fn compact_forgets_layers_before_the_checkpoint() {
+        let history = counting_history(100).compact(LayerIndex(70));
+
+        assert_eq!(history.checkpoint(), LayerIndex(70));
+        assert_eq!(history.get(LayerIndex(69)), None);
+        assert_eq!(history.get(LayerIndex(70)), Some(&70));
+        assert_eq!(history.get(LayerIndex(99)), Some(&99));
+        assert_eq!(history.last_layer_index(), Some(LayerIndex(99)));
+
+        let truncated = history.clone().truncate_redoable(LayerIndex(10));
+        assert_eq!(truncated.last_layer_index(), Some(LayerIndex(70)));
+        assert_eq!(truncated.redo(), history);
+    }
+
+    #[test]
+    fn with_end_before_the_checkpoint_records_at_the_oldest_stored_layer() {
+        let history = counting_history(10)
+            .compact(LayerIndex(5))
+            .with_end(LayerIndex(2), Some(42));
+
+        assert_eq!(history.get(LayerIndex(5)), Some(&42));
+        assert_eq!(history.last_layer_index(), Some(LayerIndex(5)));
+    }
+
+    #[test]
+    fn compact_to_an_older_checkpoint_does_nothing() {
+        let history = counting_history(10).compact(LayerIndex(5));
+
+        assert_eq!(history.clone().compact(LayerIndex(3)), history);
+        assert_eq!(history.clone().compact(LayerIndex(5)), history);
+    }
+
+    #[test]
+    fn compact_prunes_branches_that_diverge_before_the_checkpoint() {
+        let history = branching_history();
+
+        let iter_branch_indices = |history: &PaintableHistory<u32>| {
+            history
+                .iter_branches()
+                .map(|(branch, _)| *branch)
+                .collect::<Vec<_>>()
+        };
+
+        let compacted = history.clone().compact(LayerIndex(3));
+        assert_eq!(iter_branch_indices(&compacted), vec![1, 2]);
+        assert!(!compacted.can_reach(LayerIndex(3), BranchIndex(0)));
+        assert!(compacted.can_reach(LayerIndex(5), BranchIndex(1)));
+        assert_eq!(
+            compacted.divergence(BranchIndex(1), BranchIndex(2)),
+            Some(LayerIndex(5))
+        );
+
+        let compacted = compacted.switch_branch(BranchIndex(0));
+        assert_eq!(compacted.active_branch(), BranchIndex(2));
+
+        let compacted = history.compact(LayerIndex(5));
+        assert_eq!(iter_branch_indices(&compacted), vec![2]);
+        assert_eq!(compacted.get(LayerIndex(5)), Some(&25));
+    }
+
+    #[test]
+    fn compact_keeps_redoable_layers_after_the_checkpoint() {
+        let history = counting_history(10).truncate_redoable(LayerIndex(6));
+
+        let compacted = history.compact(LayerIndex(4));
+        assert!(compacted.can_redo());
+        assert!(compacted.can_reach(LayerIndex(9), BranchIndex(0)));
+
+        let redone = compacted.redo();
+        assert_eq!(redone.get(LayerIndex(3)), None);
+        assert_eq!(redone.get(LayerIndex(9)), Some(&9));
+    }
+}
@@ -1,26 +1,60 @@
 use std::time::Duration;
 
-use bevy::asset::RenderAssetUsages;
 use bevy::camera::visibility::RenderLayers;
+use bevy::mesh::morph::MeshMorphWeights;
+use bevy::mesh::skinning::{SkinnedMesh, SkinnedMeshInverseBindposes};
 use bevy::prelude::{Image, *};
-use bevy::render::render_resource::TextureFormat;
 use bevy::render::view::screenshot::{Screenshot, ScreenshotCaptured};
 use bevy_pipe_affect::prelude::*;
 use leafwing_input_manager::prelude::*;
 
 use crate::button_predicate::add_button_timer;
 use crate::clear_skies::ClearSkiesState;
-use crate::clear_skies::camera::{ClearSkiesRenderTarget, ClearSkiesResolution, PaintSkiesAction};
+use crate::clear_skies::camera::{ClearSkiesRenderTarget, PaintSkiesAction};
+use crate::clear_skies::paint_skies::canvas_atlas::{
+    CanvasSlot,
+    PaintCanvasAtlas,
+    PaintCanvasAtlasSettings,
+    save_screenshot_to_canvas,
+};
+use crate::clear_skies::paint_skies::canvas_copy::{CanvasCopied, CanvasCopy, CanvasCopyMode};
+use crate::clear_skies::paint_skies::clip_triangle::{PaintFrustum, clip_triangle};
+use crate::clear_skies::paint_skies::layer_distance::{
+    LayerDistanceCurve,
+    LayerDistanceCurves,
+    ParallaxDepthBand,
+};
+use crate::clear_skies::paint_skies::material_transfer::{PaintSource, TransferTriangle};
+use crate::clear_skies::paint_skies::paint_brush::{PaintBrush, PaintBrushSettings};
 use crate::clear_skies::paint_skies::paint_layer_history::{
+    BranchIndex,
+    BranchPaintLayers,
+    HistoryUnit,
     PaintLayerHistoryPlugin,
     PaintableHistory,
     RecordPaintLayerHistorySet,
     RecordPresent,
+    RedoPaintLayers,
+    SwitchPaintBranch,
     TruncatePaintLayers,
     last_layer_index,
     triggerable_last_layer_index,
 };
-use crate::clear_skies::paint_skies::triangle_with_uvs::{OctahedronWithUvs, TriangleWithUvs};
+use crate::clear_skies::paint_skies::paint_visibility::{PaintOccluders, world_triangle};
+use crate::clear_skies::paint_skies::painted_sky_material::{
+    PaintedSkyMaterial,
+    PaintedSkySettings,
+    layer_uvs,
+};
+use crate::clear_skies::paint_skies::posed_mesh::{MeshPose, posed_mesh};
+use crate::clear_skies::paint_skies::replay::PaintReplay;
+use crate::clear_skies::paint_skies::sky_projection::SkyProjection;
+use crate::clear_skies::paint_skies::spherical_coords::LookAtSphericalCoords;
+use crate::clear_skies::paint_skies::triangle_with_uvs::{
+    OctahedronWithUvs,
+    TriangleWithUvs,
+    merge_octahedra,
+};
 use crate::clear_skies::play_skies::PlaySkiesCamera;
 use crate::clear_skies::render_layers::{PAINTABLE_LAYER, PAINTED_LAYER};
 use crate::pipe_system::pipe;
@@ -39,6 +73,13 @@ impl Plugin for PaintMeshesPlugin {
             PaintSkiesAction::Remove,
         );
 
+        let redo_paint_layer_timer = add_button_timer(
+            app,
+            Timer::new(Duration::from_millis(100), TimerMode::Repeating),
+            default(),
+            PaintSkiesAction::Redo,
+        );
+
         let add_layer_timer = add_predicate_timer(
             app,
             Timer::new(Duration::from_millis(100), TimerMode::Repeating),
@@ -50,27 +91,32 @@ impl Plugin for PaintMeshesPlugin {
             .add_plugins((
                 PaintLayerHistoryPlugin::<GlobalTransform>::default(),
                 PaintLayerHistoryPlugin::<ActionState<PaintSkiesAction>>::default(),
+                PaintLayerHistoryPlugin::<MeshMorphWeights>::default(),
             ))
             .add_systems(
                 OnEnter(ClearSkiesState::Setup),
-                (
-                    create_paint_skies_canvas.pipe(affect),
-                    (move || {
-                        (
-                            command_spawn(
-                                Observer::new(paint_canvas.pipe(affect))
-                                    .with_entity(add_layer_timer),
-                            ),
-                            command_spawn(
-                                Observer::new(remove_paint_layers.pipe(affect))
-                                    .with_entity(remove_paint_layer_timer),
-                            ),
-                        )
-                    })
-                    .pipe(affect),
-                ),
+                (move || {
+                    (
+                        command_spawn(
+                            Observer::new(paint_canvas.pipe(affect)).with_entity(add_layer_timer),
+                        ),
+                        command_spawn(Observer::new(
+                            triggerable_last_layer_index::<CanvasSkipped>
+                                .pipe(trigger_paint_layer)
+                                .pipe(affect),
+                        )),
+                        command_spawn(
+                            Observer::new(remove_paint_layers.pipe(affect))
+                                .with_entity(remove_paint_layer_timer),
+                        ),
+                        command_spawn(
+                            Observer::new(redo_paint_layers.pipe(affect))
+                                .with_entity(redo_paint_layer_timer),
+                        ),
+                    )
+                })
+                .pipe(affect),
             )
-            .register_type::<PaintSkiesCanvas>()
             .add_systems(
                 Update,
                 (
@@ -78,21 +124,40 @@ impl Plugin for PaintMeshesPlugin {
                     last_layer_index
                         .pipe(paint_meshes)
                         .pipe(affect)
+                        .in_set(PaintMeshesSet)
                         .after(RecordPaintLayerHistorySet)
                         .run_if(
                             in_state(ClearSkiesState::PaintSkies)
                                 .and_then(on_message::<RecordPresent>),
                         ),
-                    (truncate_paint_layers_meshes
+                    switch_paint_branch
                         .pipe(affect)
-                        .run_if(on_message::<TruncatePaintLayers>),)
                         .run_if(in_state(ClearSkiesState::PaintSkies)),
+                    show_active_branch_meshes
+                        .pipe(affect)
+                        .after(RecordPaintLayerHistorySet)
+                        .run_if(
+                            in_state(ClearSkiesState::PaintSkies).and(
+                                on_message::<TruncatePaintLayers>
+                                    .or(on_message::<RedoPaintLayers>)
+                                    .or(on_message::<SwitchPaintBranch>)
+                                    .or(on_message::<BranchPaintLayers>),
+                            ),
+                        ),
                 ),
             );
     }
 }
 
+/// System set for the system that paints meshes for new paint layers.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default, Hash, SystemSet)]
+pub struct PaintMeshesSet;
+
 /// Marker component for paintable meshes.
+///
+/// Put a [`PaintableScene`] on an ancestor to mark every mesh below it instead.
+///
+/// [`PaintableScene`]: crate::clear_skies::paint_skies::paintable_scene::PaintableScene
 #[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Reflect, Component)]
 #[reflect(Component)]
 #[require(Mesh3d, RenderLayers = PAINTABLE_LAYER)]
@@ -103,16 +168,13 @@ fn remove_paint_layers(
     history: Single<&PaintableHistory<ActionState<PaintSkiesAction>>>,
 ) -> MessageWrite<TruncatePaintLayers> {
     let last_layer_painted = history
-        .iter_enumerate_layers()
-        .collect::<Vec<_>>()
-        .iter()
-        .rev()
+        .iter_enumerate_layers_rev()
         // skip 1 so at least 1 layer is always removed
         .skip(1)
         .find(|(_, action_state)| {
             action_state.is_some_and(|action_state| action_state.pressed(&PaintSkiesAction::Paint))
         })
-        .map(|(layer_index, _)| *layer_index)
+        .map(|(layer_index, _)| layer_index)
         .unwrap_or_default();
 
     message_write(TruncatePaintLayers::new(LayerIndex(
@@ -121,29 +183,80 @@ fn remove_paint_layers(
     )))
 }
 
-fn truncate_paint_layers_meshes() -> MessagesReadAnd<
-    TruncatePaintLayers,
-    RunFnSystem<Query<'static, 'static, (Entity, &'static PaintedMesh)>, Vec<EntityCommandDespawn>>,
-> {
-    messages_read_and(|truncate_paint_layers: &TruncatePaintLayers| {
-        let layer = *truncate_paint_layers.layer();
-        run_fn_system(move |paint_layers: Query<(Entity, &PaintedMesh)>| {
-            paint_layers
-                .iter()
-                .filter(|(_, painted_mesh)| *painted_mesh.paint_layer >= layer.0)
-                .map(|(entity, _)| entity)
-                .map(entity_command_despawn)
-                .collect()
+fn redo_paint_layers(_: On<PredicateTimerFinished>) -> MessageWrite<RedoPaintLayers> {
+    message_write(RedoPaintLayers)
+}
+
+/// Switches to the next branch of the paint layer history, wrapping around to the first.
+fn switch_paint_branch(
+    paint_action: Single<&ActionState<PaintSkiesAction>>,
+    history: Single<&PaintableHistory<HistoryUnit>>,
+) -> Option<MessageWrite<SwitchPaintBranch>> {
+    paint_action
+        .just_pressed(&PaintSkiesAction::SwitchBranch)
+        .then(|| {
+            let active_branch = history.active_branch();
+
+            let branch = history
+                .iter_branches()
+                .map(|(branch, _)| branch)
+                .find(|branch| **branch > *active_branch)
+                .unwrap_or_default();
+
+            message_write(SwitchPaintBranch { branch })
         })
-    })
 }
 
+/// Painted meshes are shown only if their layer is part of the active branch of the history.
+///
+/// Hidden meshes are kept around so they can be shown again by redoing or switching branches.
+fn show_active_branch_meshes(
+    paint_action_history: Single<&PaintableHistory<ActionState<PaintSkiesAction>>>,
+    painted_meshes: Query<(Entity, &PaintedMesh, &Visibility)>,
+) -> Vec<EntityCommandInsert<Visibility>> {
+    painted_meshes
+        .iter()
+        .filter_map(|(entity, painted_mesh, visibility)| {
+            let new_visibility = painted_mesh.visibility_in(&paint_action_history);
+
+            (*visibility != new_visibility).then(|| entity_command_insert(entity, new_visibility))
+        })
+        .collect()
+}
+
+/// Paintable meshes added after painting has started get histories with the same branches as the
+/// others, so that branch indices stay in sync between them.
+///
+/// The joints and morph target weights posing animated meshes are tracked too, so that replays can
+/// pose them as they were painted.
 fn track_transform_for_paintable_meshes(
-    meshes: Query<Entity, (With<Mesh3d>, Added<Paintable>)>,
-) -> Vec<EntityCommandInsert<PaintableHistory<GlobalTransform>>> {
+    meshes: Query<
+        (Entity, Option<&SkinnedMesh>, Has<MeshMorphWeights>),
+        (With<Mesh3d>, Added<Paintable>),
+    >,
+    tracked: Query<(), With<PaintableHistory<GlobalTransform>>>,
+    history: Single<&PaintableHistory<HistoryUnit>>,
+) -> Vec<(
+    Vec<EntityCommandInsert<PaintableHistory<GlobalTransform>>>,
+    Option<EntityCommandInsert<PaintableHistory<MeshMorphWeights>>>,
+)> {
     meshes
         .into_iter()
-        .map(|entity| entity_command_insert(entity, PaintableHistory::default()))
+        .map(|(entity, skin, morphed)| {
+            // Joints can be shared between meshes, and keep the history they already have.
+            let joints = skin
+                .into_iter()
+                .flat_map(|skin| skin.joints.iter().copied())
+                .filter(|joint| !tracked.contains(*joint));
+
+            (
+                std::iter::once(entity)
+                    .chain(joints)
+                    .map(|entity| entity_command_insert(entity, history.without_layers()))
+                    .collect(),
+                morphed.then(|| entity_command_insert(entity, history.without_layers())),
+            )
+        })
         .collect()
 }
 
@@ -151,47 +264,99 @@ fn track_transform_for_paintable_meshes(
 #[derive(Default, Debug, PartialEq, Eq, Copy, Clone, Hash, Reflect, Deref, DerefMut)]
 pub struct LayerIndex(pub u32);
 
+/// Settings for splitting the prisms between layers into sub-layers when the paint skies camera
+/// turns quickly.
+#[derive(Debug, PartialEq, Copy, Clone, Reflect)]
+pub struct SubLayerSettings {
+    /// The most the paint skies camera can turn within a sub-layer, in radians.
+    pub max_angle: f32,
+    /// The most sub-layers that a layer can be split into.
+    pub max_sub_layers: u32,
+}
+
+impl Default for SubLayerSettings {
+    fn default() -> Self {
+        SubLayerSettings {
+            max_angle: 2.0_f32.to_radians(),
+            max_sub_layers: 8,
+        }
+    }
+}
+
+impl SubLayerSettings {
+    /// Returns how many sub-layers to split a layer into, given how the paint skies camera turned
+    /// since the previous one.
+    pub fn sub_layer_count(&self, previous: &GlobalTransform, current: &GlobalTransform) -> u32 {
+        let angle = previous.rotation().angle_between(current.rotation());
+
+        ((angle / self.max_angle).ceil() as u32).clamp(1, self.max_sub_layers.max(1))
+    }
+}
+
+/// Returns the transform the given fraction of the way between two transforms.
+fn interpolate_transform(
+    from: &GlobalTransform,
+    to: &GlobalTransform,
+    fraction: f32,
+) -> GlobalTransform {
+    if fraction <= 0.0 {
+        return *from;
+    }
+
+    if fraction >= 1.0 {
+        return *to;
+    }
+
+    let (from, to) = (from.compute_transform(), to.compute_transform());
+
+    GlobalTransform::from(Transform {
+        translation: from.translation.lerp(to.translation, fraction),
+        rotation: from.rotation.slerp(to.rotation, fraction),
+        scale: from.scale.lerp(to.scale, fraction),
+    })
+}
+
 /// Settings for the logic of painting layers.
-#[derive(Debug, PartialEq, Copy, Clone, Reflect, Resource)]
+#[derive(Debug, PartialEq, Clone, Reflect, Resource)]
 pub struct PaintLayerSettings {
-    pub zero_layer_distance: f32,
-    pub layer_distance_collapse_rate: f32,
+    /// How far from the play skies camera each layer is painted.
+    pub layer_distance_curve: LayerDistanceCurve,
+    /// How painted vertices are placed around the play skies camera at their layer distance.
+    pub sky_projection: SkyProjection,
+    /// Keeps some of the depth of painted vertices if set, instead of painting every vertex of a
+    /// layer at the same distance.
+    pub parallax_depth_band: Option<ParallaxDepthBand>,
+    /// Splits layers into sub-layers when the paint skies camera turns quickly if set.
+    pub sub_layers: Option<SubLayerSettings>,
+    /// Where painted meshes get their colors from.
+    pub paint_source: PaintSource,
+    /// How painted meshes are shaded with [`PaintSource::Canvas`].
+    pub sky_material: PaintedSkySettings,
     pub max_empty_layers: u32,
+    /// The most layers that can be undone before the oldest are compacted, see
+    /// [`PaintLayerCompactionPlugin`](crate::clear_skies::paint_skies::compaction::PaintLayerCompactionPlugin).
+    ///
+    /// Compacted layers can still be seen, collided with, touched for objectives and flown along
+    /// by rails, but their canvases lose resolution, and they're left out of replays since the
+    /// rest of their histories are forgotten.
+    pub layer_budget: u32,
 }
 
 impl Default for PaintLayerSettings {
     fn default() -> Self {
         PaintLayerSettings {
-            zero_layer_distance: 1000.0,
-            layer_distance_collapse_rate: 0.98,
+            layer_distance_curve: default(),
+            sky_projection: default(),
+            parallax_depth_band: None,
+            sub_layers: None,
+            paint_source: default(),
+            sky_material: default(),
             max_empty_layers: 10,
+            layer_budget: 1000,
         }
     }
 }
 
-/// Resource pointing to the image that is "painted to" with screenshots.
-///
-/// Painted meshes use this image as a texture.
-#[derive(Default, Debug, PartialEq, Clone, Deref, DerefMut, Reflect, Resource)]
-#[reflect(Resource)]
-pub struct PaintSkiesCanvas(Handle<Image>);
-
-/// System that creates [`ClearSkiesRenderTarget`].
-pub fn create_paint_skies_canvas(
-    resolution: Res<ClearSkiesResolution>,
-) -> AssetAddAnd<Image, CommandInsertResource<PaintSkiesCanvas>> {
-    let image = Image::new_target_texture(
-        resolution.x,
-        resolution.y,
-        TextureFormat::Rgba8UnormSrgb,
-        None,
-    );
-
-    asset_add_and(image, |handle| {
-        command_insert_resource(PaintSkiesCanvas(handle))
-    })
-}
-
 fn world_to_viewport_uv(
     camera: &Camera,
     camera_transform: &GlobalTransform,
@@ -211,26 +376,26 @@ fn world_to_viewport_uv(
     Some(uv_coords)
 }
 
-fn save_screenshot_to_canvas(
-    screenshot: On<ScreenshotCaptured>,
-) -> AssetAddAnd<Image, ResSet<PaintSkiesCanvas>> {
-    let image = Image {
-        asset_usage: RenderAssetUsages::RENDER_WORLD,
-        ..screenshot.image.clone()
-    };
-    asset_add_and(image, |handle| res_set(PaintSkiesCanvas(handle)))
-}
-
+/// Returns whether painting should keep adding layers.
+///
+/// This is false outside of [`ClearSkiesState::PaintSkies`], so that the add layer timer stops
+/// rather than painting while the sky is being played.
 fn paint_recently_pressed(
     last_layer_index: In<LayerIndex>,
+    state: Res<State<ClearSkiesState>>,
     paint_action_query: Single<(
         &ActionState<PaintSkiesAction>,
         &PaintableHistory<ActionState<PaintSkiesAction>>,
     )>,
     paint_layer_settings: Res<PaintLayerSettings>,
+    paint_replay: Res<PaintReplay>,
 ) -> bool {
     let (paint_action, paint_action_history) = *paint_action_query;
-    !paint_action.pressed(&PaintSkiesAction::Remove)
+    // layers are added by the replay itself while it's active
+    *state.get() == ClearSkiesState::PaintSkies
+        && paint_replay.is_inactive()
+        && !paint_action.pressed(&PaintSkiesAction::Remove)
+        && !paint_action.pressed(&PaintSkiesAction::Redo)
         && (paint_action.pressed(&PaintSkiesAction::Paint)
             || ((0..paint_layer_settings.max_empty_layers)
                 .map(|offset| {
@@ -241,47 +406,121 @@ fn paint_recently_pressed(
                 })))
 }
 
-fn trigger_paint_layer(last_layer_index: In<LayerIndex>) -> MessageWrite<RecordPresent> {
-    message_write(RecordPresent {
-        layer: LayerIndex(last_layer_index.0.0 + 1),
-    })
+fn trigger_paint_layer(
+    last_layer_index: In<LayerIndex>,
+    paint_action: Single<&ActionState<PaintSkiesAction>>,
+    history: Single<&PaintableHistory<HistoryUnit>>,
+) -> (
+    MessageWrite<RecordPresent>,
+    Option<MessageWrite<BranchPaintLayers>>,
+) {
+    (
+        message_write(RecordPresent {
+            layer: LayerIndex(last_layer_index.0.0 + 1),
+        }),
+        // Painting a new layer over redoable layers branches off, but recording empty layers
+        // doesn't.
+        (paint_action.pressed(&PaintSkiesAction::Paint) && history.can_redo())
+            .then_some(message_write(BranchPaintLayers)),
+    )
 }
 
+/// Event triggered instead of capturing a canvas when painting with
+/// [`PaintSource::MaterialTransfer`], which records a new paint layer right away.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Event)]
+pub struct CanvasSkipped;
+
+/// Effect that captures the [`ClearSkiesRenderTarget`] as the pending canvas and records it as a
+/// new paint layer, with a screenshot or a [`CanvasCopy`] depending on the [`CanvasCopyMode`].
+///
+/// With [`PaintSource::MaterialTransfer`], no canvas is captured and [`CanvasSkipped`] is
+/// triggered instead.
+pub type CaptureCanvas = (
+    Option<CommandSpawnAnd<Screenshot, (CommandSpawn<Observer>, CommandSpawn<Observer>)>>,
+    Option<CommandSpawnAnd<CanvasCopy, CommandSpawn<Observer>>>,
+    Option<CommandTrigger<CanvasSkipped>>,
+);
+
 fn paint_canvas(
     _: On<PredicateTimerFinished>,
+    state: Res<State<ClearSkiesState>>,
     render_target: Res<ClearSkiesRenderTarget>,
-) -> CommandSpawnAnd<Screenshot, (CommandSpawn<Observer>, CommandSpawn<Observer>)> {
-    command_spawn_and(
-        Screenshot::image((**render_target).clone()),
-        |screenshot_entity| {
-            (
-                command_spawn(
-                    Observer::new(
-                        triggerable_last_layer_index::<ScreenshotCaptured>
-                            .pipe(trigger_paint_layer)
-                            .pipe(affect),
+    atlas_settings: Res<PaintCanvasAtlasSettings>,
+    paint_layer_settings: Res<PaintLayerSettings>,
+) -> Option<CaptureCanvas> {
+    (*state.get() == ClearSkiesState::PaintSkies).then(|| {
+        capture_canvas(
+            &render_target,
+            atlas_settings.copy_mode,
+            paint_layer_settings.paint_source,
+        )
+    })
+}
+
+/// Returns the [`CaptureCanvas`] effect for the given render target.
+pub fn capture_canvas(
+    render_target: &ClearSkiesRenderTarget,
+    copy_mode: CanvasCopyMode,
+    paint_source: PaintSource,
+) -> CaptureCanvas {
+    if paint_source == PaintSource::MaterialTransfer {
+        return (None, None, Some(command_trigger(CanvasSkipped)));
+    }
+
+    match copy_mode {
+        CanvasCopyMode::Gpu => (
+            None,
+            Some(command_spawn_and(
+                CanvasCopy((**render_target).clone()),
+                |copy_entity| {
+                    command_spawn(
+                        Observer::new(
+                            triggerable_last_layer_index::<CanvasCopied>
+                                .pipe(trigger_paint_layer)
+                                .pipe(affect),
+                        )
+                        .with_entity(copy_entity),
                     )
-                    .with_entity(screenshot_entity),
-                ),
-                command_spawn(
-                    Observer::new(save_screenshot_to_canvas.pipe(affect))
-                        .with_entity(screenshot_entity),
-                ),
-            )
-        },
-    )
+                },
+            )),
+            None,
+        ),
+        CanvasCopyMode::CpuReadback => (
+            Some(command_spawn_and(
+                Screenshot::image((**render_target).clone()),
+                |screenshot_entity| {
+                    (
+                        command_spawn(
+                            Observer::new(
+                                triggerable_last_layer_index::<ScreenshotCaptured>
+                                    .pipe(trigger_paint_layer)
+                                    .pipe(affect),
+                            )
+                            .with_entity(screenshot_entity),
+                        ),
+                        command_spawn(
+                            Observer::new(save_screenshot_to_canvas).with_entity(screenshot_entity),
+                        ),
+                    )
+                },
+            )),
+            None,
+            None,
+        ),
+    }
 }
 
 fn triangle_projector_for_mesh_for_universe<'w>(
-    paint_layer_settings: &'w PaintLayerSettings,
-    layer_index: &'w LayerIndex,
+    sky_projection: SkyProjection,
+    parallax_depth_band: Option<ParallaxDepthBand>,
+    layer_distance: f32,
     paintable_camera: &'w Camera,
     paintable_camera_transform: &'w GlobalTransform,
     play_skies_camera: &'w Camera,
     play_skies_camera_transform: &'w GlobalTransform,
-) -> impl Fn(&'w GlobalTransform) -> Box<dyn Fn(Triangle3d) -> Option<TriangleWithUvs> + 'w> + 'w {
+) -> impl Fn(GlobalTransform) -> Box<dyn Fn(Triangle3d) -> Option<TriangleWithUvs> + 'w> + 'w {
     move |mesh_transform| {
-        Box::new(|triangle| {
+        Box::new(move |triangle| {
             let vertex_uvs = triangle
                 .vertices
                 .into_iter()
@@ -294,20 +533,33 @@ fn triangle_projector_for_mesh_for_universe<'w>(
                         world_translation,
                     )?;
 
-                    let viewport_coords = paintable_camera
-                        .world_to_viewport(paintable_camera_transform, world_translation)
-                        .ok()?;
+                    let from_paint_camera =
+                        world_translation - paintable_camera_transform.translation();
 
-                    let play_skies_ray = play_skies_camera
-                        .viewport_to_world(play_skies_camera_transform, viewport_coords)
-                        .ok()?;
+                    let coords = LookAtSphericalCoords::from_direction(from_paint_camera);
 
-                    let vertex = play_skies_ray.get_point(
-                        paint_layer_settings.zero_layer_distance
-                            * paint_layer_settings
-                                .layer_distance_collapse_rate
-                                .powf(**layer_index as f32),
-                    );
+                    let vertex_distance = parallax_depth_band.map_or(layer_distance, |band| {
+                        band.distance(layer_distance, from_paint_camera.length())
+                    });
+
+                    let vertex = match sky_projection.dome_point(
+                        play_skies_camera_transform.translation(),
+                        coords,
+                        vertex_distance,
+                    ) {
+                        Some(vertex) => vertex,
+                        None => {
+                            let viewport_coords = paintable_camera
+                                .world_to_viewport(paintable_camera_transform, world_translation)
+                                .ok()?;
+
+                            let play_skies_ray = play_skies_camera
+                                .viewport_to_world(play_skies_camera_transform, viewport_coords)
+                                .ok()?;
+
+                            play_skies_ray.get_point(vertex_distance)
+                        }
+                    };
 
                     Some((vertex, uv))
                 })
@@ -324,17 +576,51 @@ fn triangle_projector_for_mesh_for_universe<'w>(
 }
 
 /// Component for meshes that are created by painting the paintable meshes.
-#[derive(Copy, Clone, PartialEq, Eq, Debug, Component, Reflect)]
+///
+/// Every triangle painted from one paintable mesh on one layer is batched into the same painted
+/// mesh, as one octahedron each.
+#[derive(Clone, PartialEq, Eq, Debug, Component, Reflect)]
 #[reflect(Component)]
 #[relationship(relationship_target = PaintedMeshes)]
 pub struct PaintedMesh {
     /// The entity whose mesh was used to paint this mesh.
     #[relationship]
     pub painted_from: Entity,
-    /// The triangle of the original mesh that was used to paint this mesh.
-    pub triangle_index: usize,
+    /// The triangle of the original mesh that each octahedron of this mesh was painted from.
+    ///
+    /// Octahedra take up 8 consecutive triangles of the mesh each, in this order.
+    pub triangle_indices: Vec<usize>,
     /// The layer that this mesh was painted on.
     pub paint_layer: LayerIndex,
+    /// The branch of the history that this mesh was painted on.
+    pub paint_branch: BranchIndex,
+}
+
+impl PaintedMesh {
+    /// Returns the visibility this mesh should have given the paint skies camera's action history.
+    ///
+    /// Painted meshes are only visible if their layer is part of the active branch, and was
+    /// painted on in it. Painting over redoable layers always branches off, so a layer that was
+    /// painted on can only come back by redoing the same layers, while layers that were only
+    /// recorded as empty in the meantime leave the meshes under them hidden.
+    pub fn visibility_in(
+        &self,
+        paint_action_history: &PaintableHistory<ActionState<PaintSkiesAction>>,
+    ) -> Visibility {
+        let shares_layer = paint_action_history
+            .divergence(paint_action_history.active_branch(), self.paint_branch)
+            .is_none_or(|divergence| *self.paint_layer < *divergence);
+
+        let painted_on = paint_action_history
+            .get(self.paint_layer)
+            .is_some_and(|action_state| action_state.pressed(&PaintSkiesAction::Paint));
+
+        if shares_layer && painted_on {
+            Visibility::Inherited
+        } else {
+            Visibility::Hidden
+        }
+    }
 }
 
 /// The meshes that were painted from this entity's mesh.
@@ -343,6 +629,45 @@ pub struct PaintedMesh {
 #[relationship_target(relationship = PaintedMesh)]
 pub struct PaintedMeshes(Vec<Entity>);
 
+/// Effect that spawns a [`PaintedMesh`] with the given material.
+pub type SpawnLayerMesh<M> = AssetAddAnd<
+    Mesh,
+    CommandSpawn<(
+        Mesh3d,
+        MeshMaterial3d<M>,
+        Transform,
+        RenderLayers,
+        PaintedMesh,
+    )>,
+>;
+
+/// Query data for the material of a painted mesh, which is a [`PaintedSkyMaterial`] with
+/// [`PaintSource::Canvas`] or the transferred [`StandardMaterial`] with
+/// [`PaintSource::MaterialTransfer`].
+pub type PaintedMaterials = AnyOf<(
+    &'static MeshMaterial3d<PaintedSkyMaterial>,
+    &'static MeshMaterial3d<StandardMaterial>,
+)>;
+
+/// Returns the [`SpawnLayerMesh`] effect for the given mesh, material, transform and
+/// [`PaintedMesh`].
+fn spawn_layer_mesh<M: Material>(
+    mesh: Mesh,
+    material: Handle<M>,
+    transform: Transform,
+    painted_mesh: PaintedMesh,
+) -> SpawnLayerMesh<M> {
+    asset_add_and(mesh, move |mesh_handle| {
+        command_spawn((
+            Mesh3d(mesh_handle),
+            MeshMaterial3d(material),
+            transform,
+            PAINTED_LAYER,
+            painted_mesh,
+        ))
+    })
+}
+
 fn paint_meshes(
     In(layer_index): In<LayerIndex>,
     paintable_meshes: Query<
@@ -351,10 +676,14 @@ fn paint_meshes(
             &Mesh3d,
             &GlobalTransform,
             &PaintableHistory<GlobalTransform>,
+            Option<&MeshMaterial3d<StandardMaterial>>,
+            MeshPose,
         ),
         With<Paintable>,
     >,
     mesh_assets: Res<Assets<Mesh>>,
+    joints: Query<&GlobalTransform>,
+    inverse_bindposes: Res<Assets<SkinnedMeshInverseBindposes>>,
     paintable_camera: Single<
         (
             &Camera,
@@ -362,37 +691,52 @@ fn paint_meshes(
             &PaintableHistory<GlobalTransform>,
             &ActionState<PaintSkiesAction>,
             &PaintableHistory<ActionState<PaintSkiesAction>>,
+            &PaintBrush,
         ),
         With<Paintable>,
     >,
     play_skies_camera: Single<(&Camera, &GlobalTransform), With<PlaySkiesCamera>>,
     paint_layer_settings: Res<PaintLayerSettings>,
-    paint_skies_canvas: Res<PaintSkiesCanvas>,
-) -> Vec<
-    AssetAddAnd<
-        Mesh,
-        AssetAddAnd<
-            StandardMaterial,
-            CommandSpawn<(
-                Mesh3d,
-                MeshMaterial3d<StandardMaterial>,
-                Transform,
-                RenderLayers,
-                PaintedMesh,
-            )>,
-        >,
-    >,
-> {
+    layer_distance_curves: Res<LayerDistanceCurves>,
+    brush_settings: Res<PaintBrushSettings>,
+    image_assets: Res<Assets<Image>>,
+    atlas: Res<PaintCanvasAtlas>,
+) -> Option<(
+    Vec<Option<SpawnLayerMesh<PaintedSkyMaterial>>>,
+    Vec<Option<SpawnLayerMesh<StandardMaterial>>>,
+    Option<ResSet<PaintCanvasAtlas>>,
+)> {
     let (
         paintable_camera,
         paintable_camera_transform,
         paintable_camera_transform_history,
         paint_action,
         paint_action_history,
+        brush,
     ) = *paintable_camera;
 
+    // Only the most recent canvas can be painted with, and only once.
+    let canvas = match paint_layer_settings.paint_source {
+        PaintSource::Canvas => Some(
+            atlas
+                .pending_slot()
+                .and_then(|slot| Some((slot, atlas.page(slot)?.material.clone())))?,
+        ),
+        PaintSource::MaterialTransfer => None,
+    };
+
+    let to_atlas_uvs = |triangle_with_uvs: TriangleWithUvs| match &canvas {
+        Some((canvas_slot, _)) => TriangleWithUvs {
+            uvs: triangle_with_uvs
+                .uvs
+                .map(|uv| atlas.slot_uv(*canvas_slot, uv)),
+            ..triangle_with_uvs
+        },
+        None => triangle_with_uvs,
+    };
+
     if !paint_action.pressed(&PaintSkiesAction::Paint) {
-        vec![]
+        None
     } else {
         let previous_layer_index = LayerIndex(layer_index.0.saturating_sub(1));
         let previous_paint_pressed = paint_action_history
@@ -404,94 +748,326 @@ fn paint_meshes(
             .flatten()
             .unwrap_or(paintable_camera_transform);
 
+        let paint_branch = paint_action_history.active_branch();
+
         let (play_skies_camera, play_skies_camera_transform) = *play_skies_camera;
 
-        let triangle_projector_for_mesh = triangle_projector_for_mesh_for_universe(
-            &paint_layer_settings,
-            &layer_index,
-            paintable_camera,
-            paintable_camera_transform,
-            play_skies_camera,
-            play_skies_camera_transform,
-        );
-        let previous_triangle_projector_for_mesh = triangle_projector_for_mesh_for_universe(
-            &paint_layer_settings,
-            &previous_layer_index,
-            paintable_camera,
-            previous_paintable_camera_transform,
-            play_skies_camera,
-            play_skies_camera_transform,
+        let layer_distance = |layer_index| {
+            paint_layer_settings
+                .layer_distance_curve
+                .distance(layer_index, &layer_distance_curves)
+        };
+
+        let (Some(distance), Some(previous_distance)) = (
+            layer_distance(layer_index),
+            layer_distance(previous_layer_index),
+        ) else {
+            warn!(
+                "layer distance curve {:?} can't be evaluated, so this layer can't be painted",
+                paint_layer_settings.layer_distance_curve
+            );
+            return None;
+        };
+
+        // Fast turns are split into sub-layers, so that the prisms between layers follow the
+        // paint skies camera's path instead of cutting straight across it.
+        let sub_layer_count = paint_layer_settings.sub_layers.map_or(1, |sub_layers| {
+            sub_layers.sub_layer_count(
+                previous_paintable_camera_transform,
+                paintable_camera_transform,
+            )
+        });
+
+        // How far each sub-layer boundary is from the previous layer to this one.
+        let fractions = (0..=sub_layer_count)
+            .map(|sub_layer| sub_layer as f32 / sub_layer_count as f32)
+            .collect::<Vec<_>>();
+
+        let paintable_camera_transforms = fractions
+            .iter()
+            .map(|fraction| {
+                interpolate_transform(
+                    previous_paintable_camera_transform,
+                    paintable_camera_transform,
+                    *fraction,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let triangle_projectors_for_mesh = fractions
+            .iter()
+            .zip(&paintable_camera_transforms)
+            .map(|(fraction, paintable_camera_transform)| {
+                triangle_projector_for_mesh_for_universe(
+                    paint_layer_settings.sky_projection,
+                    paint_layer_settings.parallax_depth_band,
+                    previous_distance.lerp(distance, *fraction),
+                    paintable_camera,
+                    paintable_camera_transform,
+                    play_skies_camera,
+                    play_skies_camera_transform,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let frustums = paintable_camera_transforms
+            .iter()
+            .map(|paintable_camera_transform| {
+                PaintFrustum::new(paintable_camera, paintable_camera_transform).with_half_spaces(
+                    brush.half_spaces(
+                        &brush_settings,
+                        paintable_camera,
+                        paintable_camera_transform,
+                    ),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let aspect_ratio = paintable_camera
+            .logical_viewport_size()
+            .map_or(1.0, |viewport_size| viewport_size.x / viewport_size.y);
+
+        let mask_image = brush_settings
+            .mask_image
+            .as_ref()
+            .and_then(|mask_image| image_assets.get(mask_image));
+
+        // Mask images can't be clipped to, so triangles are kept whole if they touch the mask.
+        let brush_covers = |face: &TriangleWithUvs| {
+            brush.covers(
+                &brush_settings,
+                aspect_ratio,
+                mask_image,
+                face.uvs
+                    .into_iter()
+                    .chain([face.uvs.iter().sum::<Vec2>() / 3.0]),
+            )
+        };
+
+        // Animated meshes are painted as they currently look rather than in their bind pose.
+        let posed_meshes = paintable_meshes
+            .iter()
+            .filter_map(
+                |(entity, mesh, mesh_transform, mesh_transform_history, source_material, pose)| {
+                    let mesh = posed_mesh(
+                        mesh_assets.get(mesh)?,
+                        pose,
+                        mesh_transform,
+                        &joints,
+                        &inverse_bindposes,
+                        &image_assets,
+                    );
+
+                    Some((
+                        entity,
+                        mesh,
+                        mesh_transform,
+                        mesh_transform_history,
+                        source_material,
+                    ))
+                },
+            )
+            .collect::<Vec<_>>();
+
+        let occluders = PaintOccluders::new(
+            posed_meshes
+                .iter()
+                .filter_map(|(_, mesh, mesh_transform, _, _)| {
+                    let triangles = mesh.triangles().ok()?;
+
+                    Some(triangles.map(move |triangle| world_triangle(mesh_transform, triangle)))
+                })
+                .flatten(),
         );
+        let eye = paintable_camera_transform.translation();
 
-        paintable_meshes
+        let spawn_painted_meshes = posed_meshes
             .iter()
             .flat_map(
-                |(paintable_mesh_entity, mesh, mesh_transform, mesh_transform_history)| {
-                    let mesh = mesh_assets.get(mesh)?;
+                |&(
+                    paintable_mesh_entity,
+                    ref mesh,
+                    mesh_transform,
+                    mesh_transform_history,
+                    source_material,
+                )| {
+                    let mesh: &Mesh = mesh;
 
-                    let triangle_projector = triangle_projector_for_mesh(mesh_transform);
+                    let transfer_material = match paint_layer_settings.paint_source {
+                        PaintSource::Canvas => None,
+                        PaintSource::MaterialTransfer => Some((**source_material?).clone()),
+                    };
 
                     let previous_mesh_transform =
                         mesh_transform_history.get(previous_layer_index)?;
 
-                    let previous_triangle_projector =
-                        previous_triangle_projector_for_mesh(previous_mesh_transform);
+                    let mesh_transforms = fractions
+                        .iter()
+                        .map(|fraction| {
+                            interpolate_transform(
+                                previous_mesh_transform,
+                                mesh_transform,
+                                *fraction,
+                            )
+                        })
+                        .collect::<Vec<_>>();
+
+                    let triangle_projectors = triangle_projectors_for_mesh
+                        .iter()
+                        .zip(&mesh_transforms)
+                        .map(|(triangle_projector_for_mesh, mesh_transform)| {
+                            triangle_projector_for_mesh(*mesh_transform)
+                        })
+                        .collect::<Vec<_>>();
+
+                    let clip_frustums = frustums.iter().zip(&mesh_transforms).collect::<Vec<_>>();
 
-                    let spawn_commands = mesh
+                    let painted_octahedra = mesh
                         .clone()
                         .triangles()
                         .ok()?
                         .enumerate()
-                        .flat_map(|(triangle_index, triangle)| {
-                            Some((
-                                triangle_index,
-                                triangle_projector(triangle)?,
-                                previous_triangle_projector(triangle)?,
-                            ))
+                        .filter(|(_, triangle)| {
+                            occluders.is_visible(eye, world_triangle(mesh_transform, *triangle))
                         })
-                        .flat_map(
-                            |(triangle_index, triangle_with_uvs, previous_triangle_with_uvs)| {
-                                let octahedron_with_uvs = OctahedronWithUvs {
-                                    near_face: triangle_with_uvs,
-                                    far_face: previous_triangle_with_uvs,
-                                };
-                                let (centroid, centered_octahedron) =
-                                    octahedron_with_uvs.centered();
-
-                                let mesh = Mesh::from(centered_octahedron);
-
-                                // Note: We don't need to adjust this relative to camera translation
-                                // since we already calculated it in world-space
-                                let transform = Transform::from_translation(centroid);
-
-                                let material = StandardMaterial {
-                                    unlit: true,
-                                    ..StandardMaterial::from((**paint_skies_canvas).clone())
-                                };
-
-                                Some(asset_add_and(mesh, move |mesh_handle| {
-                                    asset_add_and(material, move |material_handle| {
-                                        command_spawn((
-                                            Mesh3d(mesh_handle),
-                                            MeshMaterial3d(material_handle),
-                                            transform,
-                                            PAINTED_LAYER,
-                                            PaintedMesh {
-                                                painted_from: paintable_mesh_entity,
-                                                triangle_index,
-                                                paint_layer: layer_index,
+                        .filter_map(|(triangle_index, triangle)| {
+                            let transfer_triangle = match transfer_material {
+                                Some(_) => Some(TransferTriangle::from_mesh(
+                                    mesh,
+                                    triangle,
+                                    triangle_index,
+                                )?),
+                                None => None,
+                            };
+
+                            Some((triangle_index, triangle, transfer_triangle))
+                        })
+                        .flat_map(|(triangle_index, triangle, transfer_triangle)| {
+                            // Only the parts of triangles that were on screen through every
+                            // sub-layer can be painted.
+                            clip_triangle(triangle, &clip_frustums)
+                                .into_iter()
+                                .filter_map(|triangle| {
+                                    let faces = triangle_projectors
+                                        .iter()
+                                        .map(|triangle_projector| triangle_projector(triangle))
+                                        .collect::<Option<Vec<_>>>()?;
+
+                                    Some((triangle, faces))
+                                })
+                                .filter(|(_, faces)| faces.last().is_some_and(brush_covers))
+                                .flat_map(|(triangle, faces)| {
+                                    // Transferred vertices keep the source mesh's UVs and colors
+                                    // instead of sampling the canvas.
+                                    let transferred = transfer_triangle.map(|transfer_triangle| {
+                                        triangle.vertices.map(|vertex| transfer_triangle.at(vertex))
+                                    });
+
+                                    let colors = transferred
+                                        .map_or([Vec4::ONE; 3], |transferred| {
+                                            transferred.map(|(_, color)| color)
+                                        });
+
+                                    faces
+                                        .into_iter()
+                                        .map(|face| match transferred {
+                                            Some(transferred) => TriangleWithUvs {
+                                                uvs: transferred.map(|(uv, _)| uv),
+                                                ..face
                                             },
-                                        ))
-                                    })
-                                }))
-                            },
-                        )
+                                            None => to_atlas_uvs(face),
+                                        })
+                                        .collect::<Vec<_>>()
+                                        .windows(2)
+                                        .map(|faces| {
+                                            (
+                                                triangle_index,
+                                                colors,
+                                                OctahedronWithUvs {
+                                                    near_face: faces[1],
+                                                    far_face: faces[0],
+                                                },
+                                            )
+                                        })
+                                        .collect::<Vec<_>>()
+                                })
+                                .collect::<Vec<_>>()
+                        })
+                        .collect::<Vec<_>>();
+
+                    let triangle_indices = painted_octahedra
+                        .iter()
+                        .map(|(triangle_index, _, _)| *triangle_index)
+                        .collect();
+
+                    // Both faces of an octahedron have the colors of the painted triangle.
+                    let colors = painted_octahedra
+                        .iter()
+                        .flat_map(|(_, colors, _)| colors.iter().chain(colors))
+                        .map(|color| color.to_array())
                         .collect::<Vec<_>>();
 
-                    Some(spawn_commands)
+                    let (center, mesh) = merge_octahedra(
+                        painted_octahedra
+                            .into_iter()
+                            .map(|(_, _, octahedron)| octahedron),
+                    )?;
+
+                    // Note: We don't need to adjust this relative to camera translation
+                    // since we already calculated it in world-space
+                    let transform = Transform::from_translation(center);
+
+                    let painted_mesh = PaintedMesh {
+                        painted_from: paintable_mesh_entity,
+                        triangle_indices,
+                        paint_layer: layer_index,
+                        paint_branch,
+                    };
+
+                    match (&canvas, transfer_material) {
+                        (Some((_, material)), _) => {
+                            let vertex_count = mesh.count_vertices();
+
+                            Some((
+                                Some(spawn_layer_mesh(
+                                    mesh.with_inserted_attribute(
+                                        Mesh::ATTRIBUTE_UV_1,
+                                        layer_uvs(layer_index, vertex_count),
+                                    ),
+                                    material.clone(),
+                                    transform,
+                                    painted_mesh,
+                                )),
+                                None,
+                            ))
+                        }
+                        (None, Some(material)) => Some((
+                            None,
+                            Some(spawn_layer_mesh(
+                                mesh.with_inserted_attribute(Mesh::ATTRIBUTE_COLOR, colors),
+                                material,
+                                transform,
+                                painted_mesh,
+                            )),
+                        )),
+                        (None, None) => None,
+                    }
                 },
             )
-            .flatten()
-            .collect::<Vec<_>>()
+            .unzip::<_, _, Vec<_>, Vec<_>>();
+
+        let (spawn_canvas_meshes, spawn_transfer_meshes) = spawn_painted_meshes;
+
+        let atlas = canvas.map(|(canvas_slot, _)| {
+            res_set(atlas.clone().with_slot(
+                canvas_slot,
+                CanvasSlot::Layer {
+                    layer: layer_index,
+                    branch: paint_branch,
+                },
+            ))
+        });
+
+        Some((spawn_canvas_meshes, spawn_transfer_meshes, atlas))
     }
 }
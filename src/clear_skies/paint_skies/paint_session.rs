@@ -0,0 +1,1158 @@
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::{fs, iter};
+
+use bevy::asset::RenderAssetUsages;
+use bevy::camera::visibility::RenderLayers;
+use bevy::image::{
+    CompressedImageFormats,
+    ImageSampler,
+    ImageType,
+    IntoDynamicImageError,
+    TextureError,
+};
+use bevy::mesh::morph::MeshMorphWeights;
+use bevy::mesh::{Indices, PrimitiveTopology, VertexAttributeValues};
+use bevy::platform::collections::{HashMap, HashSet};
+use bevy::prelude::*;
+use bevy::reflect::TypeRegistry;
+use bevy::reflect::serde::{TypedReflectDeserializer, TypedReflectSerializer};
+use bevy::render::gpu_readback::{Readback, ReadbackComplete};
+use bevy::render::render_resource::TextureDimension;
+use bevy_pipe_affect::prelude::*;
+use leafwing_input_manager::InputControlKind;
+use leafwing_input_manager::prelude::*;
+use serde::Deserialize;
+use serde::de::DeserializeSeed;
+use thiserror::Error;
+
+use crate::button_predicate::button_just_pressed_predicate;
+use crate::clear_skies::ClearSkiesState;
+use crate::clear_skies::camera::{PaintSkiesAction, PaintSkiesCamera};
+use crate::clear_skies::paint_skies::canvas_atlas::{
+    CANVAS_PIXEL_SIZE,
+    CanvasSlot,
+    PaintCanvasAtlas,
+    PaintCanvasAtlasSettings,
+};
+use crate::clear_skies::paint_skies::canvas_copy::CanvasCopyMode;
+use crate::clear_skies::paint_skies::compaction::{
+    BakedPaintLayers,
+    BakedPaintedMesh,
+    CompactedCameraPath,
+    SpawnBakedPaintLayers,
+    spawn_baked_paint_layers,
+};
+use crate::clear_skies::paint_skies::paint_layer_history::{
+    BranchIndex,
+    HistoryUnit,
+    PaintableHistory,
+    PaintableHistoryRecord,
+};
+use crate::clear_skies::paint_skies::paint_meshes::{
+    LayerIndex,
+    PaintLayerSettings,
+    Paintable,
+    PaintedMaterials,
+    PaintedMesh,
+};
+use crate::clear_skies::paint_skies::painted_sky_material::PaintedSkyMaterial;
+use crate::clear_skies::play_skies::{PlaySkiesResult, PlaySkiesResults};
+use crate::clear_skies::render_layers::PAINTED_LAYER;
+
+/// The version of the paint session format written by this build.
+///
+/// Increment this whenever [`PaintSession`] changes shape in a released build.
+pub const PAINT_SESSION_VERSION: u32 = 1;
+
+/// The name of the session file within a paint session directory.
+const SESSION_FILE: &str = "session.ron";
+
+/// The name of the canvas directory within a paint session directory.
+const CANVAS_DIR: &str = "canvases";
+
+/// Plugin for saving paint sessions to disk and loading them back.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Reflect)]
+pub struct PaintSessionPlugin;
+
+impl Plugin for PaintSessionPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PaintSessionPath>()
+            .init_resource::<PendingPaintSessionSave>()
+            .register_type::<PaintSessionPath>()
+            .register_type::<PaintSession>()
+            .add_systems(
+                Update,
+                (
+                    start_paint_session_save
+                        .pipe(affect)
+                        .run_if(button_just_pressed_predicate(PaintSkiesAction::SaveSession)),
+                    (
+                        serialize_paint_session.pipe(write_paint_session),
+                        finish_paint_session_save.pipe(affect),
+                    )
+                        .chain()
+                        .run_if(paint_session_save_ready),
+                    read_paint_session
+                        .pipe(load_paint_session)
+                        .pipe(affect)
+                        .run_if(button_just_pressed_predicate(PaintSkiesAction::LoadSession)),
+                )
+                    .run_if(in_state(ClearSkiesState::PaintSkies)),
+            );
+    }
+}
+
+/// Resource defining the directory paint sessions are saved to and loaded from.
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Deref, DerefMut, Reflect, Resource)]
+#[reflect(Resource)]
+pub struct PaintSessionPath(pub PathBuf);
+
+impl Default for PaintSessionPath {
+    fn default() -> Self {
+        PaintSessionPath(PathBuf::from("paint_session"))
+    }
+}
+
+/// Resource holding a save that is waiting for the canvas atlas pages to be read back from the GPU.
+///
+/// With [`CanvasCopyMode::Gpu`], canvases are only copied into the render world's page textures,
+/// so the main world page images are out of date until they're read back.
+#[derive(Debug, Default, Clone, Resource)]
+pub struct PendingPaintSessionSave(Option<HashMap<AssetId<Image>, Option<Image>>>);
+
+/// Errors that can occur while saving or loading a paint session.
+#[derive(Debug, Error)]
+pub enum PaintSessionError {
+    /// Reading or writing the session directory failed.
+    #[error("failed to access paint session files: {0}")]
+    Io(#[from] std::io::Error),
+    /// The session couldn't be written as RON.
+    #[error("failed to write paint session: {0}")]
+    Serialize(#[from] ron::Error),
+    /// The session file isn't valid RON.
+    #[error("failed to read paint session: {0}")]
+    Deserialize(#[from] ron::error::SpannedError),
+    /// The session file was written by an incompatible version of the format.
+    #[error("paint session version {0} is not supported, expected {PAINT_SESSION_VERSION}")]
+    UnsupportedVersion(u32),
+    /// The session file is valid RON, but doesn't describe a [`PaintSession`].
+    #[error("paint session file doesn't describe a paint session")]
+    InvalidSession,
+    /// More than one paintable entity has this path, so painted meshes can't be matched with them.
+    #[error("more than one paintable entity has the path {0}")]
+    DuplicatePaintablePath(String),
+    /// A canvas atlas page image isn't loaded.
+    #[error("canvas atlas page image is missing")]
+    MissingCanvas,
+    /// A canvas image doesn't have any CPU-side data to save.
+    #[error("failed to convert canvas: {0}")]
+    IntoDynamicImage(#[from] IntoDynamicImageError),
+    /// A canvas image couldn't be encoded as a PNG.
+    #[error("failed to encode canvas: {0}")]
+    EncodeCanvas(#[from] image::ImageError),
+    /// A canvas image couldn't be decoded.
+    #[error("failed to decode canvas: {0}")]
+    DecodeCanvas(#[from] TextureError),
+}
+
+/// The geometry of a painted mesh, as stored in a [`PaintSession`].
+#[derive(Debug, Default, Clone, PartialEq, Reflect)]
+pub struct PaintedGeometry {
+    /// Vertex positions, relative to the painted mesh's translation.
+    pub positions: Vec<Vec3>,
+    /// Vertex UVs into the canvas atlas page, or into the textures of the transferred material.
+    pub uvs: Vec<Vec2>,
+    /// The layer each vertex was painted on, which the [`PaintedSkyMaterial`] fades by.
+    ///
+    /// This is empty for meshes with a transferred material.
+    pub layers: Vec<f32>,
+    /// The color of each vertex, which is empty for meshes textured with a canvas.
+    pub colors: Vec<Vec4>,
+    /// Triangle list indices.
+    pub indices: Vec<u32>,
+}
+
+impl PaintedGeometry {
+    /// Reads the geometry of a painted mesh, if it has positions, UVs and indices.
+    pub fn from_mesh(mesh: &Mesh) -> Option<PaintedGeometry> {
+        let VertexAttributeValues::Float32x3(positions) =
+            mesh.attribute(Mesh::ATTRIBUTE_POSITION)?
+        else {
+            return None;
+        };
+
+        let VertexAttributeValues::Float32x2(uvs) = mesh.attribute(Mesh::ATTRIBUTE_UV_0)? else {
+            return None;
+        };
+
+        let layers = match mesh.attribute(Mesh::ATTRIBUTE_UV_1) {
+            Some(VertexAttributeValues::Float32x2(layers)) => {
+                layers.iter().map(|[layer, _]| *layer).collect()
+            }
+            _ => vec![],
+        };
+
+        let colors = match mesh.attribute(Mesh::ATTRIBUTE_COLOR) {
+            Some(VertexAttributeValues::Float32x4(colors)) => {
+                colors.iter().copied().map(Vec4::from).collect()
+            }
+            _ => vec![],
+        };
+
+        Some(PaintedGeometry {
+            positions: positions.iter().copied().map(Vec3::from).collect(),
+            uvs: uvs.iter().copied().map(Vec2::from).collect(),
+            layers,
+            colors,
+            indices: mesh.indices()?.iter().map(|index| index as u32).collect(),
+        })
+    }
+}
+
+impl From<PaintedGeometry> for Mesh {
+    fn from(
+        PaintedGeometry {
+            positions,
+            uvs,
+            layers,
+            colors,
+            indices,
+        }: PaintedGeometry,
+    ) -> Self {
+        let mesh = Mesh::new(
+            PrimitiveTopology::TriangleList,
+            RenderAssetUsages::default(),
+        )
+        .with_inserted_attribute(
+            Mesh::ATTRIBUTE_POSITION,
+            positions
+                .into_iter()
+                .map(Into::<[f32; 3]>::into)
+                .collect::<Vec<_>>(),
+        )
+        .with_inserted_attribute(
+            Mesh::ATTRIBUTE_UV_0,
+            uvs.into_iter()
+                .map(Into::<[f32; 2]>::into)
+                .collect::<Vec<_>>(),
+        )
+        .with_inserted_indices(Indices::U32(indices));
+
+        let mesh = if layers.is_empty() {
+            mesh
+        } else {
+            mesh.with_inserted_attribute(
+                Mesh::ATTRIBUTE_UV_1,
+                layers
+                    .into_iter()
+                    .map(|layer| [layer, 0.0])
+                    .collect::<Vec<_>>(),
+            )
+        };
+
+        let mesh = if colors.is_empty() {
+            mesh
+        } else {
+            mesh.with_inserted_attribute(
+                Mesh::ATTRIBUTE_COLOR,
+                colors
+                    .into_iter()
+                    .map(Into::<[f32; 4]>::into)
+                    .collect::<Vec<_>>(),
+            )
+        };
+
+        mesh.with_computed_normals()
+    }
+}
+
+/// The material of a painted or baked mesh, as stored in a [`PaintSession`].
+#[derive(Debug, Clone, PartialEq, Eq, Reflect)]
+pub enum PaintedMaterialRecord {
+    /// The [`PaintedSkyMaterial`] of the canvas atlas page with this index.
+    Page(usize),
+    /// The [`StandardMaterial`] of the paintable entity with this path, transferred with
+    /// [`PaintSource::MaterialTransfer`].
+    ///
+    /// [`PaintSource::MaterialTransfer`]: crate::clear_skies::paint_skies::material_transfer::PaintSource::MaterialTransfer
+    Transferred(String),
+}
+
+impl Default for PaintedMaterialRecord {
+    fn default() -> Self {
+        PaintedMaterialRecord::Page(0)
+    }
+}
+
+/// A [`PaintedMesh`], as stored in a [`PaintSession`].
+#[derive(Debug, Default, Clone, PartialEq, Reflect)]
+pub struct PaintedMeshRecord {
+    /// The path of the paintable entity this mesh was painted from.
+    pub painted_from: String,
+    /// The triangle of the original mesh that each octahedron of this mesh was painted from.
+    pub triangle_indices: Vec<usize>,
+    /// The layer that this mesh was painted on.
+    pub paint_layer: LayerIndex,
+    /// The branch of the history that this mesh was painted on.
+    pub paint_branch: BranchIndex,
+    /// The translation of the painted mesh.
+    pub translation: Vec3,
+    /// The geometry of the painted mesh.
+    pub geometry: PaintedGeometry,
+    /// The material this mesh is shaded with.
+    pub material: PaintedMaterialRecord,
+}
+
+/// A [`BakedPaintedMesh`], as stored in a [`PaintSession`].
+#[derive(Debug, Default, Clone, PartialEq, Eq, Reflect)]
+pub struct BakedPaintedMeshRecord {
+    /// The path of the paintable entity the baked mesh was painted from.
+    pub painted_from: String,
+    /// The triangle of the original mesh that each octahedron was painted from.
+    pub triangle_indices: Vec<usize>,
+    /// The layer that the baked mesh was painted on.
+    pub paint_layer: LayerIndex,
+    /// The first triangle of the [`BakedPaintLayersRecord`] geometry it was merged into.
+    pub first_triangle: usize,
+}
+
+/// A [`BakedPaintLayers`] mesh, as stored in a [`PaintSession`].
+#[derive(Debug, Default, Clone, PartialEq, Reflect)]
+pub struct BakedPaintLayersRecord {
+    /// The geometry of the baked mesh, in world space.
+    pub geometry: PaintedGeometry,
+    /// The material this mesh is shaded with.
+    pub material: PaintedMaterialRecord,
+    /// Every painted mesh that was merged into this one.
+    pub painted_meshes: Vec<BakedPaintedMeshRecord>,
+}
+
+/// A [`PaintCanvasAtlas`], as stored in a [`PaintSession`].
+#[derive(Debug, Default, Clone, PartialEq, Reflect)]
+pub struct PaintCanvasAtlasRecord {
+    /// The size of each canvas in the atlas.
+    pub canvas_size: UVec2,
+    /// The number of canvas columns and rows in each page.
+    pub page_grid: UVec2,
+    /// Whether each page exists, and so has an image saved alongside the session.
+    pub pages: Vec<bool>,
+    /// What every slot is holding.
+    pub slots: Vec<CanvasSlot>,
+}
+
+/// The [`ActionState`] of the [`PaintSkiesCamera`] at one layer, as stored in a [`PaintSession`].
+#[derive(Debug, Default, Clone, PartialEq, Reflect)]
+pub struct PaintActionRecord {
+    /// Every button action that was pressed.
+    pub pressed: Vec<PaintSkiesAction>,
+    /// The value of every axis action.
+    pub axes: Vec<(PaintSkiesAction, f32)>,
+    /// The value of every dual axis action.
+    pub axis_pairs: Vec<(PaintSkiesAction, Vec2)>,
+}
+
+impl From<&ActionState<PaintSkiesAction>> for PaintActionRecord {
+    fn from(action_state: &ActionState<PaintSkiesAction>) -> Self {
+        let actions_of_kind = |kind| {
+            action_state
+                .all_action_data()
+                .keys()
+                .filter(move |action| action.input_control_kind() == kind)
+        };
+
+        PaintActionRecord {
+            pressed: action_state.get_pressed(),
+            axes: actions_of_kind(InputControlKind::Axis)
+                .map(|action| (*action, action_state.value(action)))
+                .collect(),
+            axis_pairs: actions_of_kind(InputControlKind::DualAxis)
+                .map(|action| (*action, action_state.axis_pair(action)))
+                .collect(),
+        }
+    }
+}
+
+impl From<&PaintActionRecord> for ActionState<PaintSkiesAction> {
+    fn from(record: &PaintActionRecord) -> Self {
+        let mut action_state = ActionState::default();
+
+        record
+            .pressed
+            .iter()
+            .for_each(|action| action_state.press(action));
+
+        record
+            .axes
+            .iter()
+            .for_each(|(action, value)| action_state.set_value(action, *value));
+
+        record
+            .axis_pairs
+            .iter()
+            .for_each(|(action, pair)| action_state.set_axis_pair(action, *pair));
+
+        action_state
+    }
+}
+
+/// Returns the path of every given paintable entity, which painted meshes and histories are matched
+/// with when a session is loaded.
+///
+/// Paths are made of the [`Name`] of the entity and of each of its ancestors, or their index among
+/// their siblings for unnamed ones, so they stay the same as long as the scene does. Paintable
+/// entities that share a path can't be told apart, so they're an error.
+fn paintable_paths(
+    paintables: impl IntoIterator<Item = Entity>,
+    parents: &Query<&ChildOf>,
+    siblings: &Query<&Children>,
+    names: &Query<&Name>,
+) -> Result<HashMap<Entity, String>, PaintSessionError> {
+    let segment = |node: Entity| match names.get(node) {
+        Ok(name) => name.to_string(),
+        Err(_) => {
+            let index = parents.get(node).ok().and_then(|child_of| {
+                siblings
+                    .get(child_of.parent())
+                    .ok()?
+                    .iter()
+                    .position(|sibling| *sibling == node)
+            });
+
+            format!(
+                "#{}",
+                index.map(|index| index.to_string()).unwrap_or_default()
+            )
+        }
+    };
+
+    let mut seen = HashSet::new();
+
+    paintables
+        .into_iter()
+        .map(|paintable| {
+            let mut segments = iter::once(paintable)
+                .chain(parents.iter_ancestors(paintable))
+                .map(segment)
+                .collect::<Vec<_>>();
+            segments.reverse();
+
+            let path = segments.join("/");
+
+            if !seen.insert(path.clone()) {
+                return Err(PaintSessionError::DuplicatePaintablePath(path));
+            }
+
+            Ok((paintable, path))
+        })
+        .collect()
+}
+
+/// Everything needed to reopen a painted sky.
+///
+/// Canvas atlas page images are stored next to the session file rather than inside it.
+#[derive(Debug, Clone, PartialEq, Reflect)]
+pub struct PaintSession {
+    /// The version of the format this session was saved with.
+    pub version: u32,
+    /// The settings the sky was painted with.
+    pub settings: PaintLayerSettings,
+    /// The universal history, defining the layers and branches of the session.
+    pub history: PaintableHistoryRecord<HistoryUnit>,
+    /// The history of the [`PaintSkiesCamera`] transform.
+    pub camera_transform_history: PaintableHistoryRecord<GlobalTransform>,
+    /// The history of the [`PaintSkiesCamera`] actions.
+    pub camera_action_history: PaintableHistoryRecord<PaintActionRecord>,
+    /// The [`PaintSkiesCamera`] transforms of compacted layers.
+    pub compacted_camera_path: Vec<(LayerIndex, GlobalTransform)>,
+    /// The transform histories of paintable meshes and the joints posing them, by path.
+    pub paintable_transform_histories: Vec<(String, PaintableHistoryRecord<GlobalTransform>)>,
+    /// The morph target weight histories of paintable meshes, by path.
+    pub paintable_morph_weight_histories: Vec<(String, PaintableHistoryRecord<MeshMorphWeights>)>,
+    /// Every painted mesh, on every branch.
+    pub painted_meshes: Vec<PaintedMeshRecord>,
+    /// Every mesh that painted meshes of compacted layers were baked into.
+    pub baked_meshes: Vec<BakedPaintLayersRecord>,
+    /// The layout of the canvas atlas that painted meshes are textured with.
+    pub canvas_atlas: PaintCanvasAtlasRecord,
+    /// The result of every run of [`ClearSkiesState::PlaySkies`] through this sky.
+    pub play_results: Vec<PlaySkiesResult>,
+}
+
+/// Only the version of a session file, read before the rest to reject incompatible sessions.
+#[derive(Debug, Deserialize)]
+struct PaintSessionHeader {
+    version: u32,
+}
+
+/// The contents of a paint session directory, ready to be written to disk.
+pub struct PaintSessionFiles {
+    session: String,
+    /// The encoded image of every page, or `None` for pages that were freed.
+    canvases: Vec<Option<Vec<u8>>>,
+}
+
+fn serialize_session(
+    session: &PaintSession,
+    type_registry: &TypeRegistry,
+) -> Result<String, PaintSessionError> {
+    let serializer = TypedReflectSerializer::new(session, type_registry);
+
+    Ok(ron::ser::to_string_pretty(
+        &serializer,
+        ron::ser::PrettyConfig::default(),
+    )?)
+}
+
+fn deserialize_session(
+    session: &str,
+    type_registry: &TypeRegistry,
+) -> Result<PaintSession, PaintSessionError> {
+    let PaintSessionHeader { version } = ron::from_str(session)?;
+
+    if version != PAINT_SESSION_VERSION {
+        return Err(PaintSessionError::UnsupportedVersion(version));
+    }
+
+    let mut deserializer = ron::Deserializer::from_str(session)?;
+
+    let reflected = TypedReflectDeserializer::of::<PaintSession>(type_registry)
+        .deserialize(&mut deserializer)?;
+
+    PaintSession::from_reflect(&*reflected).ok_or(PaintSessionError::InvalidSession)
+}
+
+fn encode_canvas(canvas: &Image) -> Result<Vec<u8>, PaintSessionError> {
+    let mut bytes = vec![];
+
+    canvas
+        .clone()
+        .try_into_dynamic()?
+        .write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Png)?;
+
+    Ok(bytes)
+}
+
+fn decode_canvas(bytes: &[u8]) -> Result<Image, PaintSessionError> {
+    Ok(Image::from_buffer(
+        bytes,
+        ImageType::Extension("png"),
+        CompressedImageFormats::NONE,
+        true,
+        ImageSampler::Default,
+        RenderAssetUsages::all(),
+    )?)
+}
+
+/// Builds an image like the given page image, with the data read back from its texture.
+///
+/// Read back rows are padded to the GPU's copy alignment, which is stripped here.
+fn read_back_page(page: &Image, data: &[u8]) -> Image {
+    let size = page.texture_descriptor.size;
+    let row_size = size.width as usize * CANVAS_PIXEL_SIZE;
+    let padded_row_size = data.len() / size.height.max(1) as usize;
+
+    Image::new(
+        size,
+        TextureDimension::D2,
+        data.chunks_exact(padded_row_size.max(row_size))
+            .flat_map(|row| &row[..row_size])
+            .copied()
+            .collect(),
+        page.texture_descriptor.format,
+        RenderAssetUsages::MAIN_WORLD,
+    )
+}
+
+/// System that starts saving the paint session, reading back every canvas atlas page first with
+/// [`CanvasCopyMode::Gpu`].
+fn start_paint_session_save(
+    atlas: Res<PaintCanvasAtlas>,
+    atlas_settings: Res<PaintCanvasAtlasSettings>,
+) -> (
+    ResSet<PendingPaintSessionSave>,
+    Vec<CommandSpawnAnd<Readback, CommandSpawn<Observer>>>,
+) {
+    let pages = match atlas_settings.copy_mode {
+        CanvasCopyMode::Gpu => atlas.pages().iter().flatten().collect::<Vec<_>>(),
+        CanvasCopyMode::CpuReadback => vec![],
+    };
+
+    (
+        res_set(PendingPaintSessionSave(Some(
+            pages.iter().map(|page| (page.image.id(), None)).collect(),
+        ))),
+        pages
+            .into_iter()
+            .map(|page| {
+                command_spawn_and(Readback::texture(page.image.clone()), |readback_entity| {
+                    command_spawn(
+                        Observer::new(store_page_readback.pipe(affect))
+                            .with_entity(readback_entity),
+                    )
+                })
+            })
+            .collect(),
+    )
+}
+
+/// Observer that stores a read back canvas atlas page in the [`PendingPaintSessionSave`].
+fn store_page_readback(
+    readback: On<ReadbackComplete>,
+    readbacks: Query<&Readback>,
+    image_assets: Res<Assets<Image>>,
+    mut pending_save: ResMut<PendingPaintSessionSave>,
+) -> EntityCommandDespawn {
+    if let Ok(Readback::Texture(page)) = readbacks.get(readback.entity)
+        && let Some(page_image) = image_assets.get(page)
+        && let Some(pages) = pending_save.0.as_mut()
+    {
+        pages.insert(page.id(), Some(read_back_page(page_image, &readback.data)));
+    }
+
+    // Readbacks repeat every frame until they're removed.
+    entity_command_despawn(readback.entity)
+}
+
+/// Returns whether a save is pending and every page it needs has been read back.
+fn paint_session_save_ready(pending_save: Res<PendingPaintSessionSave>) -> bool {
+    pending_save
+        .0
+        .as_ref()
+        .is_some_and(|pages| pages.values().all(Option::is_some))
+}
+
+fn finish_paint_session_save() -> ResSet<PendingPaintSessionSave> {
+    res_set(default())
+}
+
+/// System that captures the current paint session as files.
+///
+/// Page images are taken from the [`PendingPaintSessionSave`] if they were read back.
+fn serialize_paint_session(
+    type_registry: Res<AppTypeRegistry>,
+    settings: Res<PaintLayerSettings>,
+    history: Single<&PaintableHistory<HistoryUnit>>,
+    paint_skies_camera: Single<
+        (
+            &PaintableHistory<GlobalTransform>,
+            &PaintableHistory<ActionState<PaintSkiesAction>>,
+            &CompactedCameraPath,
+        ),
+        With<PaintSkiesCamera>,
+    >,
+    paintable_meshes: Query<
+        (Entity, Option<&MeshMaterial3d<StandardMaterial>>),
+        (With<Paintable>, Without<PaintSkiesCamera>),
+    >,
+    transform_histories: Query<
+        (Entity, &PaintableHistory<GlobalTransform>),
+        Without<PaintSkiesCamera>,
+    >,
+    morph_weight_histories: Query<(Entity, &PaintableHistory<MeshMorphWeights>)>,
+    painted_meshes: Query<(&PaintedMesh, &Transform, &Mesh3d, PaintedMaterials)>,
+    baked_meshes: Query<(&BakedPaintLayers, &Mesh3d, PaintedMaterials)>,
+    parents: Query<&ChildOf>,
+    siblings: Query<&Children>,
+    names: Query<&Name>,
+    mesh_assets: Res<Assets<Mesh>>,
+    image_assets: Res<Assets<Image>>,
+    atlas: Res<PaintCanvasAtlas>,
+    play_results: Res<PlaySkiesResults>,
+    pending_save: Res<PendingPaintSessionSave>,
+) -> Result<PaintSessionFiles, PaintSessionError> {
+    let (camera_transform_history, camera_action_history, compacted_camera_path) =
+        *paint_skies_camera;
+
+    let paths = paintable_paths(
+        paintable_meshes
+            .iter()
+            .map(|(entity, _)| entity)
+            .chain(transform_histories.iter().map(|(entity, _)| entity))
+            .chain(morph_weight_histories.iter().map(|(entity, _)| entity))
+            .collect::<HashSet<_>>(),
+        &parents,
+        &siblings,
+        &names,
+    )?;
+
+    let page_indices = atlas
+        .pages()
+        .iter()
+        .enumerate()
+        .filter_map(|(index, page)| Some((page.as_ref()?.material.id(), index)))
+        .collect::<HashMap<_, _>>();
+
+    // Transferred materials are recorded by the path of a paintable entity that has them.
+    let transfer_sources = paintable_meshes
+        .iter()
+        .filter_map(|(entity, material)| Some((material?.id(), paths.get(&entity)?.clone())))
+        .collect::<HashMap<_, _>>();
+
+    let material_record = |(sky_material, transfer_material): (
+        Option<&MeshMaterial3d<PaintedSkyMaterial>>,
+        Option<&MeshMaterial3d<StandardMaterial>>,
+    )| match (sky_material, transfer_material) {
+        (Some(material), _) => page_indices
+            .get(&material.id())
+            .copied()
+            .map(PaintedMaterialRecord::Page),
+        (None, Some(material)) => transfer_sources
+            .get(&material.id())
+            .cloned()
+            .map(PaintedMaterialRecord::Transferred),
+        (None, None) => None,
+    };
+
+    let painted_meshes = painted_meshes
+        .iter()
+        .filter_map(|(painted_mesh, transform, mesh, materials)| {
+            let material = material_record(materials)?;
+
+            Some(PaintedMeshRecord {
+                painted_from: paths.get(&painted_mesh.painted_from)?.clone(),
+                triangle_indices: painted_mesh.triangle_indices.clone(),
+                paint_layer: painted_mesh.paint_layer,
+                paint_branch: painted_mesh.paint_branch,
+                translation: transform.translation,
+                geometry: PaintedGeometry::from_mesh(mesh_assets.get(mesh)?)?,
+                material,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let baked_meshes = baked_meshes
+        .iter()
+        .filter_map(|(baked_paint_layers, mesh, materials)| {
+            Some(BakedPaintLayersRecord {
+                material: material_record(materials)?,
+                geometry: PaintedGeometry::from_mesh(mesh_assets.get(mesh)?)?,
+                painted_meshes: baked_paint_layers
+                    .painted_meshes
+                    .iter()
+                    .filter_map(|baked_painted_mesh| {
+                        Some(BakedPaintedMeshRecord {
+                            painted_from: paths.get(&baked_painted_mesh.painted_from)?.clone(),
+                            triangle_indices: baked_painted_mesh.triangle_indices.clone(),
+                            paint_layer: baked_painted_mesh.paint_layer,
+                            first_triangle: baked_painted_mesh.first_triangle,
+                        })
+                    })
+                    .collect(),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let session = PaintSession {
+        version: PAINT_SESSION_VERSION,
+        settings: settings.clone(),
+        history: (*history).into(),
+        camera_transform_history: camera_transform_history.into(),
+        camera_action_history: (&camera_action_history.map(PaintActionRecord::from)).into(),
+        compacted_camera_path: compacted_camera_path.0.clone(),
+        paintable_transform_histories: transform_histories
+            .iter()
+            .filter_map(|(entity, history)| Some((paths.get(&entity)?.clone(), history.into())))
+            .collect(),
+        paintable_morph_weight_histories: morph_weight_histories
+            .iter()
+            .filter_map(|(entity, history)| Some((paths.get(&entity)?.clone(), history.into())))
+            .collect(),
+        painted_meshes,
+        baked_meshes,
+        canvas_atlas: PaintCanvasAtlasRecord {
+            canvas_size: atlas.canvas_size(),
+            page_grid: atlas.page_grid(),
+            pages: atlas.pages().iter().map(Option::is_some).collect(),
+            slots: atlas.slots().to_vec(),
+        },
+        play_results: play_results.to_vec(),
+    };
+
+    let canvases = atlas
+        .pages()
+        .iter()
+        .map(|page| {
+            page.as_ref()
+                .map(|page| {
+                    let read_back_page = pending_save
+                        .0
+                        .as_ref()
+                        .and_then(|pages| pages.get(&page.image.id())?.as_ref());
+
+                    encode_canvas(
+                        read_back_page
+                            .or_else(|| image_assets.get(&page.image))
+                            .ok_or(PaintSessionError::MissingCanvas)?,
+                    )
+                })
+                .transpose()
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(PaintSessionFiles {
+        session: serialize_session(&session, &type_registry.read())?,
+        canvases,
+    })
+}
+
+/// System that writes the paint session files to the [`PaintSessionPath`].
+fn write_paint_session(
+    In(files): In<Result<PaintSessionFiles, PaintSessionError>>,
+    path: Res<PaintSessionPath>,
+) {
+    let write = || -> Result<(), PaintSessionError> {
+        let PaintSessionFiles { session, canvases } = files?;
+
+        let canvas_dir = path.join(CANVAS_DIR);
+
+        fs::create_dir_all(&canvas_dir)?;
+
+        fs::write(path.join(SESSION_FILE), session)?;
+
+        canvases
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, canvas)| Some((index, canvas?)))
+            .try_for_each(|(index, canvas)| {
+                fs::write(canvas_dir.join(format!("{index}.png")), canvas)
+            })?;
+
+        Ok(())
+    };
+
+    match write() {
+        Ok(()) => info!("saved paint session to {}", path.display()),
+        Err(e) => error!("failed to save paint session to {}: {e}", path.display()),
+    }
+}
+
+/// System that reads a paint session and its canvas atlas pages from the [`PaintSessionPath`].
+fn read_paint_session(
+    type_registry: Res<AppTypeRegistry>,
+    path: Res<PaintSessionPath>,
+) -> Result<(PaintSession, Vec<Option<Image>>), PaintSessionError> {
+    let session = deserialize_session(
+        &fs::read_to_string(path.join(SESSION_FILE))?,
+        &type_registry.read(),
+    )?;
+
+    let canvas_dir = path.join(CANVAS_DIR);
+
+    let canvases = session
+        .canvas_atlas
+        .pages
+        .iter()
+        .enumerate()
+        .map(|(index, exists)| {
+            exists
+                .then(|| decode_canvas(&fs::read(canvas_dir.join(format!("{index}.png")))?))
+                .transpose()
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok((session, canvases))
+}
+
+type SpawnPaintedMesh<M> = AssetAddAnd<
+    Mesh,
+    CommandSpawn<(
+        Mesh3d,
+        MeshMaterial3d<M>,
+        Transform,
+        Visibility,
+        RenderLayers,
+        PaintedMesh,
+    )>,
+>;
+
+/// Returns the [`SpawnPaintedMesh`] effect rebuilding a painted mesh from its record.
+fn spawn_painted_mesh<M: Material>(
+    geometry: PaintedGeometry,
+    material: Handle<M>,
+    transform: Transform,
+    visibility: Visibility,
+    painted_mesh: PaintedMesh,
+) -> SpawnPaintedMesh<M> {
+    asset_add_and(Mesh::from(geometry), move |mesh_handle| {
+        command_spawn((
+            Mesh3d(mesh_handle),
+            MeshMaterial3d(material),
+            transform,
+            visibility,
+            PAINTED_LAYER,
+            painted_mesh,
+        ))
+    })
+}
+
+type LoadPaintSession = (
+    ResSet<PaintLayerSettings>,
+    EntityCommandInsert<PaintableHistory<HistoryUnit>>,
+    EntityCommandInsert<(
+        PaintableHistory<GlobalTransform>,
+        PaintableHistory<ActionState<PaintSkiesAction>>,
+        CompactedCameraPath,
+    )>,
+    Vec<EntityCommandInsert<PaintableHistory<GlobalTransform>>>,
+    Vec<EntityCommandInsert<PaintableHistory<MeshMorphWeights>>>,
+    Vec<EntityCommandDespawn>,
+    Vec<Option<SpawnPaintedMesh<PaintedSkyMaterial>>>,
+    Vec<Option<SpawnPaintedMesh<StandardMaterial>>>,
+    Vec<Option<SpawnBakedPaintLayers>>,
+    Vec<Option<SpawnBakedPaintLayers<StandardMaterial>>>,
+    ResSet<PaintCanvasAtlas>,
+    ResSet<PlaySkiesResults>,
+);
+
+/// System that replaces the current paint session with the one that was read.
+///
+/// Painted meshes are rebuilt from the session, and attached to the paintable entities with the
+/// same path as the ones they were originally painted from. Transferred materials are taken from
+/// the paintable entities with the recorded paths too.
+///
+/// Transform and morph target weight histories are restored by path as well, and tracked entities
+/// missing from the session get empty histories with its branches, so that branch indices stay in
+/// sync.
+///
+/// The canvas atlas pages are added as assets directly, since the rebuilt [`PaintCanvasAtlas`] needs
+/// all of their handles at once.
+fn load_paint_session(
+    In(session): In<Result<(PaintSession, Vec<Option<Image>>), PaintSessionError>>,
+    path: Res<PaintSessionPath>,
+    history_entity: Single<Entity, With<PaintableHistory<HistoryUnit>>>,
+    paint_skies_camera: Single<Entity, With<PaintSkiesCamera>>,
+    paintable_meshes: Query<
+        (Entity, Option<&MeshMaterial3d<StandardMaterial>>),
+        (With<Paintable>, Without<PaintSkiesCamera>),
+    >,
+    transform_histories: Query<
+        Entity,
+        (
+            With<PaintableHistory<GlobalTransform>>,
+            Without<PaintSkiesCamera>,
+        ),
+    >,
+    morph_weight_histories: Query<Entity, With<PaintableHistory<MeshMorphWeights>>>,
+    parents: Query<&ChildOf>,
+    siblings: Query<&Children>,
+    names: Query<&Name>,
+    existing_painted_meshes: Query<Entity, Or<(With<PaintedMesh>, With<BakedPaintLayers>)>>,
+    atlas_settings: Res<PaintCanvasAtlasSettings>,
+    mut images: ResMut<Assets<Image>>,
+    mut materials: ResMut<Assets<PaintedSkyMaterial>>,
+) -> Option<LoadPaintSession> {
+    let ((session, canvases), paths) = session
+        .and_then(|session| {
+            let paths = paintable_paths(
+                paintable_meshes
+                    .iter()
+                    .map(|(entity, _)| entity)
+                    .chain(&transform_histories)
+                    .chain(&morph_weight_histories)
+                    .collect::<HashSet<_>>(),
+                &parents,
+                &siblings,
+                &names,
+            )?;
+
+            Ok((session, paths))
+        })
+        .inspect_err(|e| error!("failed to load paint session from {}: {e}", path.display()))
+        .ok()?;
+
+    info!("loaded paint session from {}", path.display());
+
+    let paintable_entities = paths
+        .iter()
+        .map(|(entity, path)| (path.clone(), *entity))
+        .collect::<HashMap<_, _>>();
+
+    let transfer_materials = paintable_meshes
+        .iter()
+        .filter_map(|(entity, material)| Some((paths.get(&entity)?.clone(), (**material?).clone())))
+        .collect::<HashMap<_, _>>();
+
+    let history = PaintableHistory::from(session.history);
+
+    let mut saved_transform_histories = session
+        .paintable_transform_histories
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+
+    let mut saved_morph_weight_histories = session
+        .paintable_morph_weight_histories
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+
+    // Tracked entities without a saved history still need the loaded branches.
+    let transform_histories = transform_histories
+        .iter()
+        .map(|entity| {
+            let saved = paths
+                .get(&entity)
+                .and_then(|path| saved_transform_histories.remove(path));
+
+            entity_command_insert(
+                entity,
+                saved.map_or_else(|| history.without_layers(), PaintableHistory::from),
+            )
+        })
+        .collect();
+
+    let morph_weight_histories = morph_weight_histories
+        .iter()
+        .map(|entity| {
+            let saved = paths
+                .get(&entity)
+                .and_then(|path| saved_morph_weight_histories.remove(path));
+
+            entity_command_insert(
+                entity,
+                saved.map_or_else(|| history.without_layers(), PaintableHistory::from),
+            )
+        })
+        .collect();
+
+    let camera_action_history =
+        PaintableHistory::from(session.camera_action_history).map(ActionState::from);
+
+    let atlas = PaintCanvasAtlas::from_saved_pages(
+        session.canvas_atlas.canvas_size,
+        &PaintCanvasAtlasSettings {
+            page_grid: session.canvas_atlas.page_grid,
+            ..*atlas_settings
+        },
+        session.settings.layer_budget,
+        canvases,
+        session.canvas_atlas.slots,
+        &mut images,
+        &mut materials,
+    );
+
+    let page_material = |page: usize| Some(atlas.pages().get(page)?.as_ref()?.material.clone());
+
+    let transfer_material = |source: &String| {
+        let material = transfer_materials.get(source).cloned();
+
+        if material.is_none() {
+            warn!("transferred material source {source} is missing from the scene");
+        }
+
+        material
+    };
+
+    let (spawn_painted_meshes, spawn_transfer_meshes) = session
+        .painted_meshes
+        .into_iter()
+        .filter_map(|record| {
+            let Some(painted_from) = paintable_entities.get(&record.painted_from) else {
+                warn!(
+                    "painted mesh source {} is missing from the scene",
+                    record.painted_from
+                );
+                return None;
+            };
+
+            let painted_mesh = PaintedMesh {
+                painted_from: *painted_from,
+                triangle_indices: record.triangle_indices,
+                paint_layer: record.paint_layer,
+                paint_branch: record.paint_branch,
+            };
+
+            let transform = Transform::from_translation(record.translation);
+            let visibility = painted_mesh.visibility_in(&camera_action_history);
+
+            match record.material {
+                PaintedMaterialRecord::Page(page) => Some((
+                    Some(spawn_painted_mesh(
+                        record.geometry,
+                        page_material(page)?,
+                        transform,
+                        visibility,
+                        painted_mesh,
+                    )),
+                    None,
+                )),
+                PaintedMaterialRecord::Transferred(source) => Some((
+                    None,
+                    Some(spawn_painted_mesh(
+                        record.geometry,
+                        transfer_material(&source)?,
+                        transform,
+                        visibility,
+                        painted_mesh,
+                    )),
+                )),
+            }
+        })
+        .unzip::<_, _, Vec<_>, Vec<_>>();
+
+    let (spawn_baked_meshes, spawn_baked_transfer_meshes) = session
+        .baked_meshes
+        .into_iter()
+        .filter_map(|record| {
+            let mesh = Mesh::from(record.geometry);
+
+            let baked_paint_layers = BakedPaintLayers {
+                painted_meshes: record
+                    .painted_meshes
+                    .into_iter()
+                    .filter_map(|painted_mesh| {
+                        Some(BakedPaintedMesh {
+                            painted_from: *paintable_entities.get(&painted_mesh.painted_from)?,
+                            triangle_indices: painted_mesh.triangle_indices,
+                            paint_layer: painted_mesh.paint_layer,
+                            first_triangle: painted_mesh.first_triangle,
+                        })
+                    })
+                    .collect(),
+            };
+
+            match record.material {
+                PaintedMaterialRecord::Page(page) => Some((
+                    Some(spawn_baked_paint_layers(
+                        mesh,
+                        page_material(page)?,
+                        baked_paint_layers,
+                    )),
+                    None,
+                )),
+                PaintedMaterialRecord::Transferred(source) => Some((
+                    None,
+                    Some(spawn_baked_paint_layers(
+                        mesh,
+                        transfer_material(&source)?,
+                        baked_paint_layers,
+                    )),
+                )),
+            }
+        })
+        .unzip::<_, _, Vec<_>, Vec<_>>();
+
+    Some((
+        res_set(session.settings),
+        entity_command_insert(*history_entity, history),
+        entity_command_insert(
+            *paint_skies_camera,
+            (
+                PaintableHistory::from(session.camera_transform_history),
+                camera_action_history,
+                CompactedCameraPath(session.compacted_camera_path),
+            ),
+        ),
+        transform_histories,
+        morph_weight_histories,
+        existing_painted_meshes
+            .iter()
+            .map(entity_command_despawn)
+            .collect(),
+        spawn_painted_meshes,
+        spawn_transfer_meshes,
+        spawn_baked_meshes,
+        spawn_baked_transfer_meshes,
+        res_set(atlas),
+        res_set(PlaySkiesResults(session.play_results)),
+    ))
+}
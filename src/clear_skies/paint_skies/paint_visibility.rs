@@ -0,0 +1,125 @@
+use bevy::math::bounding::RayCast3d;
+use bevy::prelude::*;
+
+use crate::clear_skies::triangle_bvh::TriangleBvh;
+
+/// How far sample points are pulled towards the centroid of their triangle, so that rays to them
+/// don't graze the edges of neighbouring triangles.
+const SAMPLE_INSET: f32 = 0.01;
+
+/// Rays hitting an occluder this close to either end are ignored, so that triangles don't occlude
+/// themselves.
+const RAY_EPSILON: f32 = 1e-4;
+
+/// Every paintable triangle in world space, used to find which triangles the paint camera can see.
+///
+/// Hidden triangles would be painted with whatever was in front of them, so they're culled before
+/// they're projected.
+///
+/// The triangles are kept in a [`TriangleBvh`], so that each ray is only tested against the
+/// triangles near it.
+pub struct PaintOccluders {
+    bvh: Option<TriangleBvh<Triangle3d>>,
+}
+
+impl PaintOccluders {
+    /// Constructs [`PaintOccluders`] from triangles in world space.
+    pub fn new(triangles: impl IntoIterator<Item = Triangle3d>) -> Self {
+        PaintOccluders {
+            bvh: TriangleBvh::new(triangles.into_iter().collect()),
+        }
+    }
+
+    /// Returns whether the given world space triangle faces the eye, and isn't completely hidden
+    /// behind any occluder.
+    ///
+    /// A triangle counts as visible if its centroid or any of its corners are, so partly hidden
+    /// triangles are kept whole.
+    pub fn is_visible(&self, eye: Vec3, triangle: Triangle3d) -> bool {
+        let centroid = triangle.centroid();
+
+        let faces_eye = triangle
+            .normal()
+            .is_ok_and(|normal| normal.dot(eye - centroid) > 0.0);
+
+        faces_eye
+            && triangle
+                .vertices
+                .iter()
+                .map(|vertex| vertex.lerp(centroid, SAMPLE_INSET))
+                .chain([centroid])
+                .any(|sample| !self.is_occluded(eye, sample))
+    }
+
+    /// Returns whether any occluder is between the eye and the point.
+    fn is_occluded(&self, eye: Vec3, point: Vec3) -> bool {
+        let segment = point - eye;
+
+        let (Some(bvh), Ok(direction)) = (&self.bvh, Dir3::new(segment)) else {
+            return false;
+        };
+
+        let ray_cast = RayCast3d::new(eye, direction, segment.length());
+
+        bvh.candidates(|aabb| ray_cast.aabb_intersection_at(aabb).is_some())
+            .into_iter()
+            .any(|occluder| {
+                segment_triangle_intersection(eye, segment, occluder)
+                    .is_some_and(|t| t > RAY_EPSILON && t < 1.0 - RAY_EPSILON)
+            })
+    }
+}
+
+/// Returns the given triangle of a mesh in world space.
+///
+/// Transforms with negative scale mirror the triangle, which would flip its winding and so its
+/// normal, so two of its vertices are swapped back to keep it facing the same way as in the mesh.
+pub fn world_triangle(mesh_transform: &GlobalTransform, triangle: Triangle3d) -> Triangle3d {
+    let [a, b, c] = triangle
+        .vertices
+        .map(|vertex| mesh_transform.transform_point(vertex));
+
+    let mirrored = mesh_transform.affine().matrix3.determinant() < 0.0;
+
+    Triangle3d {
+        vertices: if mirrored { [a, c, b] } else { [a, b, c] },
+    }
+}
+
+/// Returns how far along the segment from `origin` to `origin + segment` it crosses the triangle,
+/// from either side, using the Möller-Trumbore algorithm.
+fn segment_triangle_intersection(
+    origin: Vec3,
+    segment: Vec3,
+    triangle: &Triangle3d,
+) -> Option<f32> {
+    let [a, b, c] = triangle.vertices;
+
+    let edge_ab = b - a;
+    let edge_ac = c - a;
+
+    let p = segment.cross(edge_ac);
+    let determinant = edge_ab.dot(p);
+
+    if determinant.abs() < f32::EPSILON {
+        return None;
+    }
+
+    let inverse_determinant = determinant.recip();
+
+    let to_origin = origin - a;
+    let u = to_origin.dot(p) * inverse_determinant;
+
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+
+    let q = to_origin.cross(edge_ab);
+    let v = segment.dot(q) * inverse_determinant;
+
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+
+    Some(edge_ac.dot(q) * inverse_determinant)
+}
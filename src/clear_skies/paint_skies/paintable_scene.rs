@@ -0,0 +1,99 @@
+use std::iter;
+
+use bevy::platform::collections::HashSet;
+use bevy::prelude::*;
+use bevy_pipe_affect::prelude::*;
+
+use crate::clear_skies::paint_skies::paint_meshes::Paintable;
+
+/// Plugin that marks the meshes of [`PaintableScene`]s as [`Paintable`].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Reflect)]
+pub struct PaintableScenePlugin;
+
+impl Plugin for PaintableScenePlugin {
+    fn build(&self, app: &mut App) {
+        app.register_type::<PaintableScene>()
+            .register_type::<NotPaintable>()
+            .register_type::<PaintableFromScene>()
+            .add_systems(Update, propagate_paintable_scenes.pipe(affect));
+    }
+}
+
+/// Component that makes every mesh below this entity in the hierarchy [`Paintable`], like a scene
+/// root or a parent empty authored in Blender.
+///
+/// Meshes are marked as they're spawned or reparented, so they stay paintable when the scene is hot
+/// reloaded. Meshes that are already below this entity when it's added are marked too.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Reflect, Component)]
+#[reflect(Component)]
+pub struct PaintableScene;
+
+/// Component that keeps this entity and everything below it from being made [`Paintable`] by a
+/// [`PaintableScene`] further up the hierarchy.
+///
+/// A [`PaintableScene`] below this entity makes its own meshes paintable again. Meshes that a
+/// [`PaintableScene`] already made [`Paintable`] stop being paintable once this is added above
+/// them.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Reflect, Component)]
+#[reflect(Component)]
+pub struct NotPaintable;
+
+/// Marker component for meshes that a [`PaintableScene`] made [`Paintable`], which lose both again
+/// once they're excluded by a [`NotPaintable`] or moved out of the scene.
+///
+/// Meshes that were made [`Paintable`] directly don't have this, so they're never unmarked.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Reflect, Component)]
+#[reflect(Component)]
+pub struct PaintableFromScene;
+
+/// Marks every mesh whose nearest [`PaintableScene`] or [`NotPaintable`] ancestor is a
+/// [`PaintableScene`] as [`Paintable`], and unmarks the [`PaintableFromScene`] meshes whose nearest
+/// one isn't.
+///
+/// Only meshes that were just spawned or reparented, or that are below a node that just became a
+/// [`PaintableScene`] or [`NotPaintable`], are checked, so the ancestors of every mesh aren't
+/// walked every frame.
+fn propagate_paintable_scenes(
+    moved_meshes: Query<
+        Entity,
+        (
+            With<Mesh3d>,
+            With<ChildOf>,
+            Or<(Added<Mesh3d>, Changed<ChildOf>)>,
+        ),
+    >,
+    marked_nodes: Query<Entity, Or<(Added<PaintableScene>, Added<NotPaintable>)>>,
+    meshes: Query<(Has<Paintable>, Has<PaintableFromScene>), With<Mesh3d>>,
+    parents: Query<&ChildOf>,
+    children: Query<&Children>,
+    nodes: Query<(Has<PaintableScene>, Has<NotPaintable>)>,
+) -> Vec<(
+    Option<EntityCommandInsert<(Paintable, PaintableFromScene)>>,
+    Option<EntityCommandRemove<(Paintable, PaintableFromScene)>>,
+)> {
+    moved_meshes
+        .iter()
+        .chain(
+            marked_nodes
+                .iter()
+                .flat_map(|node| iter::once(node).chain(children.iter_descendants(node))),
+        )
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .filter_map(|mesh| {
+            let (paintable, from_scene) = meshes.get(mesh).ok()?;
+
+            let in_paintable_scene = iter::once(mesh)
+                .chain(parents.iter_ancestors(mesh))
+                .filter_map(|node| nodes.get(node).ok())
+                .find(|(paintable, not_paintable)| *paintable || *not_paintable)
+                .is_some_and(|(paintable, _)| paintable);
+
+            Some((
+                (in_paintable_scene && !paintable)
+                    .then(|| entity_command_insert(mesh, (Paintable, PaintableFromScene))),
+                (!in_paintable_scene && from_scene).then(|| entity_command_remove(mesh)),
+            ))
+        })
+        .collect()
+}
@@ -0,0 +1,160 @@
+use bevy::prelude::*;
+use bevy::render::render_resource::{AsBindGroup, ShaderType};
+use bevy::shader::ShaderRef;
+
+use crate::clear_skies::ClearSkiesState;
+use crate::clear_skies::paint_skies::canvas_atlas::PaintCanvasAtlas;
+use crate::clear_skies::paint_skies::paint_layer_history::last_layer_index;
+use crate::clear_skies::paint_skies::paint_meshes::{LayerIndex, PaintLayerSettings};
+
+/// The path of the [`PaintedSkyMaterial`] fragment shader.
+const PAINTED_SKY_SHADER_PATH: &str = "shaders/painted_sky.wgsl";
+
+/// Plugin for the [`PaintedSkyMaterial`] used by painted meshes.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Reflect)]
+pub struct PaintedSkyMaterialPlugin;
+
+impl Plugin for PaintedSkyMaterialPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(MaterialPlugin::<PaintedSkyMaterial>::default())
+            .register_type::<PaintedSkySettings>()
+            .add_systems(
+                Update,
+                last_layer_index.pipe(update_painted_sky_materials).run_if(
+                    in_state(ClearSkiesState::PaintSkies).and(resource_exists::<PaintCanvasAtlas>),
+                ),
+            );
+    }
+}
+
+/// Settings for how painted meshes are shaded by their [`PaintedSkyMaterial`].
+#[derive(Debug, Copy, Clone, PartialEq, Reflect)]
+pub struct PaintedSkySettings {
+    /// The tint of the newest layer.
+    pub newest_tint: Color,
+    /// The tint of layers at least [`Self::fade_layers`] old, with layers in between blending
+    /// from [`Self::newest_tint`].
+    pub oldest_tint: Color,
+    /// The number of layers it takes for a layer to fade to [`Self::oldest_opacity`].
+    pub fade_layers: u32,
+    /// The opacity of layers at least [`Self::fade_layers`] old.
+    pub oldest_opacity: f32,
+    /// The color painted meshes fade to with distance, with its alpha as the strength of the fog.
+    pub fog_color: Color,
+    /// The distance from the camera where fog starts.
+    pub fog_start: f32,
+    /// The distance from the camera where fog is at its strongest.
+    pub fog_end: f32,
+    /// Canvas pixels with at most this alpha are cut out, like the unpainted background of
+    /// screenshots, which is transparent.
+    pub alpha_cutoff: f32,
+}
+
+impl Default for PaintedSkySettings {
+    fn default() -> Self {
+        PaintedSkySettings {
+            newest_tint: Color::WHITE,
+            oldest_tint: Color::WHITE,
+            fade_layers: 500,
+            oldest_opacity: 1.0,
+            fog_color: Color::NONE,
+            fog_start: 500.0,
+            fog_end: 1000.0,
+            alpha_cutoff: 0.01,
+        }
+    }
+}
+
+/// The uniform parameters of a [`PaintedSkyMaterial`].
+#[derive(Debug, Default, Copy, Clone, PartialEq, ShaderType)]
+pub struct PaintedSkyMaterialParams {
+    newest_tint: LinearRgba,
+    oldest_tint: LinearRgba,
+    fog_color: LinearRgba,
+    alpha_cutoff: f32,
+    fog_start: f32,
+    fog_end: f32,
+    newest_layer: f32,
+    fade_layers: f32,
+    oldest_opacity: f32,
+}
+
+impl PaintedSkyMaterialParams {
+    /// Returns the parameters for the given settings, while the given layer is the newest.
+    fn new(settings: &PaintedSkySettings, newest_layer: LayerIndex) -> Self {
+        PaintedSkyMaterialParams {
+            newest_tint: settings.newest_tint.into(),
+            oldest_tint: settings.oldest_tint.into(),
+            fog_color: settings.fog_color.into(),
+            alpha_cutoff: settings.alpha_cutoff,
+            fog_start: settings.fog_start,
+            fog_end: settings.fog_end,
+            newest_layer: *newest_layer as f32,
+            fade_layers: settings.fade_layers as f32,
+            oldest_opacity: settings.oldest_opacity,
+        }
+    }
+}
+
+/// The material of painted meshes, sampling a [`PaintCanvasAtlas`] page.
+///
+/// Painted meshes store the layer they were painted on in [`Mesh::ATTRIBUTE_UV_1`], so one
+/// material can tint and fade every layer on its page.
+#[derive(Debug, Clone, Asset, TypePath, AsBindGroup)]
+pub struct PaintedSkyMaterial {
+    /// The canvas atlas page.
+    #[texture(0)]
+    #[sampler(1)]
+    pub canvas: Handle<Image>,
+    #[uniform(2)]
+    params: PaintedSkyMaterialParams,
+}
+
+impl From<Handle<Image>> for PaintedSkyMaterial {
+    fn from(canvas: Handle<Image>) -> Self {
+        PaintedSkyMaterial {
+            canvas,
+            params: PaintedSkyMaterialParams::new(&default(), default()),
+        }
+    }
+}
+
+impl Material for PaintedSkyMaterial {
+    fn fragment_shader() -> ShaderRef {
+        PAINTED_SKY_SHADER_PATH.into()
+    }
+
+    fn alpha_mode(&self) -> AlphaMode {
+        AlphaMode::Blend
+    }
+}
+
+/// Returns the [`Mesh::ATTRIBUTE_UV_1`] values marking every vertex of a mesh as painted on the
+/// given layer.
+pub fn layer_uvs(layer: LayerIndex, vertex_count: usize) -> Vec<[f32; 2]> {
+    vec![[*layer as f32, 0.0]; vertex_count]
+}
+
+/// System that keeps the parameters of every [`PaintCanvasAtlas`] page material up to date with
+/// the [`PaintLayerSettings`] and the newest layer.
+///
+/// Materials are only touched when their parameters actually change, so that their bind groups
+/// aren't rebuilt every frame.
+fn update_painted_sky_materials(
+    In(newest_layer): In<LayerIndex>,
+    settings: Res<PaintLayerSettings>,
+    atlas: Res<PaintCanvasAtlas>,
+    mut materials: ResMut<Assets<PaintedSkyMaterial>>,
+) {
+    let params = PaintedSkyMaterialParams::new(&settings.sky_material, newest_layer);
+
+    for page in atlas.pages().iter().flatten() {
+        let outdated = materials
+            .get(&page.material)
+            .is_some_and(|material| material.params != params);
+
+        if outdated && let Some(material) = materials.get_mut(&page.material) {
+            material.params = params;
+        }
+    }
+}
@@ -3,8 +3,18 @@ use bevy_pipe_affect::prelude::*;
 
 use crate::clear_skies::ClearSkiesState;
 use crate::clear_skies::camera::PaintSkiesAction;
+use crate::clear_skies::paint_skies::canvas_atlas::PaintCanvasAtlasPlugin;
+use crate::clear_skies::paint_skies::canvas_bake::CanvasBakePlugin;
+use crate::clear_skies::paint_skies::canvas_copy::CanvasCopyPlugin;
+use crate::clear_skies::paint_skies::compaction::PaintLayerCompactionPlugin;
 use crate::clear_skies::paint_skies::control_spherical_coords::control_spherical_coords;
+use crate::clear_skies::paint_skies::layer_distance::LayerDistancePlugin;
+use crate::clear_skies::paint_skies::paint_brush::PaintBrushPlugin;
 use crate::clear_skies::paint_skies::paint_meshes::PaintMeshesPlugin;
+use crate::clear_skies::paint_skies::paint_session::PaintSessionPlugin;
+use crate::clear_skies::paint_skies::paintable_scene::PaintableScenePlugin;
+use crate::clear_skies::paint_skies::painted_sky_material::PaintedSkyMaterialPlugin;
+use crate::clear_skies::paint_skies::replay::{PaintReplayPlugin, paint_replay_inactive};
 use crate::clear_skies::paint_skies::settings::PaintSkiesSettings;
 use crate::clear_skies::paint_skies::spherical_coords::look_at_spherical_coords;
 use crate::clear_skies::switch_gamepads::SwitchGamepadsPlugin;
@@ -18,14 +28,27 @@ impl Plugin for PaintSkiesPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugins((
             SwitchGamepadsPlugin::<PaintSkiesAction>::default(),
+            LayerDistancePlugin,
             PaintMeshesPlugin,
+            PaintableScenePlugin,
+            PaintBrushPlugin,
+            PaintCanvasAtlasPlugin,
+            CanvasCopyPlugin,
+            CanvasBakePlugin,
+            PaintedSkyMaterialPlugin,
+            PaintSessionPlugin,
+            PaintReplayPlugin,
+            PaintLayerCompactionPlugin,
         ))
         .init_resource::<PaintSkiesSettings>()
         .add_systems(
             FixedUpdate,
             (
-                control_spherical_coords.pipe(affect),
-                look_at_spherical_coords.pipe(affect),
+                (
+                    control_spherical_coords.pipe(affect),
+                    look_at_spherical_coords.pipe(affect),
+                )
+                    .run_if(paint_replay_inactive),
                 lock_cursor.pipe(affect),
             )
                 .run_if(in_state(ClearSkiesState::PaintSkies)),
@@ -0,0 +1,164 @@
+use std::borrow::Cow;
+
+use bevy::mesh::VertexAttributeValues;
+use bevy::mesh::morph::{MeshMorphWeights, MorphAttributes};
+use bevy::mesh::skinning::{SkinnedMesh, SkinnedMeshInverseBindposes};
+use bevy::prelude::*;
+
+/// Query data for how an animated paintable mesh is currently posed, by its morph target weights
+/// and skin.
+pub type MeshPose = (
+    Option<&'static MeshMorphWeights>,
+    Option<&'static SkinnedMesh>,
+);
+
+/// Returns the given mesh as it currently looks, with its morph targets and skinning evaluated on
+/// the CPU.
+///
+/// Skinned vertices are moved back relative to the mesh transform, so that posed meshes can be
+/// painted like any other. Meshes that aren't animated are returned as they are.
+pub fn posed_mesh<'a>(
+    mesh: &'a Mesh,
+    (morph_weights, skin): (Option<&MeshMorphWeights>, Option<&SkinnedMesh>),
+    mesh_transform: &GlobalTransform,
+    joints: &Query<&GlobalTransform>,
+    inverse_bindposes: &Assets<SkinnedMeshInverseBindposes>,
+    images: &Assets<Image>,
+) -> Cow<'a, Mesh> {
+    if morph_weights.is_none() && skin.is_none() {
+        return Cow::Borrowed(mesh);
+    }
+
+    let Some(VertexAttributeValues::Float32x3(positions)) =
+        mesh.attribute(Mesh::ATTRIBUTE_POSITION)
+    else {
+        return Cow::Borrowed(mesh);
+    };
+
+    let mut positions = positions
+        .iter()
+        .copied()
+        .map(Vec3::from)
+        .collect::<Vec<_>>();
+
+    if let Some(morph_weights) = morph_weights {
+        morph_positions(mesh, &mut positions, morph_weights, images);
+    }
+
+    if let Some(skin) = skin {
+        skin_positions(
+            mesh,
+            &mut positions,
+            skin,
+            mesh_transform,
+            joints,
+            inverse_bindposes,
+        );
+    }
+
+    Cow::Owned(
+        mesh.clone().with_inserted_attribute(
+            Mesh::ATTRIBUTE_POSITION,
+            positions
+                .into_iter()
+                .map(Into::<[f32; 3]>::into)
+                .collect::<Vec<_>>(),
+        ),
+    )
+}
+
+/// Adds the weighted position differences of every morph target to the given positions.
+///
+/// The differences are read from the mesh's morph target image, which has a layer per target of
+/// [`MorphAttributes::COMPONENT_COUNT`] floats per vertex.
+fn morph_positions(
+    mesh: &Mesh,
+    positions: &mut [Vec3],
+    morph_weights: &MeshMorphWeights,
+    images: &Assets<Image>,
+) {
+    let Some(image) = mesh
+        .morph_targets()
+        .and_then(|morph_targets| images.get(morph_targets))
+    else {
+        return;
+    };
+
+    let Some(data) = image.data.as_ref() else {
+        warn!("morph targets aren't available on the CPU, so they can't be painted");
+        return;
+    };
+
+    let components = data
+        .chunks_exact(size_of::<f32>())
+        .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .collect::<Vec<_>>();
+
+    let size = image.texture_descriptor.size;
+    let layer_stride = (size.width * size.height) as usize;
+
+    for (target, weight) in morph_weights.weights().iter().enumerate() {
+        if *weight == 0.0 {
+            continue;
+        }
+
+        for (vertex, position) in positions.iter_mut().enumerate() {
+            let offset = target * layer_stride + vertex * MorphAttributes::COMPONENT_COUNT;
+
+            if let Some([x, y, z]) = components.get(offset..offset + 3) {
+                *position += Vec3::new(*x, *y, *z) * *weight;
+            }
+        }
+    }
+}
+
+/// Moves the given positions by the current transforms of the skin's joints, then back relative to
+/// the mesh transform.
+fn skin_positions(
+    mesh: &Mesh,
+    positions: &mut [Vec3],
+    skin: &SkinnedMesh,
+    mesh_transform: &GlobalTransform,
+    joints: &Query<&GlobalTransform>,
+    inverse_bindposes: &Assets<SkinnedMeshInverseBindposes>,
+) {
+    let (
+        Some(VertexAttributeValues::Uint16x4(joint_indices)),
+        Some(VertexAttributeValues::Float32x4(joint_weights)),
+        Some(inverse_bindposes),
+    ) = (
+        mesh.attribute(Mesh::ATTRIBUTE_JOINT_INDEX),
+        mesh.attribute(Mesh::ATTRIBUTE_JOINT_WEIGHT),
+        inverse_bindposes.get(&skin.inverse_bindposes),
+    )
+    else {
+        return;
+    };
+
+    // Missing joints are left untransformed.
+    let joint_matrices = skin
+        .joints
+        .iter()
+        .zip(inverse_bindposes.iter())
+        .map(|(joint, inverse_bindpose)| {
+            joints
+                .get(*joint)
+                .map_or(Mat4::IDENTITY, |joint_transform| {
+                    joint_transform.to_matrix() * *inverse_bindpose
+                })
+        })
+        .collect::<Vec<_>>();
+
+    let local_from_world = mesh_transform.to_matrix().inverse();
+
+    for ((position, indices), weights) in positions.iter_mut().zip(joint_indices).zip(joint_weights)
+    {
+        let world_from_bind = indices
+            .iter()
+            .zip(weights)
+            .filter_map(|(index, weight)| Some(*joint_matrices.get(*index as usize)? * *weight))
+            .fold(Mat4::ZERO, |sum, matrix| sum + matrix);
+
+        *position = local_from_world.transform_point3(world_from_bind.transform_point3(*position));
+    }
+}
@@ -0,0 +1,444 @@
+use std::time::Duration;
+
+use bevy::app::AnimationSystems;
+use bevy::camera::visibility::VisibilitySystems;
+use bevy::mesh::InheritWeightSystems;
+use bevy::mesh::morph::MeshMorphWeights;
+use bevy::platform::collections::HashMap;
+use bevy::prelude::*;
+use bevy::render::view::screenshot::trigger_screenshots;
+use bevy::transform::TransformSystems;
+use bevy_pipe_affect::prelude::*;
+use leafwing_input_manager::prelude::*;
+
+use crate::button_predicate::button_just_pressed_predicate;
+use crate::clear_skies::ClearSkiesState;
+use crate::clear_skies::camera::{ClearSkiesRenderTarget, PaintSkiesAction, PaintSkiesCamera};
+use crate::clear_skies::paint_skies::canvas_atlas::PaintCanvasAtlasSettings;
+use crate::clear_skies::paint_skies::paint_layer_history::{
+    PaintableHistory,
+    RecordPaintLayerHistorySet,
+    TruncatePaintLayers,
+    last_layer_index,
+};
+use crate::clear_skies::paint_skies::paint_meshes::{
+    CaptureCanvas,
+    LayerIndex,
+    PaintLayerSettings,
+    capture_canvas,
+};
+use crate::clear_skies::paint_skies::spherical_coords::LookAtSphericalCoords;
+
+/// Plugin for replaying the recorded history of the [`PaintSkiesCamera`].
+///
+/// While replaying, the camera pose and painting are driven by the recorded history instead of live
+/// input, and every layer after the checkpoint is painted again in order. Paintable meshes are
+/// posed as they were recorded too, overriding their animations. Since the paint layers are
+/// truncated before replaying, the replayed layers end up on a new branch of the history.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Reflect)]
+pub struct PaintReplayPlugin;
+
+impl Plugin for PaintReplayPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PaintReplaySettings>()
+            .init_resource::<PaintReplay>()
+            .register_type::<PaintReplaySettings>()
+            .add_systems(
+                Update,
+                (
+                    start_paint_replay
+                        .pipe(affect)
+                        .run_if(button_just_pressed_predicate(PaintSkiesAction::Replay)),
+                    drive_paint_skies_camera
+                        .pipe(affect)
+                        .before(RecordPaintLayerHistorySet)
+                        .before(trigger_screenshots),
+                    last_layer_index
+                        .pipe(advance_paint_replay)
+                        .pipe(affect)
+                        .after(RecordPaintLayerHistorySet),
+                )
+                    .chain()
+                    .run_if(in_state(ClearSkiesState::PaintSkies)),
+            )
+            .add_systems(
+                PostUpdate,
+                drive_paintable_poses
+                    .pipe(affect)
+                    .after(AnimationSystems)
+                    .after(InheritWeightSystems)
+                    .after(TransformSystems::Propagate)
+                    .before(VisibilitySystems::CalculateBounds)
+                    .run_if(in_state(ClearSkiesState::PaintSkies)),
+            );
+    }
+}
+
+/// Settings for replaying paint layers.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Reflect, Resource)]
+pub struct PaintReplaySettings {
+    /// Time between replayed layers.
+    ///
+    /// Set this lower than the interval layers were recorded at for a time-lapse.
+    pub layer_interval: Duration,
+}
+
+impl Default for PaintReplaySettings {
+    fn default() -> Self {
+        PaintReplaySettings {
+            layer_interval: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Resource defining the state of the paint replay.
+#[derive(Debug, Default, Clone, PartialEq, Reflect, Resource)]
+pub enum PaintReplay {
+    /// The [`PaintSkiesCamera`] is driven by live input.
+    #[default]
+    Inactive,
+    /// The [`PaintSkiesCamera`] is driven by the recorded history.
+    Replaying {
+        /// The recorded transforms of the camera.
+        transform_history: PaintableHistory<GlobalTransform>,
+        /// The recorded actions of the camera.
+        action_history: PaintableHistory<ActionState<PaintSkiesAction>>,
+        /// The recorded global transforms of the paintable meshes and the joints posing them.
+        paintable_transform_histories: HashMap<Entity, PaintableHistory<GlobalTransform>>,
+        /// The recorded morph target weights of the paintable meshes.
+        morph_weight_histories: HashMap<Entity, PaintableHistory<Vec<f32>>>,
+        /// The layer currently being replayed.
+        layer: LayerIndex,
+        /// Whether the screenshot for the current layer has been taken, but not yet recorded.
+        screenshot_pending: bool,
+        /// Timer between replayed layers.
+        timer: Timer,
+    },
+}
+
+impl PaintReplay {
+    /// Returns `true` if the camera is driven by live input.
+    pub fn is_inactive(&self) -> bool {
+        matches!(self, PaintReplay::Inactive)
+    }
+}
+
+/// Run condition that returns `true` if the camera is driven by live input.
+pub fn paint_replay_inactive(paint_replay: Res<PaintReplay>) -> bool {
+    paint_replay.is_inactive()
+}
+
+/// Starts replaying the active branch of the camera's history from the first layer after the
+/// checkpoint.
+fn start_paint_replay(
+    paint_replay: Res<PaintReplay>,
+    settings: Res<PaintReplaySettings>,
+    paint_skies_camera: Single<
+        (
+            &PaintableHistory<GlobalTransform>,
+            &PaintableHistory<ActionState<PaintSkiesAction>>,
+        ),
+        With<PaintSkiesCamera>,
+    >,
+    paintable_transform_histories: Query<
+        (Entity, &PaintableHistory<GlobalTransform>),
+        Without<PaintSkiesCamera>,
+    >,
+    morph_weight_histories: Query<(Entity, &PaintableHistory<MeshMorphWeights>)>,
+) -> Option<(ResSet<PaintReplay>, MessageWrite<TruncatePaintLayers>)> {
+    let (transform_history, action_history) = *paint_skies_camera;
+
+    let first_layer = LayerIndex(*transform_history.checkpoint() + 1);
+
+    let has_layers_to_replay = transform_history
+        .last_layer_index()
+        .is_some_and(|last_layer_index| *last_layer_index >= *first_layer);
+
+    (paint_replay.is_inactive() && has_layers_to_replay).then(|| {
+        (
+            res_set(PaintReplay::Replaying {
+                transform_history: transform_history.clone(),
+                action_history: action_history.clone(),
+                paintable_transform_histories: paintable_transform_histories
+                    .iter()
+                    .map(|(entity, history)| (entity, history.clone()))
+                    .collect(),
+                morph_weight_histories: morph_weight_histories
+                    .iter()
+                    .map(|(entity, history)| {
+                        (entity, history.map(|weights| weights.weights().to_vec()))
+                    })
+                    .collect(),
+                layer: first_layer,
+                screenshot_pending: false,
+                timer: Timer::new(settings.layer_interval, TimerMode::Repeating),
+            }),
+            message_write(TruncatePaintLayers::new(first_layer)),
+        )
+    })
+}
+
+/// Sets the pose and actions of the [`PaintSkiesCamera`] to the recorded ones of the layer
+/// currently being replayed, overriding live input.
+///
+/// The [`LookAtSphericalCoords`] are set too, so that live input picks up from the replayed pose.
+fn drive_paint_skies_camera(
+    paint_replay: Res<PaintReplay>,
+    paint_skies_camera: Single<Entity, With<PaintSkiesCamera>>,
+) -> Option<
+    EntityCommandInsert<(
+        Transform,
+        LookAtSphericalCoords,
+        ActionState<PaintSkiesAction>,
+    )>,
+> {
+    let PaintReplay::Replaying {
+        transform_history,
+        action_history,
+        layer,
+        ..
+    } = &*paint_replay
+    else {
+        return None;
+    };
+
+    // The camera has no parent, so its global transform is its local transform.
+    let transform = transform_history.get(*layer)?.compute_transform();
+    let coords = LookAtSphericalCoords::from_direction(*transform.forward());
+    let action_state = replayed_action_state(action_history.get(*layer)?);
+
+    Some(entity_command_insert(
+        *paint_skies_camera,
+        (transform, coords, action_state),
+    ))
+}
+
+/// Returns the recorded actions that paint layers, with every other action released.
+///
+/// Actions like switching branches or saving the session would be triggered again if they were
+/// replayed.
+fn replayed_action_state(
+    recorded: &ActionState<PaintSkiesAction>,
+) -> ActionState<PaintSkiesAction> {
+    let mut action_state = ActionState::default();
+
+    if recorded.pressed(&PaintSkiesAction::Paint) {
+        action_state.press(&PaintSkiesAction::Paint);
+    }
+
+    action_state
+}
+
+/// Sets the global transforms and morph target weights of the paintable meshes and their joints to
+/// the recorded ones of the layer currently being replayed.
+///
+/// Global transforms are set after they're propagated, so that they match the recorded ones no
+/// matter how the ancestors of the paintable meshes moved since.
+fn drive_paintable_poses(
+    paint_replay: Res<PaintReplay>,
+) -> Option<(
+    QueryMap<
+        (Entity, &'static GlobalTransform),
+        Option<ComponentSet<GlobalTransform>>,
+        Without<PaintSkiesCamera>,
+    >,
+    QueryMap<(Entity, &'static MeshMorphWeights), Option<ComponentSet<MeshMorphWeights>>>,
+)> {
+    let PaintReplay::Replaying {
+        paintable_transform_histories,
+        morph_weight_histories,
+        layer,
+        ..
+    } = &*paint_replay
+    else {
+        return None;
+    };
+
+    let transforms = paintable_transform_histories
+        .iter()
+        .filter_map(|(entity, history)| Some((*entity, *history.get(*layer)?)))
+        .collect::<HashMap<_, _>>();
+
+    let weights = morph_weight_histories
+        .iter()
+        .filter_map(|(entity, history)| Some((*entity, history.get(*layer)?.clone())))
+        .collect::<HashMap<_, _>>();
+
+    Some((
+        query_map(move |(entity, _): (Entity, &GlobalTransform)| {
+            transforms.get(&entity).copied().map(component_set)
+        }),
+        query_map(
+            move |(entity, morph_weights): (Entity, &MeshMorphWeights)| {
+                weights.get(&entity).map(|weights| {
+                    let mut morph_weights = morph_weights.clone();
+                    morph_weights.clear_weights();
+                    morph_weights.extend_weights(weights);
+
+                    component_set(morph_weights)
+                })
+            },
+        ),
+    ))
+}
+
+/// Paints the layer currently being replayed, then moves on to the next one once it's recorded.
+///
+/// Once the replay finishes, the transforms of the paintable meshes and their joints are marked as
+/// changed, so that their global transforms are propagated from them again.
+fn advance_paint_replay(
+    In(last_layer_index): In<LayerIndex>,
+    time: Res<Time>,
+    paint_replay: Res<PaintReplay>,
+    render_target: Res<ClearSkiesRenderTarget>,
+    atlas_settings: Res<PaintCanvasAtlasSettings>,
+    paint_layer_settings: Res<PaintLayerSettings>,
+) -> Option<(
+    ResSet<PaintReplay>,
+    Option<CaptureCanvas>,
+    Option<
+        QueryMap<
+            &'static Transform,
+            ComponentSet<Transform>,
+            (
+                With<PaintableHistory<GlobalTransform>>,
+                Without<PaintSkiesCamera>,
+            ),
+        >,
+    >,
+)> {
+    let mut next_paint_replay = (*paint_replay).clone();
+
+    let PaintReplay::Replaying {
+        transform_history,
+        layer,
+        screenshot_pending,
+        timer,
+        ..
+    } = &mut next_paint_replay
+    else {
+        return None;
+    };
+
+    if *screenshot_pending {
+        if *last_layer_index < **layer {
+            return None;
+        }
+
+        let next_layer = LayerIndex(**layer + 1);
+
+        let finished = transform_history
+            .last_layer_index()
+            .is_none_or(|last_replayed_layer| *next_layer > *last_replayed_layer);
+
+        if finished {
+            Some((
+                res_set(PaintReplay::Inactive),
+                None,
+                Some(query_map(|transform: &Transform| component_set(*transform))),
+            ))
+        } else {
+            *layer = next_layer;
+            *screenshot_pending = false;
+
+            Some((res_set(next_paint_replay), None, None))
+        }
+    } else {
+        let take_screenshot = timer.tick(time.delta()).just_finished();
+        *screenshot_pending = take_screenshot;
+
+        Some((
+            res_set(next_paint_replay),
+            take_screenshot.then(|| {
+                capture_canvas(
+                    &render_target,
+                    atlas_settings.copy_mode,
+                    paint_layer_settings.paint_source,
+                )
+            }),
+            None,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+
+    use super::*;
+
+    #[test]
+    fn replay_poses_paintables_and_only_replays_painting() {
+        let mut world = World::new();
+
+        let camera_transform =
+            GlobalTransform::from(Transform::from_xyz(0.0, 1.0, 0.0).looking_to(Vec3::X, Vec3::Y));
+        let mesh_transform = GlobalTransform::from_translation(Vec3::new(1.0, 2.0, 3.0));
+
+        let mut recorded_action_state = ActionState::<PaintSkiesAction>::default();
+        recorded_action_state.press(&PaintSkiesAction::Paint);
+        recorded_action_state.press(&PaintSkiesAction::SwitchBranch);
+        recorded_action_state.press(&PaintSkiesAction::SaveSession);
+        recorded_action_state.press(&PaintSkiesAction::Replay);
+
+        let camera = world.spawn(PaintSkiesCamera).id();
+
+        let mesh = world
+            .spawn((
+                Transform::IDENTITY,
+                GlobalTransform::IDENTITY,
+                MeshMorphWeights::new(vec![0.0, 0.0]).unwrap(),
+            ))
+            .id();
+
+        world.insert_resource(PaintReplay::Replaying {
+            transform_history: PaintableHistory::default()
+                .with_end(LayerIndex(1), Some(camera_transform)),
+            action_history: PaintableHistory::default()
+                .with_end(LayerIndex(1), Some(recorded_action_state)),
+            paintable_transform_histories: HashMap::from_iter([(
+                mesh,
+                PaintableHistory::default().with_end(LayerIndex(1), Some(mesh_transform)),
+            )]),
+            morph_weight_histories: HashMap::from_iter([(
+                mesh,
+                PaintableHistory::default().with_end(LayerIndex(1), Some(vec![0.5, 1.0])),
+            )]),
+            layer: LayerIndex(1),
+            screenshot_pending: false,
+            timer: Timer::default(),
+        });
+
+        world
+            .run_system_once(drive_paint_skies_camera.pipe(affect))
+            .unwrap();
+        world
+            .run_system_once(drive_paintable_poses.pipe(affect))
+            .unwrap();
+
+        let action_state = world.get::<ActionState<PaintSkiesAction>>(camera).unwrap();
+
+        assert!(action_state.pressed(&PaintSkiesAction::Paint));
+        assert!(!action_state.pressed(&PaintSkiesAction::SwitchBranch));
+        assert!(!action_state.pressed(&PaintSkiesAction::SaveSession));
+        assert!(!action_state.pressed(&PaintSkiesAction::Replay));
+
+        assert_eq!(
+            *world.get::<Transform>(camera).unwrap(),
+            camera_transform.compute_transform()
+        );
+        assert!(
+            world
+                .get::<LookAtSphericalCoords>(camera)
+                .unwrap()
+                .direction()
+                .abs_diff_eq(Vec3::X, 1e-5)
+        );
+
+        assert_eq!(*world.get::<GlobalTransform>(mesh).unwrap(), mesh_transform);
+        assert_eq!(
+            world.get::<MeshMorphWeights>(mesh).unwrap().weights(),
+            [0.5, 1.0]
+        );
+    }
+}
@@ -0,0 +1,52 @@
+use bevy::prelude::*;
+
+use crate::clear_skies::paint_skies::spherical_coords::LookAtSphericalCoords;
+
+/// The steepest elevation that [`SkyProjection::Cylinder`] paints at, since the cylinder's height
+/// goes to infinity straight up and down.
+const MAX_CYLINDER_ELEVATION: f32 = 80.0_f32.to_radians();
+
+/// How painted vertices are placed around the play skies camera.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Reflect)]
+pub enum SkyProjection {
+    /// Along the play skies camera's ray through the same viewport position, at the layer
+    /// distance.
+    ///
+    /// This only looks right from the exact position and orientation the layer was painted from.
+    #[default]
+    ViewportRays,
+    /// On a sphere around the play skies camera, with the layer distance as its radius.
+    ///
+    /// Vertices are placed in the direction they were seen from the paint skies camera, so the
+    /// sky can be seen from any direction.
+    Sphere,
+    /// On a vertical cylinder around the play skies camera, with the layer distance as its radius.
+    ///
+    /// Like [`SkyProjection::Sphere`], but keeps vertical lines straight.
+    Cylinder,
+}
+
+impl SkyProjection {
+    /// Returns where a vertex seen in the given direction is placed on the dome around the
+    /// origin, or `None` for [`SkyProjection::ViewportRays`], which doesn't use a dome.
+    pub fn dome_point(
+        &self,
+        origin: Vec3,
+        coords: LookAtSphericalCoords,
+        radius: f32,
+    ) -> Option<Vec3> {
+        match self {
+            SkyProjection::ViewportRays => None,
+            SkyProjection::Sphere => Some(origin + coords.direction() * radius),
+            SkyProjection::Cylinder => {
+                let elevation = coords
+                    .phi
+                    .clamp(-MAX_CYLINDER_ELEVATION, MAX_CYLINDER_ELEVATION);
+
+                let around = Vec3::new(coords.theta.cos(), 0.0, coords.theta.sin());
+
+                Some(origin + (around + Vec3::Y * elevation.tan()) * radius)
+            }
+        }
+    }
+}
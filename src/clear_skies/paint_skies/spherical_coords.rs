@@ -16,15 +16,32 @@ pub struct LookAtSphericalCoords {
     pub phi: f32,
 }
 
+impl LookAtSphericalCoords {
+    /// Returns the coordinates that look along the given direction.
+    pub fn from_direction(direction: Vec3) -> Self {
+        let direction = direction.normalize_or(Vec3::X);
+
+        LookAtSphericalCoords {
+            theta: direction.z.atan2(direction.x),
+            phi: direction.y.clamp(-1.0, 1.0).asin(),
+        }
+    }
+
+    /// Returns the unit direction these coordinates look along.
+    pub fn direction(&self) -> Vec3 {
+        let theta_unit_circle_coords = Vec2::new(self.theta.cos(), self.theta.sin());
+        let phi_unit_circle_coords = Vec2::new(self.phi.cos(), self.phi.sin());
+
+        let xz = theta_unit_circle_coords * phi_unit_circle_coords.x;
+        Vec3::new(xz.x, phi_unit_circle_coords.y, xz.y)
+    }
+}
+
 pub fn look_at_spherical_coords()
 -> QueryMap<(&'static Transform, &'static LookAtSphericalCoords), ComponentSet<Transform>> {
     query_map(
         |(transform, coords): (&Transform, &LookAtSphericalCoords)| {
-            let theta_unit_circle_coords = Vec2::new(coords.theta.cos(), coords.theta.sin());
-            let phi_unit_circle_coords = Vec2::new(coords.phi.cos(), coords.phi.sin());
-
-            let xz = theta_unit_circle_coords * phi_unit_circle_coords.x;
-            let look_at = Vec3::new(xz.x, phi_unit_circle_coords.y, xz.y) + transform.translation;
+            let look_at = coords.direction() + transform.translation;
 
             component_set(transform.looking_at(look_at, Vec3::Y))
         },
@@ -43,45 +43,6 @@ pub struct OctahedronWithUvs {
     pub far_face: TriangleWithUvs,
 }
 
-impl OctahedronWithUvs {
-    /// Returns the centroid of the octahedron and a new `OctahedronWithUvs` whose vertices are
-    /// relative to that centroid.,
-    pub fn centered(self) -> (Vec3, OctahedronWithUvs) {
-        let near_centroid = self.near_face.triangle.centroid();
-        let far_centroid = self.far_face.triangle.centroid();
-
-        let centroid = (near_centroid + far_centroid) / 2.0;
-
-        let near_triangle = Triangle3d {
-            vertices: self
-                .near_face
-                .triangle
-                .vertices
-                .map(|vertex| vertex - centroid),
-        };
-        let far_triangle = Triangle3d {
-            vertices: self
-                .far_face
-                .triangle
-                .vertices
-                .map(|vertex| vertex - centroid),
-        };
-
-        let centered = OctahedronWithUvs {
-            near_face: TriangleWithUvs {
-                triangle: near_triangle,
-                ..self.near_face
-            },
-            far_face: TriangleWithUvs {
-                triangle: far_triangle,
-                ..self.far_face
-            },
-        };
-
-        (centroid, centered)
-    }
-}
-
 impl From<OctahedronWithUvs> for Mesh {
     fn from(
         OctahedronWithUvs {
@@ -112,3 +73,23 @@ impl From<OctahedronWithUvs> for Mesh {
         ))
     }
 }
+
+/// Merges octahedra into one mesh, returning its center and the mesh with vertices relative to
+/// that center.
+///
+/// Every octahedron takes up 8 consecutive triangles of the mesh, in the order they were given.
+pub fn merge_octahedra(
+    octahedra: impl IntoIterator<Item = OctahedronWithUvs>,
+) -> Option<(Vec3, Mesh)> {
+    let mesh = octahedra
+        .into_iter()
+        .map(Mesh::from)
+        .reduce(|mut merged, mesh| {
+            merged.merge(&mesh).expect("octahedron meshes are built the same, so they should have the same types and primitive topology");
+            merged
+        })?;
+
+    let center = Vec3::from(mesh.compute_aabb()?.center);
+
+    Some((center, mesh.translated_by(-center)))
+}
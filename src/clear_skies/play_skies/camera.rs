@@ -2,10 +2,47 @@ use bevy::camera::RenderTarget;
 use bevy::camera::visibility::RenderLayers;
 use bevy::prelude::*;
 use bevy_pipe_affect::prelude::*;
+use leafwing_input_manager::prelude::*;
 
 use crate::clear_skies::camera::ClearSkiesRenderTarget;
 use crate::clear_skies::render_layers::PAINTED_LAYER;
 
+/// Actions for playing in the painted skies.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Reflect, Actionlike)]
+pub enum PlaySkiesAction {
+    /// Button input for switching back to [`ClearSkiesState::PaintSkies`].
+    ///
+    /// [`ClearSkiesState::PaintSkies`]: crate::clear_skies::ClearSkiesState::PaintSkies
+    #[actionlike(Button)]
+    PaintSkies,
+    /// Dual axis input for looking around.
+    #[actionlike(DualAxis)]
+    Look,
+    /// Dual axis input for flying forwards, backwards and sideways, relative to where the player
+    /// is looking.
+    #[actionlike(DualAxis)]
+    Fly,
+    /// Axis input for flying straight up or down.
+    #[actionlike(Axis)]
+    Ascend,
+    /// Button input for flying faster.
+    #[actionlike(Button)]
+    Boost,
+    /// Button input for getting on or off the rail along the recorded paint path.
+    #[actionlike(Button)]
+    Rail,
+    /// Button input for pausing or resuming the rail.
+    #[actionlike(Button)]
+    PauseRail,
+    /// Button input for flying the rail backwards while held.
+    #[actionlike(Button)]
+    RewindRail,
+}
+
+/// The camera that renders the painted layers.
+///
+/// Layers are painted around where it rests while painting, and it follows the
+/// [`PlaySkiesPlayer`](crate::clear_skies::play_skies::controller::PlaySkiesPlayer) while playing.
 #[derive(Default, Debug, PartialEq, Eq, Copy, Clone, Hash, Reflect, Component)]
 #[reflect(Component)]
 #[require(Name = "PlaySkiesCamera", Camera3d, RenderLayers = PAINTED_LAYER)]
@@ -13,12 +50,52 @@ pub struct PlaySkiesCamera;
 
 pub fn spawn_camera(
     render_target: Res<ClearSkiesRenderTarget>,
-) -> CommandSpawn<(PlaySkiesCamera, Camera, RenderTarget)> {
+) -> CommandSpawn<(
+    InputMap<PlaySkiesAction>,
+    PlaySkiesCamera,
+    Camera,
+    RenderTarget,
+)> {
+    let input_map = InputMap::default()
+        .with(PlaySkiesAction::PaintSkies, GamepadButton::Start)
+        .with(PlaySkiesAction::PaintSkies, KeyCode::Tab)
+        .with(PlaySkiesAction::Boost, GamepadButton::South)
+        .with(PlaySkiesAction::Boost, KeyCode::ShiftLeft)
+        .with(PlaySkiesAction::Rail, GamepadButton::North)
+        .with(PlaySkiesAction::Rail, KeyCode::KeyT)
+        .with(PlaySkiesAction::PauseRail, GamepadButton::West)
+        .with(PlaySkiesAction::PauseRail, KeyCode::KeyP)
+        .with(PlaySkiesAction::RewindRail, GamepadButton::LeftTrigger)
+        .with(PlaySkiesAction::RewindRail, KeyCode::KeyR)
+        .with_axis(
+            PlaySkiesAction::Ascend,
+            VirtualAxis::new(GamepadButton::LeftTrigger2, GamepadButton::RightTrigger2),
+        )
+        .with_axis(
+            PlaySkiesAction::Ascend,
+            VirtualAxis::new(KeyCode::KeyQ, KeyCode::KeyE),
+        )
+        .with_dual_axis(
+            PlaySkiesAction::Fly,
+            GamepadStick::LEFT.with_deadzone_symmetric(0.1),
+        )
+        .with_dual_axis(PlaySkiesAction::Fly, VirtualDPad::wasd())
+        .with_dual_axis(
+            PlaySkiesAction::Look,
+            GamepadStick::RIGHT.with_deadzone_symmetric(0.1),
+        )
+        .with_dual_axis(
+            PlaySkiesAction::Look,
+            MouseMove::default().sensitivity(0.15).inverted_y(),
+        );
+
     command_spawn((
+        input_map,
         PlaySkiesCamera,
         Camera {
             order: 1,
-            clear_color: ClearColorConfig::Custom(Color::srgb(0.0, 0.4, 1.0)),
+            // Cleared to transparent rather than the sky, see `ClearSkiesRenderTarget`.
+            clear_color: ClearColorConfig::Custom(Color::NONE),
             ..default()
         },
         RenderTarget::from((**render_target).clone()),
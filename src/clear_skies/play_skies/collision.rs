@@ -0,0 +1,620 @@
+use bevy::math::Vec3A;
+use bevy::math::bounding::{Aabb3d, BoundingSphere, BoundingVolume, IntersectsVolume, RayCast3d};
+use bevy::platform::collections::HashMap;
+use bevy::prelude::*;
+use bevy_pipe_affect::prelude::*;
+
+use crate::clear_skies::paint_skies::{BakedPaintLayers, LayerIndex, PaintedMesh};
+use crate::clear_skies::triangle_bvh::{BvhTriangle, TriangleBvh};
+
+/// The number of triangles every octahedron of a [`PaintedMesh`] takes up.
+pub const OCTAHEDRON_TRIANGLES: usize = 8;
+
+/// How many steps a sphere cast takes towards a triangle before giving up.
+const SPHERE_CAST_STEPS: usize = 32;
+
+/// How close a sphere cast has to get to a triangle to count as touching it.
+const SPHERE_CAST_TOLERANCE: f32 = 1e-3;
+
+/// How far short of painted meshes spheres moved by [`PaintedColliders::sphere_cast`] should
+/// stop, so that they don't start their next move already touching them.
+pub const PAINTED_SKIN: f32 = 0.01;
+
+/// How far past its radius a [`PaintedContactSphere`] counts as touching painted meshes, which
+/// covers the [`PAINTED_SKIN`] that moving spheres stop short by.
+const PAINTED_CONTACT_MARGIN: f32 = 4.0 * PAINTED_SKIN;
+
+/// A hit on the triangles of a [`PaintedMesh`], as returned by [`PaintedColliders`] queries.
+#[derive(Debug, Copy, Clone, PartialEq, Reflect)]
+pub struct PaintedHit {
+    /// The [`PaintedMesh`] or [`BakedPaintLayers`] entity that was hit.
+    pub painted_mesh: Entity,
+    /// The entity whose mesh the hit octahedron was painted from.
+    pub painted_from: Entity,
+    /// The layer that the hit mesh was painted on.
+    pub paint_layer: LayerIndex,
+    /// The triangle of the original paintable mesh that the hit octahedron was painted from.
+    pub triangle_index: usize,
+    /// How far along the cast the hit is, or how far the sphere's center is from it for contacts.
+    pub distance: f32,
+    /// The point on the painted triangle that was hit.
+    pub point: Vec3,
+    /// The direction facing away from the hit triangle, towards what hit it.
+    pub normal: Dir3,
+}
+
+/// A world-space triangle of a [`PaintedMesh`] or [`BakedPaintLayers`].
+#[derive(Debug, Copy, Clone, PartialEq, Reflect)]
+struct ColliderTriangle {
+    triangle: Triangle3d,
+    painted_mesh: Entity,
+    painted_from: Entity,
+    triangle_index: usize,
+}
+
+impl BvhTriangle for ColliderTriangle {
+    fn triangle(&self) -> &Triangle3d {
+        &self.triangle
+    }
+}
+
+impl ColliderTriangle {
+    fn hit(&self, paint_layer: LayerIndex, distance: f32, point: Vec3, normal: Dir3) -> PaintedHit {
+        PaintedHit {
+            painted_mesh: self.painted_mesh,
+            painted_from: self.painted_from,
+            paint_layer,
+            triangle_index: self.triangle_index,
+            distance,
+            point,
+            normal,
+        }
+    }
+}
+
+/// The bounding volume hierarchy over the painted triangles of one layer.
+#[derive(Debug, Clone, PartialEq, Reflect)]
+struct LayerCollider {
+    paint_layer: LayerIndex,
+    bvh: TriangleBvh<ColliderTriangle>,
+}
+
+impl LayerCollider {
+    /// Returns the collider of the given layer's triangles, if there are any.
+    fn new(paint_layer: LayerIndex, triangles: Vec<ColliderTriangle>) -> Option<Self> {
+        Some(LayerCollider {
+            paint_layer,
+            bvh: TriangleBvh::new(triangles)?,
+        })
+    }
+
+    /// Returns every triangle whose bounding box is in a subtree that passes the given test.
+    fn candidates(&self, overlaps: impl Fn(&Aabb3d) -> bool) -> Vec<&ColliderTriangle> {
+        self.bvh.candidates(overlaps)
+    }
+}
+
+/// Resource for collision queries against the visible [`PaintedMesh`]es and [`BakedPaintLayers`],
+/// built from their octahedra when entering [`ClearSkiesState::PlaySkies`].
+///
+/// Every layer has its own bounding volume hierarchy, so whole layers are skipped by their bounds.
+///
+/// [`ClearSkiesState::PlaySkies`]: crate::clear_skies::ClearSkiesState::PlaySkies
+#[derive(Debug, Default, Clone, PartialEq, Reflect, Resource)]
+pub struct PaintedColliders {
+    layers: Vec<LayerCollider>,
+}
+
+impl PaintedColliders {
+    /// Returns the nearest hit of the given ray within `max_distance`.
+    pub fn ray_cast(&self, ray: Ray3d, max_distance: f32) -> Option<PaintedHit> {
+        let ray_cast = RayCast3d::from_ray(ray, max_distance);
+
+        self.layers
+            .iter()
+            .flat_map(|layer| {
+                layer
+                    .candidates(|aabb| ray_cast.aabb_intersection_at(aabb).is_some())
+                    .into_iter()
+                    .filter_map(move |triangle| {
+                        let distance = ray_triangle_distance(ray, &triangle.triangle)?;
+                        let normal = triangle.triangle.normal().ok()?;
+                        let normal = if normal.dot(*ray.direction) > 0.0 {
+                            -normal
+                        } else {
+                            normal
+                        };
+
+                        Some(triangle.hit(
+                            layer.paint_layer,
+                            distance,
+                            ray.get_point(distance),
+                            normal,
+                        ))
+                    })
+            })
+            .filter(|hit| hit.distance <= max_distance)
+            .min_by(|a, b| a.distance.total_cmp(&b.distance))
+    }
+
+    /// Returns the nearest hit of a sphere with the given radius moving along the given ray within
+    /// `max_distance`.
+    ///
+    /// Triangles that the sphere already touches are ignored, so that it can move out of them, but
+    /// every other triangle along the way is still hit.
+    pub fn sphere_cast(&self, ray: Ray3d, radius: f32, max_distance: f32) -> Option<PaintedHit> {
+        let ray_cast = RayCast3d::from_ray(ray, max_distance);
+
+        self.layers
+            .iter()
+            .flat_map(|layer| {
+                layer
+                    .candidates(|aabb| {
+                        ray_cast
+                            .aabb_intersection_at(&aabb.grow(Vec3A::splat(radius)))
+                            .is_some()
+                    })
+                    .into_iter()
+                    .filter_map(move |triangle| {
+                        let (distance, point) =
+                            sphere_cast_triangle(ray, radius, max_distance, &triangle.triangle)?;
+
+                        if distance <= 0.0 {
+                            return None;
+                        }
+
+                        let normal = Dir3::new(ray.get_point(distance) - point)
+                            .or_else(|_| triangle.triangle.normal())
+                            .ok()?;
+
+                        Some(triangle.hit(layer.paint_layer, distance, point, normal))
+                    })
+            })
+            .min_by(|a, b| a.distance.total_cmp(&b.distance))
+    }
+
+    /// Returns a hit for every painted triangle that touches the sphere with the given center and
+    /// radius.
+    pub fn sphere_contacts(&self, center: Vec3, radius: f32) -> Vec<PaintedHit> {
+        let sphere = BoundingSphere::new(center, radius);
+
+        self.layers
+            .iter()
+            .flat_map(|layer| {
+                layer
+                    .candidates(|aabb| sphere.intersects(aabb))
+                    .into_iter()
+                    .filter_map(move |triangle| {
+                        let point = closest_point_on_triangle(&triangle.triangle, center);
+                        let distance = center.distance(point);
+
+                        if distance > radius {
+                            return None;
+                        }
+
+                        let normal = Dir3::new(center - point)
+                            .or_else(|_| triangle.triangle.normal())
+                            .ok()?;
+
+                        Some(triangle.hit(layer.paint_layer, distance, point, normal))
+                    })
+            })
+            .collect()
+    }
+}
+
+/// Returns how far along the ray it hits the triangle, from either side.
+fn ray_triangle_distance(ray: Ray3d, triangle: &Triangle3d) -> Option<f32> {
+    let [a, b, c] = triangle.vertices;
+    let ab = b - a;
+    let ac = c - a;
+
+    let p = ray.direction.cross(ac);
+    let determinant = ab.dot(p);
+
+    if determinant.abs() < f32::EPSILON {
+        return None;
+    }
+
+    let inverse_determinant = determinant.recip();
+    let from_a = ray.origin - a;
+
+    let u = from_a.dot(p) * inverse_determinant;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+
+    let q = from_a.cross(ab);
+    let v = ray.direction.dot(q) * inverse_determinant;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+
+    let distance = ac.dot(q) * inverse_determinant;
+    (distance >= 0.0).then_some(distance)
+}
+
+/// Returns how far along the ray a sphere moving along it first touches the triangle, and where.
+///
+/// The sphere is advanced by its distance to the triangle until it touches it, which can't step
+/// past the triangle since it's convex.
+fn sphere_cast_triangle(
+    ray: Ray3d,
+    radius: f32,
+    max_distance: f32,
+    triangle: &Triangle3d,
+) -> Option<(f32, Vec3)> {
+    let mut distance = 0.0;
+
+    for _ in 0..SPHERE_CAST_STEPS {
+        let center = ray.get_point(distance);
+        let point = closest_point_on_triangle(triangle, center);
+        let gap = center.distance(point) - radius;
+
+        if gap <= SPHERE_CAST_TOLERANCE {
+            return Some((distance, point));
+        }
+
+        distance += gap;
+
+        if distance > max_distance {
+            return None;
+        }
+    }
+
+    None
+}
+
+/// Returns the point on the triangle closest to the given point, by which of the triangle's
+/// vertex, edge or face regions it's in.
+fn closest_point_on_triangle(triangle: &Triangle3d, point: Vec3) -> Vec3 {
+    let [a, b, c] = triangle.vertices;
+    let ab = b - a;
+    let ac = c - a;
+
+    let from_a = point - a;
+    let d1 = ab.dot(from_a);
+    let d2 = ac.dot(from_a);
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return a;
+    }
+
+    let from_b = point - b;
+    let d3 = ab.dot(from_b);
+    let d4 = ac.dot(from_b);
+    if d3 >= 0.0 && d4 <= d3 {
+        return b;
+    }
+
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        return a + ab * (d1 / (d1 - d3));
+    }
+
+    let from_c = point - c;
+    let d5 = ab.dot(from_c);
+    let d6 = ac.dot(from_c);
+    if d6 >= 0.0 && d5 <= d6 {
+        return c;
+    }
+
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        return a + ac * (d2 / (d2 - d6));
+    }
+
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0.0 && d4 - d3 >= 0.0 && d5 - d6 >= 0.0 {
+        return b + (c - b) * ((d4 - d3) / ((d4 - d3) + (d5 - d6)));
+    }
+
+    let denominator = (va + vb + vc).recip();
+    a + ab * (vb * denominator) + ac * (vc * denominator)
+}
+
+/// Returns the world-space triangles of a painted or baked mesh, paired with the layer they were
+/// painted on.
+///
+/// `painted_triangle` returns the entity that a triangle of the mesh was painted from, its layer,
+/// and the triangle of the original mesh, if it's known.
+fn collider_triangles<'a>(
+    painted_mesh: Entity,
+    mesh: &'a Mesh,
+    transform: &'a GlobalTransform,
+    painted_triangle: impl Fn(usize) -> Option<(Entity, LayerIndex, usize)> + 'a,
+) -> impl Iterator<Item = (LayerIndex, ColliderTriangle)> + 'a {
+    mesh.triangles()
+        .into_iter()
+        .flatten()
+        .enumerate()
+        .filter_map(move |(mesh_triangle, triangle)| {
+            let triangle = Triangle3d {
+                vertices: triangle
+                    .vertices
+                    .map(|vertex| transform.transform_point(vertex)),
+            };
+
+            triangle.normal().ok()?;
+
+            let (painted_from, paint_layer, triangle_index) = painted_triangle(mesh_triangle)?;
+
+            Some((
+                paint_layer,
+                ColliderTriangle {
+                    triangle,
+                    painted_mesh,
+                    painted_from,
+                    triangle_index,
+                },
+            ))
+        })
+}
+
+/// Builds the [`PaintedColliders`] from the world-space triangles of the visible
+/// [`PaintedMesh`]es and the [`BakedPaintLayers`].
+pub fn build_painted_colliders(
+    painted_meshes: Query<(Entity, &PaintedMesh, &Mesh3d, &GlobalTransform, &Visibility)>,
+    baked_meshes: Query<(Entity, &BakedPaintLayers, &Mesh3d, &GlobalTransform)>,
+    meshes: Res<Assets<Mesh>>,
+) -> ResSet<PaintedColliders> {
+    let painted_triangles = painted_meshes
+        .iter()
+        .filter(|(.., visibility)| **visibility != Visibility::Hidden)
+        .filter_map(|(entity, painted_mesh, mesh, transform, _)| {
+            Some((entity, painted_mesh, meshes.get(mesh)?, transform))
+        })
+        .flat_map(|(entity, painted_mesh, mesh, transform)| {
+            collider_triangles(entity, mesh, transform, |mesh_triangle| {
+                Some((
+                    painted_mesh.painted_from,
+                    painted_mesh.paint_layer,
+                    *painted_mesh
+                        .triangle_indices
+                        .get(mesh_triangle / OCTAHEDRON_TRIANGLES)?,
+                ))
+            })
+        });
+
+    let baked_triangles = baked_meshes
+        .iter()
+        .filter_map(|(entity, baked_paint_layers, mesh, transform)| {
+            Some((entity, baked_paint_layers, meshes.get(mesh)?, transform))
+        })
+        .flat_map(|(entity, baked_paint_layers, mesh, transform)| {
+            collider_triangles(entity, mesh, transform, |mesh_triangle| {
+                let painted_mesh = baked_paint_layers.painted_mesh(mesh_triangle)?;
+
+                Some((
+                    painted_mesh.painted_from,
+                    painted_mesh.paint_layer,
+                    *painted_mesh.triangle_indices.get(
+                        (mesh_triangle - painted_mesh.first_triangle) / OCTAHEDRON_TRIANGLES,
+                    )?,
+                ))
+            })
+        });
+
+    let triangles_by_layer = painted_triangles.chain(baked_triangles).fold(
+        HashMap::<LayerIndex, Vec<ColliderTriangle>>::new(),
+        |mut layers, (paint_layer, triangle)| {
+            layers.entry(paint_layer).or_default().push(triangle);
+            layers
+        },
+    );
+
+    res_set(PaintedColliders {
+        layers: triangles_by_layer
+            .into_iter()
+            .filter_map(|(paint_layer, triangles)| LayerCollider::new(paint_layer, triangles))
+            .collect(),
+    })
+}
+
+/// Clears the [`PaintedColliders`], since painting changes the painted meshes.
+pub fn clear_painted_colliders() -> ResSet<PaintedColliders> {
+    res_set(PaintedColliders::default())
+}
+
+/// Component for a sphere around this entity that reports its contacts with painted meshes as
+/// [`PaintedContactStarted`] and [`PaintedContactEnded`] messages.
+///
+/// Painted meshes count as touching a little past the radius, so that spheres stopped
+/// [`PAINTED_SKIN`] short of them still touch them.
+#[derive(Debug, Copy, Clone, PartialEq, Reflect, Component)]
+#[reflect(Component)]
+#[require(PaintedContacts)]
+pub struct PaintedContactSphere {
+    /// The radius of the sphere.
+    pub radius: f32,
+}
+
+/// Something a [`PaintedContactSphere`] can touch, which is a [`PaintedMesh`], or one of the
+/// painted meshes merged into [`BakedPaintLayers`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Reflect)]
+pub struct PaintedContact {
+    /// The [`PaintedMesh`] or [`BakedPaintLayers`] entity.
+    pub painted_mesh: Entity,
+    /// The entity whose mesh it was painted from.
+    pub painted_from: Entity,
+    /// The layer it was painted on.
+    pub paint_layer: LayerIndex,
+}
+
+impl PaintedHit {
+    /// Returns what was hit, as a [`PaintedContact`].
+    pub fn contact(&self) -> PaintedContact {
+        PaintedContact {
+            painted_mesh: self.painted_mesh,
+            painted_from: self.painted_from,
+            paint_layer: self.paint_layer,
+        }
+    }
+}
+
+/// The [`PaintedContact`]s that this entity's [`PaintedContactSphere`] currently touches.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deref, Reflect, Component)]
+#[reflect(Component)]
+pub struct PaintedContacts(Vec<PaintedContact>);
+
+/// Message sent when a [`PaintedContactSphere`] starts touching a [`PaintedContact`].
+#[derive(Debug, Copy, Clone, PartialEq, Message)]
+pub struct PaintedContactStarted {
+    /// The entity with the [`PaintedContactSphere`].
+    pub entity: Entity,
+    /// The nearest hit on the touched [`PaintedContact`].
+    pub hit: PaintedHit,
+}
+
+/// Message sent when a [`PaintedContactSphere`] stops touching a [`PaintedContact`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Message)]
+pub struct PaintedContactEnded {
+    /// The entity with the [`PaintedContactSphere`].
+    pub entity: Entity,
+    /// The [`PaintedContact`] that's no longer touched.
+    pub contact: PaintedContact,
+}
+
+/// Updates the [`PaintedContacts`] of every [`PaintedContactSphere`], sending messages for the
+/// contacts that started and ended.
+pub fn detect_painted_contacts(
+    colliders: Res<PaintedColliders>,
+    spheres: Query<(Entity, &Transform, &PaintedContactSphere, &PaintedContacts)>,
+) -> Vec<
+    Option<(
+        EntityCommandInsert<PaintedContacts>,
+        Vec<MessageWrite<PaintedContactStarted>>,
+        Vec<MessageWrite<PaintedContactEnded>>,
+    )>,
+> {
+    spheres
+        .iter()
+        .map(|(entity, transform, sphere, contacts)| {
+            // Only the nearest hit on every contact is kept.
+            let hits = colliders
+                .sphere_contacts(
+                    transform.translation,
+                    sphere.radius + PAINTED_CONTACT_MARGIN,
+                )
+                .into_iter()
+                .fold(
+                    HashMap::<PaintedContact, PaintedHit>::new(),
+                    |mut hits, hit| {
+                        hits.entry(hit.contact())
+                            .and_modify(|nearest| {
+                                if hit.distance < nearest.distance {
+                                    *nearest = hit;
+                                }
+                            })
+                            .or_insert(hit);
+                        hits
+                    },
+                );
+
+            let started = hits
+                .values()
+                .filter(|hit| !contacts.contains(&hit.contact()))
+                .map(|hit| message_write(PaintedContactStarted { entity, hit: *hit }))
+                .collect::<Vec<_>>();
+
+            let ended = contacts
+                .iter()
+                .filter(|contact| !hits.contains_key(contact))
+                .map(|contact| {
+                    message_write(PaintedContactEnded {
+                        entity,
+                        contact: *contact,
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            (!started.is_empty() || !ended.is_empty()).then(|| {
+                (
+                    entity_command_insert(entity, PaintedContacts(hits.into_keys().collect())),
+                    started,
+                    ended,
+                )
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a triangle facing along Z at the given depth, big enough to be hit around the origin.
+    fn wall(z: f32) -> Triangle3d {
+        Triangle3d::new(
+            Vec3::new(-10.0, -10.0, z),
+            Vec3::new(10.0, -10.0, z),
+            Vec3::new(0.0, 10.0, z),
+        )
+    }
+
+    /// Returns colliders with one painted mesh per given triangle, on the layer of the same index.
+    fn colliders(triangles: impl IntoIterator<Item = Triangle3d>) -> PaintedColliders {
+        PaintedColliders {
+            layers: triangles
+                .into_iter()
+                .enumerate()
+                .filter_map(|(index, triangle)| {
+                    LayerCollider::new(
+                        LayerIndex(index as u32),
+                        vec![ColliderTriangle {
+                            triangle,
+                            painted_mesh: Entity::from_raw_u32(index as u32).unwrap(),
+                            painted_from: Entity::PLACEHOLDER,
+                            triangle_index: index,
+                        }],
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn ray_cast_hits_nearest_triangle() {
+        let colliders = colliders([wall(-8.0), wall(-4.0)]);
+
+        let hit = colliders
+            .ray_cast(Ray3d::new(Vec3::ZERO, Dir3::NEG_Z), 10.0)
+            .unwrap();
+
+        assert_eq!(hit.paint_layer, LayerIndex(1));
+        assert_eq!(hit.triangle_index, 1);
+        assert!((hit.distance - 4.0).abs() < 1e-4);
+        assert_eq!(hit.normal, Dir3::Z);
+    }
+
+    #[test]
+    fn sphere_cast_ignores_touching_triangles_but_not_further_ones() {
+        let colliders = colliders([wall(-0.5), wall(-4.0)]);
+
+        let hit = colliders
+            .sphere_cast(Ray3d::new(Vec3::ZERO, Dir3::NEG_Z), 0.5, 10.0)
+            .unwrap();
+
+        assert_eq!(hit.paint_layer, LayerIndex(1));
+        assert!((hit.distance - 3.5).abs() < 1e-2);
+    }
+
+    #[test]
+    fn sphere_stopped_short_of_a_triangle_still_touches_it() {
+        let colliders = colliders([wall(-4.0)]);
+        let radius = 0.5;
+
+        let hit = colliders
+            .sphere_cast(Ray3d::new(Vec3::ZERO, Dir3::NEG_Z), radius, 10.0)
+            .unwrap();
+
+        let stopped = Vec3::NEG_Z * (hit.distance - PAINTED_SKIN);
+
+        assert!(colliders.sphere_contacts(stopped, radius).is_empty());
+        assert_eq!(
+            colliders
+                .sphere_contacts(stopped, radius + PAINTED_CONTACT_MARGIN)
+                .len(),
+            1
+        );
+    }
+}
@@ -0,0 +1,198 @@
+use std::f32::consts::{FRAC_PI_2, PI};
+
+use bevy::camera::visibility::RenderLayers;
+use bevy::prelude::*;
+use bevy_pipe_affect::prelude::*;
+use leafwing_input_manager::prelude::*;
+
+use crate::clear_skies::ClearSkiesState;
+use crate::clear_skies::paint_skies::{LookAtSphericalCoords, SphericalCoordsBounds};
+use crate::clear_skies::play_skies::camera::{PlaySkiesAction, PlaySkiesCamera};
+use crate::clear_skies::play_skies::collision::{
+    PAINTED_SKIN,
+    PaintedColliders,
+    PaintedContactSphere,
+};
+use crate::clear_skies::play_skies::settings::PlaySkiesSettings;
+use crate::clear_skies::render_layers::PAINTED_LAYER;
+
+/// Where the [`PlaySkiesCamera`] rests while painting, which layers are painted around.
+pub const PLAY_SKIES_CAMERA_HOME: Transform = Transform::IDENTITY;
+
+/// The player flying through the painted skies, which only exists in
+/// [`ClearSkiesState::PlaySkies`].
+///
+/// It's controlled by the [`PlaySkiesAction`]s of the [`PlaySkiesCamera`], which follows it.
+#[derive(Default, Debug, PartialEq, Eq, Copy, Clone, Hash, Reflect, Component)]
+#[reflect(Component)]
+#[require(
+    Name = "PlaySkiesPlayer",
+    LookAtSphericalCoords,
+    RenderLayers = PAINTED_LAYER,
+    PaintedContactSphere = PaintedContactSphere { radius: 0.5 }
+)]
+pub struct PlaySkiesPlayer;
+
+/// Spawns the [`PlaySkiesPlayer`] in front of the [`PlaySkiesCamera`], looking the same way.
+pub fn spawn_player(
+    camera: Single<&Transform, With<PlaySkiesCamera>>,
+    settings: Res<PlaySkiesSettings>,
+) -> AssetAddAnd<
+    Mesh,
+    AssetAddAnd<
+        StandardMaterial,
+        CommandSpawn<(
+            PlaySkiesPlayer,
+            LookAtSphericalCoords,
+            SphericalCoordsBounds,
+            Transform,
+            Mesh3d,
+            MeshMaterial3d<StandardMaterial>,
+            DespawnOnExit<ClearSkiesState>,
+        )>,
+    >,
+> {
+    let forward = camera.forward();
+    let translation = camera.translation + forward * settings.follow_distance;
+
+    // Cones point up, but the player looks along -Z.
+    let mesh = Cone::new(0.3, 1.0)
+        .mesh()
+        .build()
+        .rotated_by(Quat::from_rotation_x(-FRAC_PI_2));
+
+    let material = StandardMaterial {
+        base_color: Color::srgb(1.0, 0.9, 0.4),
+        unlit: true,
+        ..default()
+    };
+
+    asset_add_and(mesh, move |mesh_handle| {
+        asset_add_and(material, move |material_handle| {
+            command_spawn((
+                PlaySkiesPlayer,
+                LookAtSphericalCoords::from_direction(*forward),
+                SphericalCoordsBounds {
+                    max_phi: 3.0 * PI / 8.0,
+                    min_phi: -3.0 * PI / 8.0,
+                },
+                Transform::from_translation(translation),
+                Mesh3d(mesh_handle),
+                MeshMaterial3d(material_handle),
+                DespawnOnExit(ClearSkiesState::PlaySkies),
+            ))
+        })
+    })
+}
+
+/// Updates the [`LookAtSphericalCoords`] of the [`PlaySkiesPlayer`] according to
+/// [`PlaySkiesAction::Look`] input.
+pub fn control_player_look(
+    action_state: Single<&ActionState<PlaySkiesAction>, With<PlaySkiesCamera>>,
+    settings: Res<PlaySkiesSettings>,
+) -> QueryMap<
+    (
+        &'static LookAtSphericalCoords,
+        &'static SphericalCoordsBounds,
+    ),
+    ComponentSet<LookAtSphericalCoords>,
+    With<PlaySkiesPlayer>,
+> {
+    let look_by =
+        action_state.clamped_axis_pair(&PlaySkiesAction::Look) * settings.look_sensitivity;
+
+    query_map(
+        move |(spherical_coords, bounds): (&LookAtSphericalCoords, &SphericalCoordsBounds)| {
+            let phi = (spherical_coords.phi + look_by.y).clamp(bounds.min_phi, bounds.max_phi);
+            let theta = (spherical_coords.theta + look_by.x) % (2.0 * PI);
+
+            component_set(LookAtSphericalCoords { phi, theta })
+        },
+    )
+}
+
+/// Moves the [`PlaySkiesPlayer`] according to [`PlaySkiesAction::Fly`] and
+/// [`PlaySkiesAction::Ascend`] input, relative to where it's looking.
+///
+/// The player stops short of painted meshes in its way.
+pub fn fly_player(
+    action_state: Single<&ActionState<PlaySkiesAction>, With<PlaySkiesCamera>>,
+    player: Single<
+        (&Transform, &LookAtSphericalCoords, &PaintedContactSphere),
+        With<PlaySkiesPlayer>,
+    >,
+    colliders: Res<PaintedColliders>,
+    settings: Res<PlaySkiesSettings>,
+    time: Res<Time>,
+) -> QueryAffect<ComponentSet<Transform>, With<PlaySkiesPlayer>> {
+    let (transform, spherical_coords, contact_sphere) = *player;
+
+    let fly = action_state.clamped_axis_pair(&PlaySkiesAction::Fly);
+    let ascend = action_state.clamped_value(&PlaySkiesAction::Ascend);
+
+    let speed = if action_state.pressed(&PlaySkiesAction::Boost) {
+        settings.fly_speed * settings.boost_multiplier
+    } else {
+        settings.fly_speed
+    };
+
+    let forward = spherical_coords.direction();
+    let right = forward.cross(Vec3::Y).normalize_or_zero();
+
+    let velocity = (forward * fly.y + right * fly.x + Vec3::Y * ascend).clamp_length_max(1.0);
+    let distance = speed * time.delta_secs() * velocity.length();
+
+    let distance = Dir3::new(velocity).map_or(0.0, |direction| {
+        colliders
+            .sphere_cast(
+                Ray3d::new(transform.translation, direction),
+                contact_sphere.radius,
+                distance,
+            )
+            .map_or(distance, |hit| (hit.distance - PAINTED_SKIN).max(0.0))
+    });
+
+    query_affect(component_set(Transform {
+        translation: transform.translation + velocity.normalize_or_zero() * distance,
+        ..*transform
+    }))
+}
+
+/// Moves the [`PlaySkiesCamera`] towards its spot behind the [`PlaySkiesPlayer`], looking at it.
+///
+/// The spot is pulled in front of painted meshes between it and the player.
+pub fn follow_player(
+    player: Single<&Transform, With<PlaySkiesPlayer>>,
+    colliders: Res<PaintedColliders>,
+    settings: Res<PlaySkiesSettings>,
+    time: Res<Time>,
+) -> QueryMap<&'static Transform, ComponentSet<Transform>, With<PlaySkiesCamera>> {
+    let player_translation = player.translation;
+    let follow_offset = player.back() * settings.follow_distance + Vec3::Y * settings.follow_height;
+
+    let follow_distance = Dir3::new(follow_offset).map_or(0.0, |direction| {
+        colliders
+            .ray_cast(
+                Ray3d::new(player_translation, direction),
+                follow_offset.length(),
+            )
+            .map_or(follow_offset.length(), |hit| hit.distance)
+    });
+
+    let follow_translation =
+        player_translation + follow_offset.normalize_or_zero() * follow_distance;
+
+    let catch_up = 1.0 - (1.0 - settings.follow_smoothing).powf(time.delta_secs());
+
+    query_map(move |transform: &Transform| {
+        component_set(
+            Transform::from_translation(transform.translation.lerp(follow_translation, catch_up))
+                .looking_at(player_translation, Vec3::Y),
+        )
+    })
+}
+
+/// Moves the [`PlaySkiesCamera`] back to where layers are painted around.
+pub fn reset_play_skies_camera() -> QueryAffect<ComponentSet<Transform>, With<PlaySkiesCamera>> {
+    query_affect(component_set(PLAY_SKIES_CAMERA_HOME))
+}
@@ -2,4 +2,15 @@ mod plugin;
 pub use plugin::PlaySkiesPlugin;
 
 mod camera;
-pub use camera::PlaySkiesCamera;
+pub use camera::{PlaySkiesAction, PlaySkiesCamera};
+
+mod controller;
+
+mod collision;
+
+mod objectives;
+pub use objectives::{PlaySkiesResult, PlaySkiesResults};
+
+mod rail;
+
+mod settings;
@@ -0,0 +1,616 @@
+use bevy::camera::visibility::RenderLayers;
+use bevy::platform::collections::HashSet;
+use bevy::prelude::*;
+use bevy::time::Stopwatch;
+use bevy_pipe_affect::prelude::*;
+
+use crate::clear_skies::ClearSkiesState;
+use crate::clear_skies::camera::ClearSkiesViewport;
+use crate::clear_skies::paint_skies::{BakedPaintLayers, LayerIndex, PaintedMesh};
+use crate::clear_skies::play_skies::collision::{
+    OCTAHEDRON_TRIANGLES,
+    PaintedContactSphere,
+    PaintedContactStarted,
+    PaintedHit,
+};
+use crate::clear_skies::play_skies::controller::PlaySkiesPlayer;
+use crate::clear_skies::play_skies::settings::PlaySkiesSettings;
+use crate::clear_skies::render_layers::PAINTED_LAYER;
+
+/// The number of vertices every octahedron of a [`PaintedMesh`] takes up.
+const OCTAHEDRON_VERTICES: usize = 6;
+
+/// Component for paintable entities whose painted meshes have to be touched to complete a
+/// [`PlaySkiesObjective::TouchPaintedFrom`] objective.
+#[derive(Default, Debug, PartialEq, Eq, Copy, Clone, Hash, Reflect, Component)]
+#[reflect(Component)]
+pub struct PlaySkiesTarget;
+
+/// Component for a goal of [`ClearSkiesState::PlaySkies`], set from the painted sky when it's
+/// entered.
+///
+/// Objectives are kept until it's entered again, so that their result can be recorded on exit.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Reflect, Component)]
+#[reflect(Component)]
+#[require(Name = "PlaySkiesObjective")]
+pub enum PlaySkiesObjective {
+    /// Touch a painted mesh on the deepest layer, which was painted first.
+    ///
+    /// Layers baked by compaction count too, so this is the first layer of the whole session.
+    ReachDeepestLayer {
+        /// The deepest layer with visible painted or baked meshes.
+        layer: LayerIndex,
+        /// Whether it's been touched.
+        reached: bool,
+    },
+    /// Touch a painted or baked mesh painted from a [`PlaySkiesTarget`].
+    TouchPaintedFrom {
+        /// The [`PlaySkiesTarget`] entity.
+        paintable: Entity,
+        /// Whether a mesh painted from it has been touched.
+        touched: bool,
+    },
+    /// Collect every [`PlaySkiesCollectible`].
+    CollectAll {
+        /// How many have been collected.
+        collected: usize,
+        /// How many were placed.
+        total: usize,
+    },
+}
+
+impl PlaySkiesObjective {
+    /// Returns `true` if this objective is completed.
+    pub fn is_completed(&self) -> bool {
+        match self {
+            PlaySkiesObjective::ReachDeepestLayer { reached, .. } => *reached,
+            PlaySkiesObjective::TouchPaintedFrom { touched, .. } => *touched,
+            PlaySkiesObjective::CollectAll { collected, total } => collected >= total,
+        }
+    }
+
+    /// Returns the points this objective has earned so far.
+    ///
+    /// Collectibles earn points as they're collected, on top of those for completing the objective.
+    pub fn points(&self, settings: &PlaySkiesSettings) -> u32 {
+        let completed_points = if self.is_completed() {
+            settings.objective_points
+        } else {
+            0
+        };
+
+        let collected_points = match self {
+            PlaySkiesObjective::CollectAll { collected, .. } => {
+                *collected as u32 * settings.collectible_points
+            }
+            _ => 0,
+        };
+
+        completed_points + collected_points
+    }
+
+    /// Returns this objective after the given painted mesh hit was touched.
+    pub fn touched(self, hit: &PaintedHit) -> Self {
+        match self {
+            PlaySkiesObjective::ReachDeepestLayer { layer, reached } => {
+                PlaySkiesObjective::ReachDeepestLayer {
+                    layer,
+                    reached: reached || hit.paint_layer == layer,
+                }
+            }
+            PlaySkiesObjective::TouchPaintedFrom { paintable, touched } => {
+                PlaySkiesObjective::TouchPaintedFrom {
+                    paintable,
+                    touched: touched || hit.painted_from == paintable,
+                }
+            }
+            objective => objective,
+        }
+    }
+
+    /// Returns this objective after the given number of [`PlaySkiesCollectible`]s were collected.
+    pub fn collected(self, count: usize) -> Self {
+        match self {
+            PlaySkiesObjective::CollectAll { collected, total } => PlaySkiesObjective::CollectAll {
+                collected: (collected + count).min(total),
+                total,
+            },
+            objective => objective,
+        }
+    }
+}
+
+/// An item placed inside a painted octahedron, collected by flying the [`PlaySkiesPlayer`] into it.
+#[derive(Default, Debug, PartialEq, Eq, Copy, Clone, Hash, Reflect, Component)]
+#[reflect(Component)]
+#[require(Name = "PlaySkiesCollectible", RenderLayers = PAINTED_LAYER)]
+pub struct PlaySkiesCollectible;
+
+/// Resource timing the current run of [`ClearSkiesState::PlaySkies`], until every
+/// [`PlaySkiesObjective`] is completed.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deref, Reflect, Resource)]
+#[reflect(Resource)]
+pub struct PlaySkiesStopwatch(pub Stopwatch);
+
+/// The outcome of a run of [`ClearSkiesState::PlaySkies`].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Reflect)]
+pub struct PlaySkiesResult {
+    /// The points earned.
+    pub score: u32,
+    /// How long the run took, in seconds.
+    pub seconds: f32,
+    /// How many objectives were completed.
+    pub completed_objectives: usize,
+    /// How many objectives there were.
+    pub objectives: usize,
+}
+
+/// Resource holding the result of every run of [`ClearSkiesState::PlaySkies`] in this paint
+/// session, which are saved with it.
+#[derive(Debug, Default, Clone, PartialEq, Deref, Reflect, Resource)]
+#[reflect(Resource)]
+pub struct PlaySkiesResults(pub Vec<PlaySkiesResult>);
+
+/// Marker component for the score, timer and objectives shown over the [`ClearSkiesViewport`].
+#[derive(Default, Debug, PartialEq, Eq, Copy, Clone, Hash, Reflect, Component)]
+#[reflect(Component)]
+#[require(Name = "PlaySkiesHud")]
+pub struct PlaySkiesHud;
+
+type SpawnCollectibles = AssetAddAnd<
+    Mesh,
+    AssetAddAnd<
+        StandardMaterial,
+        Vec<
+            CommandSpawn<(
+                PlaySkiesCollectible,
+                Transform,
+                Mesh3d,
+                MeshMaterial3d<StandardMaterial>,
+                DespawnOnExit<ClearSkiesState>,
+            )>,
+        >,
+    >,
+>;
+
+/// An octahedron of a visible painted or baked mesh, in world space.
+struct PaintedOctahedron {
+    paint_layer: LayerIndex,
+    painted_from: Entity,
+    /// The painted or baked mesh entity, and the octahedron's index within it.
+    mesh_octahedron: (Entity, usize),
+    center: Vec3,
+}
+
+/// Returns the world-space center of every octahedron of the given mesh.
+fn octahedron_centers<'a>(
+    mesh: &'a Mesh,
+    transform: &'a GlobalTransform,
+) -> Option<impl Iterator<Item = Vec3> + 'a> {
+    let positions = mesh.attribute(Mesh::ATTRIBUTE_POSITION)?.as_float3()?;
+
+    Some(
+        positions
+            .chunks_exact(OCTAHEDRON_VERTICES)
+            .map(move |vertices| {
+                let center = vertices.iter().copied().map(Vec3::from).sum::<Vec3>()
+                    / OCTAHEDRON_VERTICES as f32;
+
+                transform.transform_point(center)
+            }),
+    )
+}
+
+/// Replaces the objectives of the last run with new ones for the visible painted and baked
+/// meshes, and places the [`PlaySkiesCollectible`]s for them.
+///
+/// Collectibles are spread evenly across the painted octahedra, in layer order.
+pub fn start_play_skies_objectives(
+    old_objectives: Query<Entity, With<PlaySkiesObjective>>,
+    painted_meshes: Query<(Entity, &PaintedMesh, &Mesh3d, &GlobalTransform, &Visibility)>,
+    baked_meshes: Query<(Entity, &BakedPaintLayers, &Mesh3d, &GlobalTransform)>,
+    targets: Query<(), With<PlaySkiesTarget>>,
+    meshes: Res<Assets<Mesh>>,
+    settings: Res<PlaySkiesSettings>,
+) -> (
+    Vec<EntityCommandDespawn>,
+    Vec<CommandSpawn<PlaySkiesObjective>>,
+    ResSet<PlaySkiesStopwatch>,
+    SpawnCollectibles,
+) {
+    let painted_octahedra = painted_meshes
+        .iter()
+        .filter(|(.., visibility)| **visibility != Visibility::Hidden)
+        .filter_map(|(entity, painted_mesh, mesh, transform, _)| {
+            Some(
+                octahedron_centers(meshes.get(mesh)?, transform)?
+                    .enumerate()
+                    .map(move |(octahedron, center)| PaintedOctahedron {
+                        paint_layer: painted_mesh.paint_layer,
+                        painted_from: painted_mesh.painted_from,
+                        mesh_octahedron: (entity, octahedron),
+                        center,
+                    }),
+            )
+        })
+        .flatten();
+
+    let baked_octahedra = baked_meshes
+        .iter()
+        .filter_map(|(entity, baked_paint_layers, mesh, transform)| {
+            Some(
+                octahedron_centers(meshes.get(mesh)?, transform)?
+                    .enumerate()
+                    .filter_map(move |(octahedron, center)| {
+                        let painted_mesh =
+                            baked_paint_layers.painted_mesh(octahedron * OCTAHEDRON_TRIANGLES)?;
+
+                        Some(PaintedOctahedron {
+                            paint_layer: painted_mesh.paint_layer,
+                            painted_from: painted_mesh.painted_from,
+                            mesh_octahedron: (entity, octahedron),
+                            center,
+                        })
+                    }),
+            )
+        })
+        .flatten();
+
+    let mut octahedra = painted_octahedra.chain(baked_octahedra).collect::<Vec<_>>();
+
+    octahedra.sort_by_key(|octahedron| (*octahedron.paint_layer, octahedron.mesh_octahedron));
+
+    let deepest_layer = octahedra.first().map(|octahedron| octahedron.paint_layer);
+
+    let painted_from_targets = octahedra
+        .iter()
+        .map(|octahedron| octahedron.painted_from)
+        .filter(|painted_from| targets.contains(*painted_from))
+        .collect::<HashSet<_>>();
+
+    let collectible_count = settings.collectible_count.min(octahedra.len());
+
+    let collectible_translations = (0..collectible_count)
+        .map(|collectible| octahedra[collectible * octahedra.len() / collectible_count].center)
+        .collect::<Vec<_>>();
+
+    let objectives = deepest_layer
+        .map(|layer| PlaySkiesObjective::ReachDeepestLayer {
+            layer,
+            reached: false,
+        })
+        .into_iter()
+        .chain(painted_from_targets.into_iter().map(|paintable| {
+            PlaySkiesObjective::TouchPaintedFrom {
+                paintable,
+                touched: false,
+            }
+        }))
+        .chain(
+            (collectible_count > 0).then_some(PlaySkiesObjective::CollectAll {
+                collected: 0,
+                total: collectible_count,
+            }),
+        )
+        .map(command_spawn)
+        .collect();
+
+    let material = StandardMaterial {
+        base_color: Color::srgb(0.4, 1.0, 0.9),
+        unlit: true,
+        ..default()
+    };
+
+    (
+        old_objectives.iter().map(entity_command_despawn).collect(),
+        objectives,
+        res_set(PlaySkiesStopwatch::default()),
+        asset_add_and(
+            Sphere::new(settings.collectible_radius).mesh().build(),
+            move |mesh_handle| {
+                asset_add_and(material, move |material_handle| {
+                    collectible_translations
+                        .into_iter()
+                        .map(|translation| {
+                            command_spawn((
+                                PlaySkiesCollectible,
+                                Transform::from_translation(translation),
+                                Mesh3d(mesh_handle.clone()),
+                                MeshMaterial3d(material_handle.clone()),
+                                DespawnOnExit(ClearSkiesState::PlaySkies),
+                            ))
+                        })
+                        .collect()
+                })
+            },
+        ),
+    )
+}
+
+/// Updates the [`PlaySkiesObjective`]s with the painted meshes the [`PlaySkiesPlayer`] starts
+/// touching.
+pub fn touch_objectives(
+    player: Single<Entity, With<PlaySkiesPlayer>>,
+) -> MessagesReadAnd<
+    PaintedContactStarted,
+    Option<QueryMap<&'static PlaySkiesObjective, ComponentSet<PlaySkiesObjective>>>,
+> {
+    let player = *player;
+
+    messages_read_and(move |PaintedContactStarted { entity, hit }| {
+        let hit = *hit;
+
+        (*entity == player).then(|| {
+            query_map(move |objective: &PlaySkiesObjective| component_set(objective.touched(&hit)))
+        })
+    })
+}
+
+/// Collects the [`PlaySkiesCollectible`]s that the [`PlaySkiesPlayer`] touches.
+pub fn collect_collectibles(
+    player: Single<(&Transform, &PaintedContactSphere), With<PlaySkiesPlayer>>,
+    collectibles: Query<(Entity, &Transform), With<PlaySkiesCollectible>>,
+    settings: Res<PlaySkiesSettings>,
+) -> Option<(
+    Vec<EntityCommandDespawn>,
+    QueryMap<&'static PlaySkiesObjective, ComponentSet<PlaySkiesObjective>>,
+)> {
+    let (player_transform, contact_sphere) = *player;
+    let reach = contact_sphere.radius + settings.collectible_radius;
+
+    let collected = collectibles
+        .iter()
+        .filter(|(_, transform)| {
+            transform.translation.distance(player_transform.translation) <= reach
+        })
+        .map(|(entity, _)| entity)
+        .collect::<Vec<_>>();
+
+    if collected.is_empty() {
+        return None;
+    }
+
+    let count = collected.len();
+
+    Some((
+        collected.into_iter().map(entity_command_despawn).collect(),
+        query_map(move |objective: &PlaySkiesObjective| component_set(objective.collected(count))),
+    ))
+}
+
+/// Ticks the [`PlaySkiesStopwatch`] until every [`PlaySkiesObjective`] is completed.
+pub fn tick_play_skies_stopwatch(
+    stopwatch: Res<PlaySkiesStopwatch>,
+    objectives: Query<&PlaySkiesObjective>,
+    time: Res<Time>,
+) -> Option<ResSet<PlaySkiesStopwatch>> {
+    let all_completed = objectives.iter().all(PlaySkiesObjective::is_completed);
+
+    (!all_completed).then(|| {
+        res_set(PlaySkiesStopwatch(
+            stopwatch.0.clone().tick(time.delta()).clone(),
+        ))
+    })
+}
+
+/// Spawns the [`PlaySkiesHud`] over the [`ClearSkiesViewport`] until
+/// [`ClearSkiesState::PlaySkies`] is exited.
+pub fn spawn_play_skies_hud(
+    viewport: Single<Entity, With<ClearSkiesViewport>>,
+) -> CommandSpawn<(
+    PlaySkiesHud,
+    Text,
+    TextLayout,
+    Node,
+    DespawnOnExit<ClearSkiesState>,
+    ChildOf,
+)> {
+    command_spawn((
+        PlaySkiesHud,
+        Text::default(),
+        TextLayout::new_with_justify(Justify::Right),
+        Node {
+            position_type: PositionType::Absolute,
+            top: px(4),
+            right: px(4),
+            ..default()
+        },
+        DespawnOnExit(ClearSkiesState::PlaySkies),
+        ChildOf(*viewport),
+    ))
+}
+
+/// Returns the score of the given [`PlaySkiesObjective`]s.
+fn score<'a>(
+    objectives: impl IntoIterator<Item = &'a PlaySkiesObjective>,
+    settings: &PlaySkiesSettings,
+) -> u32 {
+    objectives
+        .into_iter()
+        .map(|objective| objective.points(settings))
+        .sum()
+}
+
+/// Writes the score, time and the state of every [`PlaySkiesObjective`] into the
+/// [`PlaySkiesHud`].
+pub fn update_play_skies_hud(
+    objectives: Query<&PlaySkiesObjective>,
+    names: Query<&Name>,
+    stopwatch: Res<PlaySkiesStopwatch>,
+    settings: Res<PlaySkiesSettings>,
+) -> QueryAffect<ComponentSet<Text>, With<PlaySkiesHud>> {
+    let objective_lines = objectives.iter().map(|objective| {
+        let check = if objective.is_completed() { "x" } else { " " };
+
+        let description = match objective {
+            PlaySkiesObjective::ReachDeepestLayer { layer, .. } => {
+                format!("Reach layer {}", **layer)
+            }
+            PlaySkiesObjective::TouchPaintedFrom { paintable, .. } => format!(
+                "Touch {}",
+                names
+                    .get(*paintable)
+                    .map_or_else(|_| paintable.to_string(), Name::to_string)
+            ),
+            PlaySkiesObjective::CollectAll { collected, total } => {
+                format!("Collect {collected}/{total}")
+            }
+        };
+
+        format!("[{check}] {description}")
+    });
+
+    let text = [
+        format!("Score {}", score(&objectives, &settings)),
+        format!("Time {:.1}s", stopwatch.elapsed_secs()),
+    ]
+    .into_iter()
+    .chain(objective_lines)
+    .collect::<Vec<_>>()
+    .join("\n");
+
+    query_affect(component_set(Text::new(text)))
+}
+
+/// Records the result of the run in the [`PlaySkiesResults`], if it had any objectives.
+pub fn record_play_skies_result(
+    objectives: Query<&PlaySkiesObjective>,
+    stopwatch: Res<PlaySkiesStopwatch>,
+    results: Res<PlaySkiesResults>,
+    settings: Res<PlaySkiesSettings>,
+) -> Option<ResSet<PlaySkiesResults>> {
+    if objectives.is_empty() {
+        return None;
+    }
+
+    let result = PlaySkiesResult {
+        score: score(&objectives, &settings),
+        seconds: stopwatch.elapsed_secs(),
+        completed_objectives: objectives
+            .iter()
+            .filter(|objective| objective.is_completed())
+            .count(),
+        objectives: objectives.iter().count(),
+    };
+
+    info!(
+        "play skies result: {} points in {:.1}s, {}/{} objectives",
+        result.score, result.seconds, result.completed_objectives, result.objectives
+    );
+
+    Some(res_set(PlaySkiesResults(
+        results.iter().copied().chain([result]).collect(),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bevy::ecs::system::RunSystemOnce;
+    use bevy::time::TimeUpdateStrategy;
+    use leafwing_input_manager::prelude::*;
+
+    use super::*;
+    use crate::clear_skies::paint_skies::LookAtSphericalCoords;
+    use crate::clear_skies::play_skies::collision::{
+        PaintedColliders,
+        PaintedContactEnded,
+        build_painted_colliders,
+        detect_painted_contacts,
+    };
+    use crate::clear_skies::play_skies::controller::fly_player;
+    use crate::clear_skies::play_skies::{PlaySkiesAction, PlaySkiesCamera};
+
+    #[test]
+    fn flying_into_a_painted_mesh_completes_touch_objectives() {
+        let mut app = App::new();
+
+        app.add_plugins(MinimalPlugins)
+            .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_millis(
+                100,
+            )))
+            .init_resource::<Assets<Mesh>>()
+            .init_resource::<Assets<StandardMaterial>>()
+            .init_resource::<PaintedColliders>()
+            .init_resource::<PlaySkiesStopwatch>()
+            .init_resource::<PlaySkiesSettings>()
+            .add_message::<PaintedContactStarted>()
+            .add_message::<PaintedContactEnded>()
+            .add_systems(
+                Update,
+                (
+                    fly_player.pipe(affect),
+                    detect_painted_contacts.pipe(affect),
+                    touch_objectives.pipe(affect),
+                )
+                    .chain(),
+            );
+
+        let world = app.world_mut();
+
+        let mesh = world
+            .resource_mut::<Assets<Mesh>>()
+            .add(Cuboid::new(4.0, 4.0, 1.0).mesh().build());
+
+        let target = world.spawn(PlaySkiesTarget).id();
+
+        // The painted mesh is 4 units in front of the player, on the first layer.
+        world.spawn((
+            Mesh3d(mesh),
+            GlobalTransform::from_translation(Vec3::new(0.0, 0.0, -5.0)),
+            PaintedMesh {
+                painted_from: target,
+                triangle_indices: vec![0, 1],
+                paint_layer: LayerIndex(0),
+                paint_branch: default(),
+            },
+        ));
+
+        let mut action_state = ActionState::<PlaySkiesAction>::default();
+        action_state.set_axis_pair(&PlaySkiesAction::Fly, Vec2::Y);
+
+        world.spawn((PlaySkiesCamera, action_state));
+
+        world.spawn((
+            PlaySkiesPlayer,
+            LookAtSphericalCoords::from_direction(Vec3::NEG_Z),
+            Transform::IDENTITY,
+        ));
+
+        world
+            .run_system_once(build_painted_colliders.pipe(affect))
+            .unwrap();
+        world
+            .run_system_once(start_play_skies_objectives.pipe(affect))
+            .unwrap();
+
+        for _ in 0..20 {
+            app.update();
+        }
+
+        let world = app.world_mut();
+
+        let touch_objectives = world
+            .query::<&PlaySkiesObjective>()
+            .iter(world)
+            .filter(|objective| !matches!(objective, PlaySkiesObjective::CollectAll { .. }))
+            .copied()
+            .collect::<Vec<_>>();
+
+        assert_eq!(touch_objectives.len(), 2);
+        assert!(
+            touch_objectives
+                .iter()
+                .all(PlaySkiesObjective::is_completed),
+            "{touch_objectives:?}"
+        );
+
+        let player = world
+            .query_filtered::<&Transform, With<PlaySkiesPlayer>>()
+            .single(world)
+            .unwrap();
+
+        assert!(player.translation.z > -4.5, "the player flew into the mesh");
+    }
+}
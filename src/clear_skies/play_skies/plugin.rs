@@ -1,20 +1,133 @@
 use bevy::prelude::*;
 use bevy_pipe_affect::prelude::*;
 
+use crate::button_predicate::button_just_pressed_predicate;
 use crate::clear_skies::ClearSkiesState;
 use crate::clear_skies::camera::CreateClearSkiesRenderTarget;
-use crate::clear_skies::play_skies::camera::spawn_camera;
+use crate::clear_skies::paint_skies::look_at_spherical_coords;
+use crate::clear_skies::play_skies::camera::{PlaySkiesAction, spawn_camera};
+use crate::clear_skies::play_skies::collision::{
+    PaintedColliders,
+    PaintedContactEnded,
+    PaintedContactSphere,
+    PaintedContactStarted,
+    PaintedContacts,
+    build_painted_colliders,
+    clear_painted_colliders,
+    detect_painted_contacts,
+};
+use crate::clear_skies::play_skies::controller::{
+    PlaySkiesPlayer,
+    control_player_look,
+    fly_player,
+    follow_player,
+    reset_play_skies_camera,
+    spawn_player,
+};
+use crate::clear_skies::play_skies::objectives::{
+    PlaySkiesCollectible,
+    PlaySkiesHud,
+    PlaySkiesObjective,
+    PlaySkiesResults,
+    PlaySkiesStopwatch,
+    PlaySkiesTarget,
+    collect_collectibles,
+    record_play_skies_result,
+    spawn_play_skies_hud,
+    start_play_skies_objectives,
+    tick_play_skies_stopwatch,
+    touch_objectives,
+    update_play_skies_hud,
+};
+use crate::clear_skies::play_skies::rail::{
+    PaintRail,
+    paint_rail_inactive,
+    reset_paint_rail,
+    ride_paint_rail,
+    toggle_paint_rail,
+};
+use crate::clear_skies::play_skies::settings::PlaySkiesSettings;
+use crate::clear_skies::switch_gamepads::SwitchGamepadsPlugin;
+use crate::cursor::lock_cursor;
 
+/// Plugin that contains systems and settings related to the [`ClearSkiesState::PlaySkies`] state.
 #[derive(Default, Debug, PartialEq, Eq, Copy, Clone, Hash, Reflect)]
 pub struct PlaySkiesPlugin;
 
 impl Plugin for PlaySkiesPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(
-            OnEnter(ClearSkiesState::Setup),
-            spawn_camera
-                .pipe(affect)
-                .after(CreateClearSkiesRenderTarget),
-        );
+        app.add_plugins(SwitchGamepadsPlugin::<PlaySkiesAction>::default())
+            .init_resource::<PlaySkiesSettings>()
+            .init_resource::<PaintRail>()
+            .init_resource::<PaintedColliders>()
+            .init_resource::<PlaySkiesStopwatch>()
+            .init_resource::<PlaySkiesResults>()
+            .add_message::<PaintedContactStarted>()
+            .add_message::<PaintedContactEnded>()
+            .register_type::<PlaySkiesPlayer>()
+            .register_type::<PaintedContactSphere>()
+            .register_type::<PaintedContacts>()
+            .register_type::<PlaySkiesTarget>()
+            .register_type::<PlaySkiesObjective>()
+            .register_type::<PlaySkiesCollectible>()
+            .register_type::<PlaySkiesHud>()
+            .register_type::<PlaySkiesStopwatch>()
+            .register_type::<PlaySkiesResults>()
+            .add_systems(
+                OnEnter(ClearSkiesState::Setup),
+                spawn_camera
+                    .pipe(affect)
+                    .after(CreateClearSkiesRenderTarget),
+            )
+            .add_systems(
+                OnEnter(ClearSkiesState::PlaySkies),
+                (
+                    build_painted_colliders.pipe(affect),
+                    spawn_player.pipe(affect),
+                    start_play_skies_objectives.pipe(affect),
+                    spawn_play_skies_hud.pipe(affect),
+                ),
+            )
+            .add_systems(
+                OnExit(ClearSkiesState::PlaySkies),
+                (
+                    record_play_skies_result.pipe(affect),
+                    reset_play_skies_camera.pipe(affect),
+                    reset_paint_rail.pipe(affect),
+                    clear_painted_colliders.pipe(affect),
+                ),
+            )
+            .add_systems(
+                Update,
+                update_play_skies_hud
+                    .pipe(affect)
+                    .run_if(in_state(ClearSkiesState::PlaySkies)),
+            )
+            .add_systems(
+                FixedUpdate,
+                (
+                    (
+                        toggle_paint_rail
+                            .pipe(affect)
+                            .run_if(button_just_pressed_predicate(PlaySkiesAction::Rail)),
+                        (
+                            control_player_look.pipe(affect),
+                            fly_player.pipe(affect),
+                            look_at_spherical_coords.pipe(affect),
+                            follow_player.pipe(affect),
+                        )
+                            .chain()
+                            .run_if(paint_rail_inactive),
+                        ride_paint_rail.pipe(affect),
+                        detect_painted_contacts.pipe(affect),
+                        touch_objectives.pipe(affect),
+                        collect_collectibles.pipe(affect),
+                        tick_play_skies_stopwatch.pipe(affect),
+                    )
+                        .chain(),
+                    lock_cursor.pipe(affect),
+                )
+                    .run_if(in_state(ClearSkiesState::PlaySkies)),
+            );
     }
 }
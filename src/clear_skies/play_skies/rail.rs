@@ -0,0 +1,249 @@
+use bevy::prelude::*;
+use bevy_pipe_affect::prelude::*;
+use leafwing_input_manager::prelude::*;
+
+use crate::clear_skies::camera::PaintSkiesCamera;
+use crate::clear_skies::paint_skies::{
+    CompactedCameraPath,
+    LayerDistanceCurves,
+    LayerIndex,
+    PaintLayerSettings,
+    PaintableHistory,
+};
+use crate::clear_skies::play_skies::camera::{PlaySkiesAction, PlaySkiesCamera};
+use crate::clear_skies::play_skies::controller::{PLAY_SKIES_CAMERA_HOME, PlaySkiesPlayer};
+use crate::clear_skies::play_skies::settings::PlaySkiesSettings;
+
+/// Resource defining the state of the rail that flies the [`PlaySkiesCamera`] along the recorded
+/// path of the [`PaintSkiesCamera`].
+#[derive(Debug, Default, Clone, PartialEq, Reflect, Resource)]
+pub enum PaintRail {
+    /// The [`PlaySkiesCamera`] follows the [`PlaySkiesPlayer`].
+    #[default]
+    Inactive,
+    /// The [`PlaySkiesCamera`] is flying along the rail.
+    Riding {
+        /// The spline through where every layer was painted.
+        path: CubicCurve<Vec3>,
+        /// The recorded rotation of every layer.
+        rotations: Vec<Quat>,
+        /// How far along the rail the camera is, in layers from the first.
+        progress: f32,
+        /// Whether the camera is paused on the rail.
+        paused: bool,
+    },
+}
+
+impl PaintRail {
+    /// Returns `true` if the [`PlaySkiesCamera`] follows the [`PlaySkiesPlayer`].
+    pub fn is_inactive(&self) -> bool {
+        matches!(self, PaintRail::Inactive)
+    }
+
+    /// Returns the rail through the given recorded [`PaintSkiesCamera`] transforms and the layers
+    /// they were recorded at, if there are enough of them for a spline.
+    ///
+    /// Each layer's point is where it was painted, at its layer distance in front of the
+    /// [`PaintSkiesCamera`], so the rail flies from the first layer to the last. Layers whose
+    /// distance can't be evaluated are skipped. The rail is offset by where layers are painted
+    /// around, so the sky looks as it did while it was being painted.
+    pub fn riding(
+        transforms: &[(LayerIndex, GlobalTransform)],
+        layer_distance: impl Fn(LayerIndex) -> Option<f32>,
+    ) -> Option<Self> {
+        let first_translation = transforms.first()?.1.translation();
+
+        let (points, rotations): (Vec<_>, Vec<_>) = transforms
+            .iter()
+            .filter_map(|(layer, transform)| {
+                let point = PLAY_SKIES_CAMERA_HOME.translation + transform.translation()
+                    - first_translation
+                    + transform.forward() * layer_distance(*layer)?;
+
+                Some((point, transform.rotation()))
+            })
+            .unzip();
+
+        let path = CubicCardinalSpline::new_catmull_rom(points)
+            .to_curve()
+            .ok()?;
+
+        Some(PaintRail::Riding {
+            path,
+            rotations,
+            progress: 0.0,
+            paused: false,
+        })
+    }
+}
+
+/// Run condition that returns `true` if the [`PlaySkiesCamera`] follows the [`PlaySkiesPlayer`].
+pub fn paint_rail_inactive(paint_rail: Res<PaintRail>) -> bool {
+    paint_rail.is_inactive()
+}
+
+/// Gets on the rail from the first layer of the active branch, or gets off it, hiding the
+/// [`PlaySkiesPlayer`] while riding.
+///
+/// The rail starts with the [`CompactedCameraPath`], so it covers compacted layers too.
+pub fn toggle_paint_rail(
+    paint_rail: Res<PaintRail>,
+    paint_skies_camera: Single<
+        (&PaintableHistory<GlobalTransform>, &CompactedCameraPath),
+        With<PaintSkiesCamera>,
+    >,
+    paint_layer_settings: Res<PaintLayerSettings>,
+    layer_distance_curves: Res<LayerDistanceCurves>,
+) -> Option<(
+    ResSet<PaintRail>,
+    QueryAffect<ComponentSet<Visibility>, With<PlaySkiesPlayer>>,
+)> {
+    if !paint_rail.is_inactive() {
+        return Some((
+            res_set(PaintRail::Inactive),
+            query_affect(component_set(Visibility::Inherited)),
+        ));
+    }
+
+    let (camera_transform_history, CompactedCameraPath(compacted_camera_path)) =
+        *paint_skies_camera;
+    let last_layer_index = camera_transform_history.last_layer_index()?;
+
+    // Layers without a recorded transform are skipped.
+    let transforms = compacted_camera_path
+        .iter()
+        .copied()
+        .chain(
+            (*camera_transform_history.checkpoint()..=*last_layer_index).filter_map(|layer| {
+                let layer = LayerIndex(layer);
+                Some((layer, *camera_transform_history.get(layer)?))
+            }),
+        )
+        .collect::<Vec<_>>();
+
+    let layer_distance = |layer| {
+        paint_layer_settings
+            .layer_distance_curve
+            .distance(layer, &layer_distance_curves)
+    };
+
+    let Some(paint_rail) = PaintRail::riding(&transforms, layer_distance) else {
+        warn!("at least 2 layers are needed to ride the paint rail");
+        return None;
+    };
+
+    Some((
+        res_set(paint_rail),
+        query_affect(component_set(Visibility::Hidden)),
+    ))
+}
+
+/// Moves the [`PlaySkiesCamera`] along the rail, pausing and rewinding according to
+/// [`PlaySkiesAction::PauseRail`] and [`PlaySkiesAction::RewindRail`] input.
+pub fn ride_paint_rail(
+    paint_rail: Res<PaintRail>,
+    action_state: Single<&ActionState<PlaySkiesAction>, With<PlaySkiesCamera>>,
+    settings: Res<PlaySkiesSettings>,
+    time: Res<Time>,
+) -> Option<(
+    ResSet<PaintRail>,
+    QueryAffect<ComponentSet<Transform>, With<PlaySkiesCamera>>,
+)> {
+    let PaintRail::Riding {
+        path,
+        rotations,
+        progress,
+        paused,
+    } = (*paint_rail).clone()
+    else {
+        return None;
+    };
+
+    let paused = paused != action_state.just_pressed(&PlaySkiesAction::PauseRail);
+
+    let direction = if action_state.pressed(&PlaySkiesAction::RewindRail) {
+        -1.0
+    } else {
+        1.0
+    };
+
+    let last_progress = rotations.len().saturating_sub(1) as f32;
+
+    let progress = if paused {
+        progress
+    } else {
+        (progress + direction * settings.rail_speed * time.delta_secs()).clamp(0.0, last_progress)
+    };
+
+    let from = progress.floor() as usize;
+    let to = (from + 1).min(rotations.len() - 1);
+    let rotation = rotations[from].slerp(rotations[to], progress.fract());
+
+    let transform = Transform::from_translation(path.position(progress)).with_rotation(rotation);
+
+    Some((
+        res_set(PaintRail::Riding {
+            path,
+            rotations,
+            progress,
+            paused,
+        }),
+        query_affect(component_set(transform)),
+    ))
+}
+
+/// Gets off the rail.
+pub fn reset_paint_rail() -> ResSet<PaintRail> {
+    res_set(PaintRail::Inactive)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rail_flies_from_the_first_layer_to_the_last() {
+        let camera_transform = GlobalTransform::from(
+            Transform::from_xyz(0.0, 2.0, 0.0).looking_to(Vec3::NEG_Z, Vec3::Y),
+        );
+        let transforms = (0..5)
+            .map(|layer| (LayerIndex(layer), camera_transform))
+            .collect::<Vec<_>>();
+
+        let Some(PaintRail::Riding { path, .. }) =
+            PaintRail::riding(&transforms, |layer| Some(1000.0 - 100.0 * *layer as f32))
+        else {
+            panic!("5 layers should be enough for a rail");
+        };
+
+        let first_point = PLAY_SKIES_CAMERA_HOME.translation + Vec3::NEG_Z * 1000.0;
+        let last_point = PLAY_SKIES_CAMERA_HOME.translation + Vec3::NEG_Z * 600.0;
+
+        assert!(path.position(0.0).abs_diff_eq(first_point, 1e-3));
+        assert!(path.position(4.0).abs_diff_eq(last_point, 1e-3));
+
+        let distances_to_last = (0..=16)
+            .map(|step| path.position(step as f32 / 4.0).distance(last_point))
+            .collect::<Vec<_>>();
+
+        assert!(
+            distances_to_last.windows(2).all(|pair| pair[1] < pair[0]),
+            "{distances_to_last:?}"
+        );
+    }
+
+    #[test]
+    fn rail_skips_layers_without_a_distance() {
+        let transforms = (0..3)
+            .map(|layer| (LayerIndex(layer), GlobalTransform::IDENTITY))
+            .collect::<Vec<_>>();
+
+        let Some(PaintRail::Riding { rotations, .. }) = PaintRail::riding(&transforms, |layer| {
+            (*layer != 1).then_some(10.0 * *layer as f32)
+        }) else {
+            panic!("2 layers should be enough for a rail");
+        };
+
+        assert_eq!(rotations.len(), 2);
+    }
+}
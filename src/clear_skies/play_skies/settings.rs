@@ -0,0 +1,47 @@
+use bevy::prelude::*;
+
+/// Various settings for behavior of the `PlaySkiesPlugin`.
+#[derive(Debug, Copy, Clone, PartialEq, Reflect, Resource)]
+pub struct PlaySkiesSettings {
+    /// Mouse/right stick sensitivity.
+    pub look_sensitivity: f32,
+    /// How fast the player flies, in units per second.
+    pub fly_speed: f32,
+    /// How many times faster the player flies while boosting.
+    pub boost_multiplier: f32,
+    /// How far behind the player the camera follows.
+    pub follow_distance: f32,
+    /// How far above the player the camera follows.
+    pub follow_height: f32,
+    /// How quickly the camera catches up with the player, as the fraction of the distance left
+    /// that it covers per second.
+    pub follow_smoothing: f32,
+    /// How many layers of the paint rail are flown per second.
+    pub rail_speed: f32,
+    /// How many collectibles are placed inside painted octahedra.
+    pub collectible_count: usize,
+    /// The radius of collectibles.
+    pub collectible_radius: f32,
+    /// The points earned for completing an objective.
+    pub objective_points: u32,
+    /// The points earned for every collectible.
+    pub collectible_points: u32,
+}
+
+impl Default for PlaySkiesSettings {
+    fn default() -> Self {
+        PlaySkiesSettings {
+            look_sensitivity: 0.02,
+            fly_speed: 10.0,
+            boost_multiplier: 4.0,
+            follow_distance: 6.0,
+            follow_height: 1.5,
+            follow_smoothing: 0.99,
+            rail_speed: 10.0,
+            collectible_count: 12,
+            collectible_radius: 0.4,
+            objective_points: 100,
+            collectible_points: 10,
+        }
+    }
+}
@@ -2,14 +2,18 @@ use bevy::prelude::*;
 use bevy_asset_loader::prelude::*;
 use bevy_pipe_affect::prelude::*;
 
-use crate::clear_skies::camera::ClearSkiesCameraPlugin;
+use crate::button_predicate::button_just_pressed_predicate;
+use crate::clear_skies::camera::{ClearSkiesCameraPlugin, PaintSkiesAction};
 use crate::clear_skies::paint_skies::PaintSkiesPlugin;
-use crate::clear_skies::play_skies::PlaySkiesPlugin;
+use crate::clear_skies::play_skies::{PlaySkiesAction, PlaySkiesPlugin};
 use crate::clear_skies::state::ClearSkiesState;
 use crate::clear_skies::transition::{
     ClearSkiesAssetCollection,
+    SkiesStateLabel,
     proceed_to_paint_skies,
+    proceed_to_play_skies,
     spawn_scene,
+    spawn_state_label,
 };
 
 /// Plugin for the Clear Skies game.
@@ -20,17 +24,33 @@ impl Plugin for ClearSkiesPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugins((PaintSkiesPlugin, PlaySkiesPlugin, ClearSkiesCameraPlugin))
             .add_sub_state::<ClearSkiesState>()
+            .register_type::<SkiesStateLabel>()
             .add_loading_state(
                 LoadingState::new(ClearSkiesState::Loading)
                     .continue_to_state(ClearSkiesState::Setup)
                     .load_collection::<ClearSkiesAssetCollection>(),
             )
             .add_systems(OnEnter(ClearSkiesState::Setup), spawn_scene.pipe(affect))
+            .add_systems(
+                OnEnter(ClearSkiesState::PaintSkies),
+                spawn_state_label(ClearSkiesState::PaintSkies, "Paint Skies").pipe(affect),
+            )
+            .add_systems(
+                OnEnter(ClearSkiesState::PlaySkies),
+                spawn_state_label(ClearSkiesState::PlaySkies, "Play Skies").pipe(affect),
+            )
             .add_systems(
                 Update,
-                proceed_to_paint_skies
-                    .pipe(affect)
-                    .run_if(in_state(ClearSkiesState::Setup)),
+                (
+                    proceed_to_paint_skies.pipe(affect).run_if(
+                        in_state(ClearSkiesState::Setup).or(in_state(ClearSkiesState::PlaySkies)
+                            .and(button_just_pressed_predicate(PlaySkiesAction::PaintSkies))),
+                    ),
+                    proceed_to_play_skies.pipe(affect).run_if(
+                        in_state(ClearSkiesState::PaintSkies)
+                            .and(button_just_pressed_predicate(PaintSkiesAction::PlaySkies)),
+                    ),
+                ),
             );
     }
 }
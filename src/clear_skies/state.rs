@@ -13,4 +13,6 @@ pub enum ClearSkiesState {
     Setup,
     /// The skybox is being drawn by the camera.
     PaintSkies,
+    /// The painted skybox is being played in.
+    PlaySkies,
 }
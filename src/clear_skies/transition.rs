@@ -4,6 +4,7 @@ use bevy_pipe_affect::prelude::*;
 use thiserror::Error;
 
 use crate::clear_skies::ClearSkiesState;
+use crate::clear_skies::camera::ClearSkiesViewport;
 
 /// GLTF assets handles should be strong paths.
 #[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Error)]
@@ -35,3 +36,46 @@ pub struct ClearSkiesAssetCollection {
 pub fn proceed_to_paint_skies() -> ResSet<NextState<ClearSkiesState>> {
     res_set(NextState::Pending(ClearSkiesState::PaintSkies))
 }
+
+/// Go to play skies state when this system runs.
+///
+/// Painted layers aren't state scoped, so they're kept for when the sky is painted again.
+pub fn proceed_to_play_skies() -> ResSet<NextState<ClearSkiesState>> {
+    res_set(NextState::Pending(ClearSkiesState::PlaySkies))
+}
+
+/// Marker component for the label naming the current state over the [`ClearSkiesViewport`].
+#[derive(Default, Debug, PartialEq, Eq, Copy, Clone, Hash, Reflect, Component)]
+#[reflect(Component)]
+#[require(Name = "SkiesStateLabel")]
+pub struct SkiesStateLabel;
+
+/// Returns a system that labels the [`ClearSkiesViewport`] with the given text until the given
+/// state is exited.
+pub fn spawn_state_label(
+    state: ClearSkiesState,
+    text: &'static str,
+) -> impl Fn(
+    Single<Entity, With<ClearSkiesViewport>>,
+) -> CommandSpawn<(
+    SkiesStateLabel,
+    Text,
+    Node,
+    DespawnOnExit<ClearSkiesState>,
+    ChildOf,
+)> {
+    move |viewport| {
+        command_spawn((
+            SkiesStateLabel,
+            Text::new(text),
+            Node {
+                position_type: PositionType::Absolute,
+                top: px(4),
+                left: px(4),
+                ..default()
+            },
+            DespawnOnExit(state),
+            ChildOf(*viewport),
+        ))
+    }
+}
@@ -0,0 +1,148 @@
+use std::ops::Range;
+
+use bevy::math::bounding::{Aabb3d, BoundingVolume};
+use bevy::prelude::*;
+
+/// The most triangles a leaf of a [`TriangleBvh`] holds.
+const BVH_LEAF_TRIANGLES: usize = 4;
+
+/// Something with a world-space triangle, that a [`TriangleBvh`] can be built over.
+pub trait BvhTriangle {
+    /// Returns the triangle.
+    fn triangle(&self) -> &Triangle3d;
+
+    /// Returns the bounding box of the triangle.
+    fn aabb(&self) -> Aabb3d {
+        Aabb3d::from_point_cloud(
+            Isometry3d::IDENTITY,
+            self.triangle().vertices.iter().copied(),
+        )
+    }
+}
+
+impl BvhTriangle for Triangle3d {
+    fn triangle(&self) -> &Triangle3d {
+        self
+    }
+}
+
+/// A node of a [`TriangleBvh`].
+#[derive(Debug, Clone, PartialEq, Reflect)]
+enum BvhNode {
+    Leaf {
+        aabb: Aabb3d,
+        triangles: Range<usize>,
+    },
+    Branch {
+        aabb: Aabb3d,
+        children: [usize; 2],
+    },
+}
+
+impl BvhNode {
+    fn aabb(&self) -> &Aabb3d {
+        match self {
+            BvhNode::Leaf { aabb, .. } | BvhNode::Branch { aabb, .. } => aabb,
+        }
+    }
+}
+
+/// A bounding volume hierarchy over triangles, for finding the ones near a query without testing
+/// every one.
+#[derive(Debug, Clone, PartialEq, Reflect)]
+pub struct TriangleBvh<T> {
+    nodes: Vec<BvhNode>,
+    root: usize,
+    triangles: Vec<T>,
+}
+
+impl<T: BvhTriangle> TriangleBvh<T> {
+    /// Returns the hierarchy over the given triangles, if there are any.
+    pub fn new(mut triangles: Vec<T>) -> Option<Self> {
+        if triangles.is_empty() {
+            return None;
+        }
+
+        let mut nodes = Vec::new();
+        let root = build_bvh(&mut triangles, 0, &mut nodes);
+
+        Some(TriangleBvh {
+            nodes,
+            root,
+            triangles,
+        })
+    }
+
+    /// Returns every triangle whose bounding box is in a subtree that passes the given test.
+    pub fn candidates(&self, overlaps: impl Fn(&Aabb3d) -> bool) -> Vec<&T> {
+        let mut candidates = Vec::new();
+        let mut stack = vec![self.root];
+
+        while let Some(node) = stack.pop() {
+            let node = &self.nodes[node];
+
+            if !overlaps(node.aabb()) {
+                continue;
+            }
+
+            match node {
+                BvhNode::Leaf { triangles, .. } => {
+                    candidates.extend(&self.triangles[triangles.clone()]);
+                }
+                BvhNode::Branch { children, .. } => stack.extend(children),
+            }
+        }
+
+        candidates
+    }
+}
+
+/// Pushes the nodes of the bounding volume hierarchy over the given triangles, sorting them so
+/// that every leaf's triangles are consecutive, and returns the index of their root node.
+///
+/// `offset` is the index of the first of the given triangles in the whole hierarchy.
+fn build_bvh<T: BvhTriangle>(
+    triangles: &mut [T],
+    offset: usize,
+    nodes: &mut Vec<BvhNode>,
+) -> usize {
+    let aabb = triangles
+        .iter()
+        .map(T::aabb)
+        .reduce(|merged, aabb| merged.merge(&aabb))
+        .expect("bvh nodes should only be built over some triangles");
+
+    let node = if triangles.len() <= BVH_LEAF_TRIANGLES {
+        BvhNode::Leaf {
+            aabb,
+            triangles: offset..offset + triangles.len(),
+        }
+    } else {
+        let extent = aabb.max - aabb.min;
+        let axis = if extent.x >= extent.y && extent.x >= extent.z {
+            0
+        } else if extent.y >= extent.z {
+            1
+        } else {
+            2
+        };
+
+        let middle = triangles.len() / 2;
+        triangles.select_nth_unstable_by(middle, |a, b| {
+            a.triangle().centroid()[axis].total_cmp(&b.triangle().centroid()[axis])
+        });
+
+        let (first, second) = triangles.split_at_mut(middle);
+
+        BvhNode::Branch {
+            aabb,
+            children: [
+                build_bvh(first, offset, nodes),
+                build_bvh(second, offset + middle, nodes),
+            ],
+        }
+    };
+
+    nodes.push(node);
+    nodes.len() - 1
+}
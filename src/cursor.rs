@@ -2,10 +2,13 @@ use bevy::prelude::*;
 use bevy::window::{CursorGrabMode, CursorOptions};
 use bevy_pipe_affect::prelude::*;
 
+/// Resource deciding whether [`lock_cursor`] locks the cursor.
 #[derive(PartialEq, Eq, Debug, Default, Copy, Clone, Resource, Reflect)]
 pub enum CursorLock {
+    /// The cursor is locked and invisible.
     #[default]
     Lock,
+    /// The cursor is free and visible.
     Unlock,
 }
 
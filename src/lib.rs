@@ -0,0 +1,32 @@
+//! The games of the collage, and the parts of them that benchmarks need.
+#![warn(missing_docs)]
+
+mod state;
+
+mod clear_skies;
+pub use clear_skies::ClearSkiesPlugin;
+pub use clear_skies::paint_skies::{
+    LayerIndex,
+    PaintLayerHistoryPlugin,
+    PaintableHistory,
+    RecordPresent,
+    RedoPaintLayers,
+    TruncatePaintLayers,
+};
+
+mod predicate_timer;
+
+mod button_predicate;
+
+mod pipe_system;
+
+mod args;
+pub use args::DevArgs;
+
+mod cursor;
+pub use cursor::CursorLock;
+
+#[cfg(feature = "dev")]
+mod toggle_free_camera;
+#[cfg(feature = "dev")]
+pub use toggle_free_camera::ToggleFreeCameraPlugin;
@@ -10,27 +10,7 @@ use bevy_inspector_egui::bevy_egui::EguiPlugin;
 use bevy_inspector_egui::quick::WorldInspectorPlugin;
 use bevy_skein::SkeinPlugin;
 use clap::Parser;
-
-use crate::args::DevArgs;
-use crate::clear_skies::ClearSkiesPlugin;
-use crate::cursor::CursorLock;
-
-mod state;
-
-mod clear_skies;
-
-mod predicate_timer;
-
-mod button_predicate;
-
-mod pipe_system;
-
-mod args;
-
-mod cursor;
-
-#[cfg(feature = "dev")]
-mod toggle_free_camera;
+use collage::{ClearSkiesPlugin, CursorLock, DevArgs};
 
 fn main() {
     let args = if cfg!(feature = "dev") {
@@ -77,8 +57,7 @@ fn main() {
 
         if args.free_cam {
             use bevy::camera_controller::free_camera::FreeCameraPlugin;
-
-            use crate::toggle_free_camera::ToggleFreeCameraPlugin;
+            use collage::ToggleFreeCameraPlugin;
 
             app.add_plugins((FreeCameraPlugin, ToggleFreeCameraPlugin::default()));
         }